@@ -0,0 +1,576 @@
+/*!
+ * Conversations with npcs in multiplayer. The player talking to an npc runs the conversation
+ * on their machine, since that's where the microphone is, and broadcasts every line as a
+ * ConversationUpdate so nearby players see subtitles. The npc's voice is only synthesized and
+ * played on that machine, with that player's api key. Only one player at a time may talk to
+ * an npc, the machine owning the npc hands out its conversation lock.
+ */
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::ai::persona::cognitive_modules::converse::{ConversationEvent, Emotion};
+use crate::ai::persona::{AssociativeMemory, Persona, Scratch};
+use crate::ai::{OpenAPI, PlayerTranscriber};
+use crate::networking::ids::EntityIndex;
+use crate::networking::relevancy::Viewer;
+use crate::networking::rpc::{Rpc, RpcTarget, SendRpc};
+use crate::networking::transport::PeerId;
+use crate::networking::{NetworkingState, SynchronizedMaster, SynchronizedSlave};
+use crate::utils::Rng;
+use crate::RT;
+
+//players further than this from an npc don't see or hear its conversations
+const HEARING_RADIUS: f32 = 20.0;
+//seconds a line stays on screen
+const SUBTITLE_DURATION: f64 = 6.0;
+
+//write this to start talking to an npc, synchronized npcs answer once their lock is granted
+#[derive(Event)]
+pub struct TalkToNpc {
+    pub npc: Entity,
+}
+
+//asks the owner of an npc for its conversation lock
+#[derive(Event, Clone, Serialize, Deserialize)]
+pub struct ConversationLockRequest {
+    pub npc: u16,
+    #[serde(skip)]
+    requester: Option<PeerId>,
+}
+
+impl Rpc for ConversationLockRequest {
+    const NAME: &'static str = "ConversationLockRequest";
+
+    fn set_sender(&mut self, sender: PeerId) {
+        self.requester = Some(sender);
+    }
+}
+
+#[derive(Event, Clone, Serialize, Deserialize)]
+pub struct ConversationLockRelease {
+    pub npc: u16,
+    #[serde(skip)]
+    holder: Option<PeerId>,
+}
+
+impl Rpc for ConversationLockRelease {
+    const NAME: &'static str = "ConversationLockRelease";
+
+    fn set_sender(&mut self, sender: PeerId) {
+        self.holder = Some(sender);
+    }
+}
+
+//broadcast by the owner of an npc whenever its lock changes hands, and sent to refused requesters
+#[derive(Event, Clone, Serialize, Deserialize)]
+pub struct ConversationLockState {
+    pub npc: u16,
+    pub holder: Option<PeerId>,
+    #[serde(skip)]
+    owner: Option<PeerId>,
+}
+
+impl Rpc for ConversationLockState {
+    const NAME: &'static str = "ConversationLockState";
+
+    fn set_sender(&mut self, sender: PeerId) {
+        self.owner = Some(sender);
+    }
+}
+
+//one step of a conversation, broadcast by the player holding the npc's lock
+#[derive(Event, Clone, Serialize, Deserialize)]
+pub struct ConversationUpdate {
+    pub npc: u16,
+    pub event: ConversationEvent,
+    #[serde(skip)]
+    speaker: Option<PeerId>,
+}
+
+impl Rpc for ConversationUpdate {
+    const NAME: &'static str = "ConversationUpdate";
+
+    fn set_sender(&mut self, sender: PeerId) {
+        self.speaker = Some(sender);
+    }
+}
+
+//who is talking to each npc by static id, kept on every machine from the owners' broadcasts
+#[derive(Resource, Default)]
+pub struct ConversationLocks {
+    holders: HashMap<u16, PeerId>,
+}
+
+impl ConversationLocks {
+    pub fn holder(&self, npc: u16) -> Option<PeerId> {
+        self.holders.get(&npc).copied()
+    }
+}
+
+//the conversation this machine asked for or is having, static ids are None for npcs that aren't synchronized
+#[derive(Resource, Default)]
+pub(super) struct LocalConversation {
+    requested: Option<(Entity, u16)>,
+    active: Option<(Entity, Option<u16>)>,
+}
+
+#[derive(Clone)]
+pub struct Subtitle {
+    pub npc: Entity,
+    pub speaker: String,
+    pub text: String,
+    pub emotion: Option<Emotion>,
+    pub shown_at: f64,
+}
+
+//lines of the conversations this machine can hear, oldest first
+#[derive(Resource, Default)]
+pub struct Subtitles {
+    pub lines: Vec<Subtitle>,
+}
+
+impl Subtitles {
+    fn show(
+        &mut self,
+        npc: Entity,
+        npc_name: &str,
+        player: &str,
+        event: &ConversationEvent,
+        now: f64,
+    ) {
+        let (speaker, text, emotion) = match event {
+            ConversationEvent::PlayerLine(text) => (player, text, None),
+            ConversationEvent::NpcLine { text, emotion } => (npc_name, text, *emotion),
+            ConversationEvent::End => return,
+        };
+        if text.is_empty() {
+            return;
+        }
+
+        self.lines.push(Subtitle {
+            npc,
+            speaker: speaker.to_string(),
+            text: text.clone(),
+            emotion,
+            shown_at: now,
+        });
+    }
+}
+
+#[derive(Component)]
+pub(super) struct SubtitleText;
+
+//npcs that can be talked to, with their entity ids when they are synchronized
+type ConversableNpcs<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static Persona,
+        &'static Scratch,
+        &'static AssociativeMemory,
+        Option<&'static SynchronizedMaster>,
+        Option<&'static SynchronizedSlave>,
+    ),
+>;
+
+//what a conversation on this machine needs to listen to the player and think of replies
+#[derive(SystemParam)]
+pub(super) struct ConversationApis<'w> {
+    open_api: Res<'w, OpenAPI>,
+    player_transcriber: Res<'w, PlayerTranscriber>,
+    rt: Res<'w, RT>,
+    rng: Res<'w, Rng>,
+}
+
+//asking owners for npc locks and hearing back from them
+#[derive(SystemParam)]
+pub(super) struct LockRequests<'w, 's> {
+    locks: Res<'w, ConversationLocks>,
+    states: EventReader<'w, 's, ConversationLockState>,
+    requests: EventWriter<'w, SendRpc<ConversationLockRequest>>,
+}
+
+//what the local conversation sends to everyone else
+#[derive(SystemParam)]
+pub(super) struct ConversationRpcs<'w> {
+    updates: EventWriter<'w, SendRpc<ConversationUpdate>>,
+    releases: EventWriter<'w, SendRpc<ConversationLockRelease>>,
+}
+
+//where this machine's player hears conversations from
+#[derive(SystemParam)]
+pub(super) struct Listener<'w, 's> {
+    networking: Option<Res<'w, NetworkingState>>,
+    viewers: Query<'w, 's, (&'static Viewer, &'static GlobalTransform)>,
+}
+
+impl Listener<'_, '_> {
+    //None outside of a session or before this machine's player exists
+    fn position(&self) -> Option<Vec3> {
+        let networking = self.networking.as_ref()?;
+        self.viewers
+            .iter()
+            .find(|(viewer, _)| viewer.peer == networking.player_id)
+            .map(|(_, transform)| transform.translation())
+    }
+}
+
+//the machine owning a synchronized npc
+fn npc_owner(
+    npc: u16,
+    networking: &NetworkingState,
+    index: &EntityIndex,
+    slaves: &Query<&SynchronizedSlave>,
+) -> Option<PeerId> {
+    let entity = index.get(npc)?;
+    match slaves.get(entity) {
+        Ok(slave) => Some(slave.owner()),
+        Err(_) => Some(networking.player_id),
+    }
+}
+
+//runs on the owner of each npc, grants and releases its lock
+pub(super) fn grant_conversation_locks(
+    networking: Option<Res<NetworkingState>>,
+    index: Option<Res<EntityIndex>>,
+    slaves: Query<&SynchronizedSlave>,
+    mut locks: ResMut<ConversationLocks>,
+    mut requests: EventReader<ConversationLockRequest>,
+    mut releases: EventReader<ConversationLockRelease>,
+    mut states: EventWriter<SendRpc<ConversationLockState>>,
+) {
+    let (Some(networking), Some(index)) = (networking, index) else {
+        return;
+    };
+    let player_id = networking.player_id;
+    let owns = |npc: u16| npc_owner(npc, &networking, &index, &slaves) == Some(player_id);
+
+    for request in requests.read() {
+        let Some(requester) = request.requester else {
+            continue;
+        };
+        //the npc changed owner while the request was on its way, the player can ask again
+        if !owns(request.npc) {
+            continue;
+        }
+
+        let holder = *locks.holders.entry(request.npc).or_insert(requester);
+        let target = if holder == requester {
+            RpcTarget::Broadcast
+        } else {
+            RpcTarget::Peer(requester)
+        };
+        states.send(SendRpc {
+            target,
+            rpc: ConversationLockState {
+                npc: request.npc,
+                holder: Some(holder),
+                owner: None,
+            },
+        });
+    }
+
+    let mut released: Vec<u16> = Vec::new();
+    for release in releases.read() {
+        if owns(release.npc)
+            && release.holder.is_some()
+            && locks.holder(release.npc) == release.holder
+        {
+            released.push(release.npc);
+        }
+    }
+    //players that left can't release their locks themselves
+    released.extend(
+        locks
+            .holders
+            .iter()
+            .filter(|(npc, holder)| {
+                **holder != player_id && !networking.active_players.contains(*holder) && owns(**npc)
+            })
+            .map(|(npc, _)| *npc),
+    );
+
+    for npc in released {
+        locks.holders.remove(&npc);
+        states.send(SendRpc {
+            target: RpcTarget::Broadcast,
+            rpc: ConversationLockState {
+                npc,
+                holder: None,
+                owner: None,
+            },
+        });
+    }
+}
+
+//keeps the lock holders up to date on every machine
+pub(super) fn receive_conversation_locks(
+    networking: Option<Res<NetworkingState>>,
+    index: Option<Res<EntityIndex>>,
+    slaves: Query<&SynchronizedSlave>,
+    mut locks: ResMut<ConversationLocks>,
+    mut states: EventReader<ConversationLockState>,
+) {
+    let (Some(networking), Some(index)) = (networking, index) else {
+        return;
+    };
+
+    for state in states.read() {
+        if state.owner.is_none()
+            || npc_owner(state.npc, &networking, &index, &slaves) != state.owner
+        {
+            println!(
+                "Ignored conversation lock of npc {} from {:?}, which doesn't own it",
+                state.npc, state.owner
+            );
+            continue;
+        }
+
+        match state.holder {
+            Some(holder) => locks.holders.insert(state.npc, holder),
+            None => locks.holders.remove(&state.npc),
+        };
+    }
+}
+
+//asks for the lock of npcs the player wants to talk to and starts the conversation once it's granted
+pub(super) fn start_conversations(
+    mut talks: EventReader<TalkToNpc>,
+    mut local: ResMut<LocalConversation>,
+    networking: Option<Res<NetworkingState>>,
+    npcs: ConversableNpcs,
+    mut lock: LockRequests,
+    apis: ConversationApis,
+) {
+    let mut start: Option<(Entity, Option<u16>)> = None;
+
+    for talk in talks.read() {
+        if local.active.is_some() {
+            println!("Already in a conversation");
+            continue;
+        }
+        let Ok((_, _, _, master, slave)) = npcs.get(talk.npc) else {
+            println!("Can't talk to an entity without a persona");
+            continue;
+        };
+
+        let static_id = master
+            .map(|master| master.static_id())
+            .or(slave.map(|slave| slave.static_id()));
+        match (static_id, networking.is_some()) {
+            (Some(npc), true) => {
+                local.requested = Some((talk.npc, npc));
+                lock.requests.send(SendRpc {
+                    target: RpcTarget::OwnerOf(npc),
+                    rpc: ConversationLockRequest {
+                        npc,
+                        requester: None,
+                    },
+                });
+            }
+            //nobody else can talk to an npc that isn't synchronized
+            _ => start = Some((talk.npc, None)),
+        }
+    }
+
+    for state in lock.states.read() {
+        let (Some((entity, npc)), Some(networking)) = (local.requested, networking.as_ref()) else {
+            continue;
+        };
+        if state.npc != npc {
+            continue;
+        }
+
+        local.requested = None;
+        if lock.locks.holder(npc) == Some(networking.player_id) {
+            start = Some((entity, Some(npc)));
+        } else {
+            println!("The npc is already talking to someone else");
+        }
+    }
+
+    let Some((entity, npc)) = start else {
+        return;
+    };
+    let Ok((persona, scratch, associative, _, _)) = npcs.get(entity) else {
+        return;
+    };
+    persona.start_conversation_with_player(
+        &apis.open_api,
+        &apis.player_transcriber,
+        scratch,
+        associative,
+        &apis.rt,
+        &apis.rng,
+    );
+    local.active = Some((entity, npc));
+}
+
+//shows the local conversation and sends it to everyone else, releases the lock once it ends
+pub(super) fn publish_conversation(
+    time: Res<Time>,
+    mut local: ResMut<LocalConversation>,
+    locks: Res<ConversationLocks>,
+    networking: Option<Res<NetworkingState>>,
+    npcs: Query<&Persona>,
+    mut subtitles: ResMut<Subtitles>,
+    mut rpcs: ConversationRpcs,
+) {
+    let Some((entity, npc)) = local.active else {
+        return;
+    };
+    let Ok(persona) = npcs.get(entity) else {
+        local.active = None;
+        return;
+    };
+    let handler = &persona.conversation_handler;
+
+    //the owner takes the lock back from players it thinks have left
+    if let (Some(npc), Some(networking)) = (npc, networking.as_ref()) {
+        if locks.holder(npc) != Some(networking.player_id) {
+            handler.stop();
+        }
+    }
+
+    let mut events = handler.drain_events();
+    //a conversation that stopped early, e.g. because a request failed, still ends for everyone else
+    if !handler.is_running() && !events.iter().any(|e| matches!(e, ConversationEvent::End)) {
+        events.push(ConversationEvent::End);
+    }
+
+    let now = time.elapsed_seconds_f64();
+    for event in events {
+        subtitles.show(entity, &persona.name, "You", &event, now);
+        let ended = matches!(event, ConversationEvent::End);
+
+        if let Some(npc) = npc {
+            rpcs.updates.send(SendRpc {
+                target: RpcTarget::Broadcast,
+                rpc: ConversationUpdate {
+                    npc,
+                    event,
+                    speaker: None,
+                },
+            });
+        }
+
+        if ended {
+            if let Some(npc) = npc {
+                rpcs.releases.send(SendRpc {
+                    target: RpcTarget::OwnerOf(npc),
+                    rpc: ConversationLockRelease { npc, holder: None },
+                });
+            }
+            local.active = None;
+            break;
+        }
+    }
+}
+
+//shows the conversations of other players if they are close enough to hear them
+pub(super) fn receive_conversations(
+    time: Res<Time>,
+    listener: Listener,
+    index: Option<Res<EntityIndex>>,
+    locks: Res<ConversationLocks>,
+    mut updates: EventReader<ConversationUpdate>,
+    npcs: Query<(&Persona, &GlobalTransform)>,
+    mut subtitles: ResMut<Subtitles>,
+) {
+    let (Some(networking), Some(index)) = (listener.networking.as_ref(), index) else {
+        return;
+    };
+    let position = listener.position();
+    let now = time.elapsed_seconds_f64();
+
+    for update in updates.read() {
+        //this machine's own lines were shown when they were published
+        let Some(speaker) = update
+            .speaker
+            .filter(|speaker| *speaker != networking.player_id)
+        else {
+            continue;
+        };
+        if locks.holder(update.npc) != Some(speaker) {
+            println!(
+                "Ignored conversation with npc {} from {:?}, which doesn't hold its lock",
+                update.npc, speaker
+            );
+            continue;
+        }
+
+        let Some((entity, (persona, transform))) = index
+            .get(update.npc)
+            .and_then(|entity| npcs.get(entity).ok().map(|npc| (entity, npc)))
+        else {
+            continue;
+        };
+        if position
+            .is_some_and(|position| position.distance(transform.translation()) > HEARING_RADIUS)
+        {
+            continue;
+        }
+
+        let player = format!("Player {}", speaker.0);
+        subtitles.show(entity, &persona.name, &player, &update.event, now);
+    }
+}
+
+pub(super) fn spawn_subtitle_text(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 24.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(40.0),
+            left: Val::Px(40.0),
+            ..default()
+        }),
+        SubtitleText,
+    ));
+}
+
+//lines disappear once they are old, or the player walks out of earshot of the npc
+pub(super) fn show_subtitles(
+    time: Res<Time>,
+    listener: Listener,
+    mut subtitles: ResMut<Subtitles>,
+    npcs: Query<&GlobalTransform, With<Persona>>,
+    mut texts: Query<&mut Text, With<SubtitleText>>,
+) {
+    let now = time.elapsed_seconds_f64();
+    let position = listener.position();
+    subtitles.lines.retain(|line| {
+        let in_earshot = match (position, npcs.get(line.npc)) {
+            (Some(position), Ok(npc)) => position.distance(npc.translation()) <= HEARING_RADIUS,
+            (_, Ok(_)) => true,
+            (_, Err(_)) => false,
+        };
+        in_earshot && now - line.shown_at < SUBTITLE_DURATION
+    });
+
+    let value = subtitles
+        .lines
+        .iter()
+        .map(|line| match line.emotion {
+            Some(emotion) => {
+                let emotion = format!("{:?}", emotion).to_lowercase();
+                format!("{} ({}): {}", line.speaker, emotion, line.text)
+            }
+            None => format!("{}: {}", line.speaker, line.text),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    for mut text in texts.iter_mut() {
+        text.sections[0].value = value.clone();
+    }
+}
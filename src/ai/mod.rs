@@ -1,15 +1,23 @@
 use bevy::prelude::*;
 use rs_openai::OpenAI;
 
+use crate::networking::rpc::AddRpc;
+use crate::networking::transport::Reliability;
 use crate::Config;
+use conversation::*;
 use utils::player_transcriber::*;
+use utils::voice_chat::*;
 
+pub mod conversation;
 pub mod persona;
 pub mod utils;
 
 pub struct AiPlugin {
     pub openapi_key: String,
     pub openapi_org: Option<String>,
+    //skips everything that needs a microphone or keyboard, used by the dedicated server
+    pub headless: bool,
+    pub voice_chat: VoiceChatSettings,
 }
 
 impl AiPlugin {
@@ -17,6 +25,8 @@ impl AiPlugin {
         Self {
             openapi_key: config.openapi_key,
             openapi_org: None,
+            headless: false,
+            voice_chat: VoiceChatSettings::default(),
         }
     }
 }
@@ -26,10 +36,53 @@ impl Plugin for AiPlugin {
         let api_key = self.openapi_key.clone();
         let api_org = self.openapi_org.clone();
 
+        app.insert_resource(OpenAPI::new(api_key, api_org));
+
+        //the owner of an npc hands out its conversation lock, even on a dedicated server
+        app.add_rpc::<ConversationLockRequest>(Reliability::Reliable)
+            .add_rpc::<ConversationLockRelease>(Reliability::Reliable)
+            .add_rpc::<ConversationLockState>(Reliability::Reliable)
+            .add_rpc::<ConversationUpdate>(Reliability::Reliable)
+            .init_resource::<ConversationLocks>()
+            .add_systems(
+                Update,
+                (grant_conversation_locks, receive_conversation_locks).chain(),
+            );
+
+        if self.headless {
+            return;
+        }
+
+        let (voice_playback, voice_output) = VoicePlayback::new();
         app.add_systems(Update, consume_idle_mic_input)
             .add_systems(Update, press_transcribe_key)
-            .insert_resource(OpenAPI::new(api_key, api_org))
-            .insert_resource(PlayerTranscriber::new());
+            .insert_resource(PlayerTranscriber::new())
+            .add_rpc::<VoiceFrame>(Reliability::Unreliable)
+            .insert_resource(self.voice_chat)
+            .init_resource::<VoiceChat>()
+            .insert_resource(voice_playback)
+            .insert_non_send_resource(voice_output)
+            .add_systems(
+                Update,
+                (
+                    capture_voice.before(consume_idle_mic_input),
+                    (toggle_mute, receive_voice, spatialize_voices).chain(),
+                ),
+            )
+            .add_event::<TalkToNpc>()
+            .init_resource::<LocalConversation>()
+            .init_resource::<Subtitles>()
+            .add_systems(Startup, spawn_subtitle_text)
+            .add_systems(
+                Update,
+                (
+                    start_conversations.after(receive_conversation_locks),
+                    publish_conversation,
+                    receive_conversations,
+                    show_subtitles,
+                )
+                    .chain(),
+            );
     }
 }
 
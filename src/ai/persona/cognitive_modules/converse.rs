@@ -1,5 +1,6 @@
 use lazy_static::lazy_static;
 use rs_openai::chat::{ChatCompletionMessageRequestBuilder, CreateChatRequestBuilder, Role};
+use serde::{Deserialize, Serialize};
 use tokio::task::JoinHandle;
 
 use crate::ai::utils::prompt_template::PromptTemplate;
@@ -8,9 +9,87 @@ use crate::utils::Rng;
 use crate::RT;
 use std::sync::Mutex;
 
+//the emotions the npc is asked to end its replies with, see emotional_expression.txt
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Emotion {
+    Happy,
+    Sad,
+    Angry,
+    Scared,
+    Disgusted,
+    Surprised,
+    Calm,
+    Excited,
+    Loving,
+    Hating,
+    Hurt,
+    Confused,
+}
+
+impl Emotion {
+    //the last emotion tag in a reply
+    pub fn find_in(response: &str) -> Option<Self> {
+        response.split_ascii_whitespace().rev().find_map(|word| {
+            Some(match word.trim_matches(|c: char| !c.is_alphabetic()) {
+                "HAPPY" => Emotion::Happy,
+                "SAD" => Emotion::Sad,
+                "ANGRY" => Emotion::Angry,
+                "SCARED" => Emotion::Scared,
+                "DISGUSTED" => Emotion::Disgusted,
+                "SURPRISED" => Emotion::Surprised,
+                "CALM" => Emotion::Calm,
+                "EXCITED" => Emotion::Excited,
+                "LOVING" => Emotion::Loving,
+                "HATING" => Emotion::Hating,
+                "HURT" => Emotion::Hurt,
+                "CONFUSED" => Emotion::Confused,
+                _ => return None,
+            })
+        })
+    }
+}
+
+//what happened in a conversation, collected so that it can be shown to other players
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ConversationEvent {
+    //what the player said, as transcribed
+    PlayerLine(String),
+    NpcLine {
+        text: String,
+        emotion: Option<Emotion>,
+    },
+    End,
+}
+
 #[derive(Default)]
 pub struct ConversationHandler {
     handle: Mutex<Option<JoinHandle<()>>>,
+    events: Mutex<Vec<ConversationEvent>>,
+}
+
+impl ConversationHandler {
+    pub fn is_running(&self) -> bool {
+        self.handle
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+    }
+
+    pub fn stop(&self) {
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+
+    //everything that happened since the last call
+    pub fn drain_events(&self) -> Vec<ConversationEvent> {
+        self.events.lock().unwrap().drain(..).collect()
+    }
+
+    fn push(&self, event: ConversationEvent) {
+        self.events.lock().unwrap().push(event);
+    }
 }
 
 impl Persona {
@@ -95,6 +174,8 @@ impl Persona {
                 .transcribe_player_async(open_api)
                 .await
                 .unwrap();
+            self.conversation_handler
+                .push(ConversationEvent::PlayerLine(response.clone()));
 
             let associations = associative.find_association_in_text(&response);
             let associations = get_string(&associations.iter().map(|a| a.clone().into()).collect());
@@ -121,6 +202,7 @@ impl Persona {
                 );
 
                 let response = response.choices[0].message.content.as_str();
+                let full_response = response;
 
                 macro_rules! vocalize {
                     () => {
@@ -131,6 +213,10 @@ impl Persona {
                             .collect::<Vec<&str>>()
                             .join(" ");
 
+                        self.conversation_handler.push(ConversationEvent::NpcLine {
+                            text: response.clone(),
+                            emotion: Emotion::find_in(&full_response),
+                        });
                         self.voice.tts(response.as_str()).await.unwrap();
                     };
                 }
@@ -155,6 +241,7 @@ impl Persona {
                     }
                     x if x.contains("END") => {
                         vocalize!();
+                        self.conversation_handler.push(ConversationEvent::End);
                         break 'a;
                     }
                     _ => {
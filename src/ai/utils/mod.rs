@@ -1,2 +1,3 @@
 pub mod player_transcriber;
 pub mod prompt_template;
+pub mod voice_chat;
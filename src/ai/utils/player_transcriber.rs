@@ -35,6 +35,8 @@ pub struct PlayerTranscriber {
     key_press_waiter: Arc<AtomicBool>,
     is_transcribing: AtomicBool,
     mic_input: Mutex<MicInput>,
+    sample_rate: u32,
+    channels: u16,
 }
 
 struct MicInput {
@@ -65,6 +67,8 @@ impl PlayerTranscriber {
             .default_input_config()
             .expect("no default input config available");
         let stream_config: StreamConfig = stream_config.into();
+        let sample_rate = stream_config.sample_rate.0;
+        let channels = stream_config.channels;
 
         let latency_frames = stream_config.sample_rate.0 as f32;
         let latency_samples = latency_frames as usize * stream_config.channels as usize;
@@ -101,6 +105,32 @@ impl PlayerTranscriber {
             key_press_waiter: Arc::new(AtomicBool::new(false)),
             mic_input,
             is_transcribing: AtomicBool::new(false),
+            sample_rate,
+            channels,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    //moves the interleaved samples captured since the last call into buffer, used by voice chat
+    //  while nothing is being transcribed
+    pub fn take_idle_input(&self, buffer: &mut Vec<f32>) {
+        if self.is_transcribing() {
+            return;
+        }
+        //the transcription holds the lock while it listens
+        let Ok(mut mic_input) = self.mic_input.try_lock() else {
+            return;
+        };
+
+        while let Some(sample) = mic_input.consumer.pop() {
+            buffer.push(sample);
         }
     }
 
@@ -0,0 +1,507 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::StreamConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use super::player_transcriber::PlayerTranscriber;
+use crate::networking::relevancy::Viewer;
+use crate::networking::rpc::{Rpc, RpcTarget, SendRpc};
+use crate::networking::transport::PeerId;
+use crate::networking::NetworkingState;
+
+//voice is sent as 8 bit mu-law at this rate, 16 kilobytes a second per speaker
+const VOICE_SAMPLE_RATE: u32 = 16000;
+//20ms of voice per frame
+const FRAME_SAMPLES: usize = 320;
+//a speaker starts playing once this much is buffered, so late frames don't cut the voice up
+const PLAYBACK_DELAY: usize = 3 * FRAME_SAMPLES;
+//a speaker that falls further behind than this skips ahead
+const MAX_BUFFERED: usize = 25 * FRAME_SAMPLES;
+const MU: f32 = 255.0;
+
+#[derive(Resource, Clone, Copy)]
+pub struct VoiceChatSettings {
+    //players further apart than this don't hear each other
+    pub range: f32,
+    pub push_to_talk: KeyCode,
+    //mutes or unmutes the player under the crosshair
+    pub mute: KeyCode,
+}
+
+impl Default for VoiceChatSettings {
+    fn default() -> Self {
+        Self {
+            range: 25.0,
+            push_to_talk: KeyCode::KeyV,
+            mute: KeyCode::KeyM,
+        }
+    }
+}
+
+//sent unreliably straight to every player in range of the speaker
+#[derive(Event, Clone, Serialize, Deserialize)]
+pub struct VoiceFrame {
+    //frames arriving after a newer one are dropped
+    pub sequence: u32,
+    pub samples: Vec<u8>,
+    #[serde(skip)]
+    speaker: Option<PeerId>,
+}
+
+impl Rpc for VoiceFrame {
+    const NAME: &'static str = "VoiceFrame";
+
+    fn set_sender(&mut self, sender: PeerId) {
+        self.speaker = Some(sender);
+    }
+}
+
+/**
+ * Proximity voice chat. While the push to talk key is held the microphone input the
+ * PlayerTranscriber captures is downmixed to mono, resampled and sent in frames to the
+ * players whose viewer is within range. Received voices are mixed into their own output
+ * stream, panned and attenuated by where the speaker's player is relative to this player's.
+ * Nothing is sent while the player is talking to an npc, the transcription has the microphone.
+ */
+#[derive(Resource, Default)]
+pub struct VoiceChat {
+    muted: HashSet<PeerId>,
+    talking: bool,
+    //mono samples at VOICE_SAMPLE_RATE waiting for a full frame
+    pending: Vec<f32>,
+    //sum and count of the microphone samples averaged into the next pending sample
+    accumulated: (f32, u32),
+    resample_phase: f32,
+    sequence: u32,
+    last_received: HashMap<PeerId, u32>,
+}
+
+impl VoiceChat {
+    pub fn mute(&mut self, peer: PeerId) {
+        self.muted.insert(peer);
+    }
+
+    pub fn unmute(&mut self, peer: PeerId) {
+        self.muted.remove(&peer);
+    }
+
+    pub fn is_muted(&self, peer: PeerId) -> bool {
+        self.muted.contains(&peer)
+    }
+
+    //downmixes interleaved microphone samples and averages them down to VOICE_SAMPLE_RATE
+    fn push_input(&mut self, input: &[f32], channels: u16, sample_rate: u32) {
+        let step = sample_rate as f32 / VOICE_SAMPLE_RATE as f32;
+        for frame in input.chunks(channels.max(1) as usize) {
+            self.accumulated.0 += frame.iter().sum::<f32>() / frame.len() as f32;
+            self.accumulated.1 += 1;
+            self.resample_phase += 1.0;
+
+            if self.resample_phase >= step {
+                let (sum, count) = self.accumulated;
+                self.pending.push(sum / count as f32);
+                self.accumulated = (0.0, 0);
+                self.resample_phase -= step;
+            }
+        }
+    }
+}
+
+fn encode(samples: &[f32]) -> Vec<u8> {
+    samples
+        .iter()
+        .map(|sample| {
+            let sample = sample.clamp(-1.0, 1.0);
+            let compressed = (1.0 + MU * sample.abs()).ln() / (1.0 + MU).ln();
+            let level = (compressed * 127.0).round() as u8;
+            if sample < 0.0 {
+                level | 0x80
+            } else {
+                level
+            }
+        })
+        .collect()
+}
+
+fn decode(bytes: &[u8]) -> impl Iterator<Item = f32> + '_ {
+    bytes.iter().map(|byte| {
+        let compressed = (byte & 0x7f) as f32 / 127.0;
+        let sample = ((1.0 + MU).powf(compressed) - 1.0) / MU;
+        if byte & 0x80 != 0 {
+            -sample
+        } else {
+            sample
+        }
+    })
+}
+
+#[derive(Default)]
+struct Track {
+    samples: VecDeque<f32>,
+    playing: bool,
+    //how far playback is between the two oldest samples
+    phase: f32,
+    //left and right volume
+    gains: (f32, f32),
+}
+
+#[derive(Default)]
+struct Mixer {
+    tracks: HashMap<PeerId, Track>,
+}
+
+impl Mixer {
+    fn mix(&mut self, data: &mut [f32], channels: usize, sample_rate: u32) {
+        data.fill(0.0);
+        let step = VOICE_SAMPLE_RATE as f32 / sample_rate as f32;
+
+        for track in self.tracks.values_mut() {
+            if !track.playing {
+                if track.samples.len() < PLAYBACK_DELAY {
+                    continue;
+                }
+                track.playing = true;
+            }
+
+            let (left, right) = track.gains;
+            for frame in data.chunks_mut(channels.max(1)) {
+                let Some(&sample) = track.samples.front() else {
+                    //waits for the buffer to fill up again
+                    track.playing = false;
+                    break;
+                };
+                match frame {
+                    [mono] => *mono += sample * (left + right) * 0.5,
+                    [left_out, right_out, ..] => {
+                        *left_out += sample * left;
+                        *right_out += sample * right;
+                    }
+                    _ => {}
+                }
+
+                track.phase += step;
+                while track.phase >= 1.0 && !track.samples.is_empty() {
+                    track.samples.pop_front();
+                    track.phase -= 1.0;
+                }
+            }
+        }
+
+        for sample in data.iter_mut() {
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+    }
+}
+
+//plays the voices of other players on the default output device
+#[derive(Resource)]
+pub struct VoicePlayback {
+    mixer: Arc<Mutex<Mixer>>,
+}
+
+//keeps the output stream playing until the app is dropped, cpal streams can't leave the thread
+//  they were made on so this is a non send resource
+pub struct VoiceOutput {
+    _stream: Option<cpal::Stream>,
+}
+
+impl VoicePlayback {
+    pub fn new() -> (Self, VoiceOutput) {
+        let mixer = Arc::new(Mutex::new(Mixer::default()));
+
+        let Some(speaker) = cpal::default_host().default_output_device() else {
+            println!("No output device available, voice chat can't be heard");
+            return (Self { mixer }, VoiceOutput { _stream: None });
+        };
+        let stream_config: StreamConfig = match speaker.default_output_config() {
+            Ok(stream_config) => stream_config.into(),
+            Err(error) => {
+                println!(
+                    "No default output config available for voice chat, {}",
+                    error
+                );
+                return (Self { mixer }, VoiceOutput { _stream: None });
+            }
+        };
+        let channels = stream_config.channels as usize;
+        let sample_rate = stream_config.sample_rate.0;
+
+        let output = mixer.clone();
+        let output_stream = speaker.build_output_stream(
+            &stream_config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                output.lock().unwrap().mix(data, channels, sample_rate);
+            },
+            move |err| {
+                println!("An error occurred on the voice chat output stream, {}", err);
+            },
+            None,
+        );
+
+        let output_stream = match output_stream {
+            Ok(output_stream) => output_stream,
+            Err(error) => {
+                println!("Failed to open the voice chat output stream, {}", error);
+                return (Self { mixer }, VoiceOutput { _stream: None });
+            }
+        };
+        if let Err(error) = output_stream.play() {
+            println!("Failed to play voice chat, {}", error);
+        }
+
+        (
+            Self { mixer },
+            VoiceOutput {
+                _stream: Some(output_stream),
+            },
+        )
+    }
+}
+
+//has to run before consume_idle_mic_input, which throws away whatever is left
+pub fn capture_voice(
+    settings: Res<VoiceChatSettings>,
+    keys: Res<ButtonInput<KeyCode>>,
+    player_transcriber: Res<PlayerTranscriber>,
+    networking: Option<Res<NetworkingState>>,
+    viewers: Query<(&Viewer, &Transform)>,
+    mut voice_chat: ResMut<VoiceChat>,
+    mut frames: EventWriter<SendRpc<VoiceFrame>>,
+) {
+    let Some(networking) = networking else {
+        return;
+    };
+
+    let mut input = Vec::new();
+    player_transcriber.take_idle_input(&mut input);
+
+    voice_chat.talking =
+        keys.pressed(settings.push_to_talk) && !player_transcriber.is_transcribing();
+    if !voice_chat.talking {
+        voice_chat.pending.clear();
+        return;
+    }
+    voice_chat.push_input(
+        &input,
+        player_transcriber.channels(),
+        player_transcriber.sample_rate(),
+    );
+
+    //players that aren't relevant to this one have no viewer here and are out of range anyway
+    let position = viewers
+        .iter()
+        .find(|(viewer, _)| viewer.peer == networking.player_id)
+        .map(|(_, transform)| transform.translation);
+    let listeners: Vec<PeerId> = match position {
+        Some(position) => viewers
+            .iter()
+            .filter(|(viewer, transform)| {
+                viewer.peer != networking.player_id
+                    && networking.active_players.contains(&viewer.peer)
+                    && transform.translation.distance(position) <= settings.range
+            })
+            .map(|(viewer, _)| viewer.peer)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    while voice_chat.pending.len() >= FRAME_SAMPLES {
+        let samples = encode(&voice_chat.pending[..FRAME_SAMPLES]);
+        voice_chat.pending.drain(..FRAME_SAMPLES);
+        voice_chat.sequence += 1;
+
+        for listener in listeners.iter() {
+            frames.send(SendRpc {
+                target: RpcTarget::Peer(*listener),
+                rpc: VoiceFrame {
+                    sequence: voice_chat.sequence,
+                    samples: samples.clone(),
+                    speaker: None,
+                },
+            });
+        }
+    }
+}
+
+//players are only muted on this machine, the speaker keeps sending
+pub fn toggle_mute(
+    settings: Res<VoiceChatSettings>,
+    keys: Res<ButtonInput<KeyCode>>,
+    rapier_context: Res<RapierContext>,
+    cameras: Query<(Entity, &GlobalTransform), With<Camera3d>>,
+    viewers: Query<&Viewer>,
+    mut voice_chat: ResMut<VoiceChat>,
+) {
+    if !keys.just_pressed(settings.mute) {
+        return;
+    }
+    let Ok((camera, transform)) = cameras.get_single() else {
+        return;
+    };
+
+    let filter = QueryFilter::default().exclude_collider(camera);
+    let hit = rapier_context.cast_ray(
+        transform.translation(),
+        transform.forward(),
+        settings.range,
+        true,
+        filter,
+    );
+    let Some(viewer) = hit.and_then(|(entity, _)| viewers.get(entity).ok()) else {
+        return;
+    };
+
+    if voice_chat.is_muted(viewer.peer) {
+        voice_chat.unmute(viewer.peer);
+        println!("Unmuted player {}", viewer.peer.0);
+    } else {
+        voice_chat.mute(viewer.peer);
+        println!("Muted player {}", viewer.peer.0);
+    }
+}
+
+pub fn receive_voice(
+    networking: Option<Res<NetworkingState>>,
+    playback: Res<VoicePlayback>,
+    viewers: Query<&Viewer>,
+    mut voice_chat: ResMut<VoiceChat>,
+    mut frames: EventReader<VoiceFrame>,
+) {
+    let Some(networking) = networking else {
+        return;
+    };
+    let mut mixer = playback.mixer.lock().unwrap();
+
+    for frame in frames.read() {
+        let Some(speaker) = frame.speaker else {
+            continue;
+        };
+        if speaker == networking.player_id
+            || voice_chat.is_muted(speaker)
+            || frame.samples.len() > FRAME_SAMPLES
+        {
+            continue;
+        }
+        //a speaker whose player isn't relevant here has nowhere to be heard from, and the
+        //  speaker only sends to players near it, so its frames are dropped instead of buffered
+        if !viewers.iter().any(|viewer| viewer.peer == speaker) {
+            continue;
+        }
+        if voice_chat
+            .last_received
+            .get(&speaker)
+            .is_some_and(|last| frame.sequence <= *last)
+        {
+            continue;
+        }
+        voice_chat.last_received.insert(speaker, frame.sequence);
+
+        let track = mixer.tracks.entry(speaker).or_default();
+        track.samples.extend(decode(&frame.samples));
+        let excess = track.samples.len().saturating_sub(MAX_BUFFERED);
+        track.samples.drain(..excess);
+    }
+
+    //players that left or were muted stop right away
+    mixer
+        .tracks
+        .retain(|peer, _| networking.active_players.contains(peer) && !voice_chat.is_muted(*peer));
+    let active_players = &networking.active_players;
+    voice_chat
+        .last_received
+        .retain(|peer, _| active_players.contains(peer));
+}
+
+//pans and attenuates every voice by where the Transform of its speaker's player is relative to this player's
+pub fn spatialize_voices(
+    settings: Res<VoiceChatSettings>,
+    networking: Option<Res<NetworkingState>>,
+    playback: Res<VoicePlayback>,
+    viewers: Query<(&Viewer, &Transform)>,
+) {
+    let Some(networking) = networking else {
+        return;
+    };
+    let listener = viewers
+        .iter()
+        .find(|(viewer, _)| viewer.peer == networking.player_id)
+        .map(|(_, transform)| *transform);
+
+    let mut mixer = playback.mixer.lock().unwrap();
+    for (peer, track) in mixer.tracks.iter_mut() {
+        let speaker = viewers
+            .iter()
+            .find(|(viewer, _)| viewer.peer == *peer)
+            .map(|(_, transform)| transform.translation);
+
+        track.gains = match (listener, speaker) {
+            (Some(listener), Some(speaker)) => {
+                let offset = speaker - listener.translation;
+                let volume = (1.0 - offset.length() / settings.range).clamp(0.0, 1.0);
+                let pan = listener.right().dot(offset.normalize_or_zero());
+                (
+                    volume * ((1.0 - pan) / 2.0).sqrt(),
+                    volume * ((1.0 + pan) / 2.0).sqrt(),
+                )
+            }
+            //the speaker stopped being relevant, what it already said is dropped with it
+            (_, None) => {
+                track.samples.clear();
+                (0.0, 0.0)
+            }
+            //this player has no body yet, voices are kept until there is somewhere to hear them from
+            (None, Some(_)) => (0.0, 0.0),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mu_law_round_trips_within_its_step_size() {
+        let samples: Vec<f32> = (-100..=100).map(|i| i as f32 / 100.0).collect();
+        let decoded: Vec<f32> = decode(&encode(&samples)).collect();
+
+        for (sample, decoded) in samples.iter().zip(decoded.iter()) {
+            //steps grow with the level, quiet samples are kept the most precisely
+            let tolerance = 0.005 + sample.abs() * 0.05;
+            assert!(
+                (sample - decoded).abs() <= tolerance,
+                "{} decoded as {}",
+                sample,
+                decoded
+            );
+        }
+    }
+
+    #[test]
+    fn mu_law_keeps_the_sign_and_clamps() {
+        assert_eq!(encode(&[0.0]), vec![0]);
+        assert_eq!(encode(&[1.0, -1.0, 2.0, -2.0]), vec![127, 255, 127, 255]);
+
+        let decoded: Vec<f32> = decode(&[127, 255]).collect();
+        assert!((decoded[0] - 1.0).abs() < 1e-5);
+        assert!((decoded[1] + 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn input_is_downmixed_and_resampled() {
+        let mut voice_chat = VoiceChat::default();
+        //one second of stereo at twice the voice rate, left and right average to 0.5
+        let input: Vec<f32> = std::iter::repeat_n([1.0, 0.0], VOICE_SAMPLE_RATE as usize * 2)
+            .flatten()
+            .collect();
+        voice_chat.push_input(&input, 2, VOICE_SAMPLE_RATE * 2);
+
+        assert_eq!(voice_chat.pending.len(), VOICE_SAMPLE_RATE as usize);
+        assert!(voice_chat
+            .pending
+            .iter()
+            .all(|sample| (sample - 0.5).abs() < 1e-6));
+    }
+}
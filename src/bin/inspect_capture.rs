@@ -0,0 +1,43 @@
+/*!
+ * Prints a packet capture written with --capture, see networking::inspect.
+ * Only pulls in the protocol, wire and capture file modules, so it builds and runs
+ * without the game's audio, steam or ai.
+ *
+ * usage: inspect_capture file
+ */
+use std::path::Path;
+
+#[path = "../networking/transport/capture_file.rs"]
+mod capture_file;
+#[path = "../networking/inspect.rs"]
+mod inspect;
+#[path = "../networking/transport/packet.rs"]
+mod packet;
+#[path = "../networking/protocol.rs"]
+mod protocol;
+//the inspector only reads packets, so the helpers writing them go unused here
+#[allow(dead_code)]
+#[path = "../networking/wire.rs"]
+mod wire;
+
+//mirrors the networking module, inspect reaches the capture types through its transport module
+mod transport {
+    pub use super::capture_file::*;
+    pub use super::packet::*;
+}
+
+use packet::{PeerId, Reliability};
+use protocol::EventType::*;
+use protocol::*;
+use wire::*;
+
+fn main() {
+    let Some(path) = std::env::args().nth(1) else {
+        println!("usage: inspect_capture file");
+        return;
+    };
+
+    if let Err(error) = inspect::print_capture(Path::new(&path)) {
+        println!("Failed to read the capture {}, {}", path, error);
+    }
+}
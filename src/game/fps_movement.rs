@@ -1,7 +1,14 @@
 use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use super::fps_camera::FPSCamera;
+use super::prediction::InputHistory;
+use crate::networking::{NetworkingState, SynchronizedSlave};
+
+//top horizontal speed of players, also used to validate players moved by other machines
+pub const PLAYER_SPEED: f32 = 5.6;
+pub const PLAYER_POWER: f32 = 300.0;
 
 #[derive(Component)]
 pub struct FPSMovement {
@@ -9,36 +16,205 @@ pub struct FPSMovement {
     pub power: f32,
 }
 
+//the input of one fixed tick, kept so it can be replayed after a correction from the authority
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub struct PlayerInput {
+    pub sequence: u32,
+    pub direction: Vec2,
+    pub jump: bool,
+    pub yaw: f32,
+}
+
+//the physical body of a player, it is moved by step_player instead of rapier's integration
+//  so that replaying inputs after a correction moves it exactly like the first time,
+//  a dynamic body would be moved again by rapier after every step and every replay.
+//  Being kinematic it still pushes dynamic props around but is no longer knocked back by them
+pub fn player_body() -> impl Bundle {
+    (
+        RigidBody::KinematicPositionBased,
+        Collider::cuboid(0.2, 1.4, 0.2),
+        LockedAxes::ROTATION_LOCKED,
+        Damping {
+            linear_damping: 4.,
+            angular_damping: 1.0,
+        },
+        FPSMovement {
+            speed: PLAYER_SPEED,
+            power: PLAYER_POWER,
+        },
+    )
+}
+
+//jump presses are latched because FixedUpdate can run zero or several times in a frame
+#[derive(Resource, Default)]
+pub struct JumpLatch(bool);
+
+pub fn latch_jump(mut latch: ResMut<JumpLatch>, key: Res<ButtonInput<KeyCode>>) {
+    if key.just_pressed(KeyCode::Space) {
+        latch.0 = true;
+    }
+}
+
+//players moved by this machine's keyboard, slaves are owned by another machine and only predicted here
+type LocalPlayers<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static mut Transform,
+        &'static Collider,
+        &'static mut Damping,
+        &'static FPSCamera,
+        &'static FPSMovement,
+        &'static mut Velocity,
+        Option<&'static mut InputHistory>,
+        Option<&'static SynchronizedSlave>,
+    ),
+>;
+
+//runs in FixedUpdate before rapier, which steps in the same schedule
 pub fn player_movement(
     time: Res<Time>,
-    rapier_context: Res<RapierContext>,
-    mut movement_query: Query<(
-        &Transform,
-        &mut Damping,
-        &FPSCamera,
-        &FPSMovement,
-        &mut Velocity,
-    )>,
+    mut rapier_context: ResMut<RapierContext>,
+    rapier_config: Res<RapierConfiguration>,
+    networking: Option<Res<NetworkingState>>,
+    mut latch: ResMut<JumpLatch>,
+    mut movement_query: LocalPlayers,
     key: Res<ButtonInput<KeyCode>>,
 ) {
     let res = movement_query.iter_mut().next();
     if res.is_none() {
         return;
     }
-    let (transform, mut damping, fps_camera, fps_movement, mut velocity) = res.unwrap();
+    let (
+        mut transform,
+        collider,
+        mut damping,
+        fps_camera,
+        fps_movement,
+        mut velocity,
+        history,
+        slave,
+    ) = res.unwrap();
 
     let mut dir = Vec2::new(0.0, 0.0);
 
-    let mut air_mod = 1.0;
+    if key.pressed(KeyCode::KeyW) {
+        dir.y -= 1.0;
+    }
+    if key.pressed(KeyCode::KeyS) {
+        dir.y += 1.0;
+    }
+    if key.pressed(KeyCode::KeyA) {
+        dir.x -= 1.0;
+    }
+    if key.pressed(KeyCode::KeyD) {
+        dir.x += 1.0;
+    }
+
+    let input = PlayerInput {
+        sequence: 0,
+        direction: dir,
+        jump: latch.0,
+        yaw: fps_camera.rotation.y,
+    };
+    latch.0 = false;
+
+    step_player(
+        &input,
+        &mut rapier_context,
+        rapier_config.gravity,
+        collider,
+        fps_movement,
+        PlayerBody {
+            transform: &mut transform,
+            damping: &mut damping,
+            velocity: &mut velocity,
+        },
+        time.delta_seconds(),
+    );
+
+    //a player owned by another machine is predicted locally and its inputs are sent to the owner
+    if let (Some(mut history), Some(slave), Some(networking)) = (history, slave, networking) {
+        history.push(input);
+        networking.send_input(slave.owner(), history.unacknowledged_bytes());
+    }
+}
 
+pub fn is_grounded(rapier_context: &RapierContext, translation: Vec3) -> bool {
     let hit = rapier_context.cast_ray(
-        transform.translation - 0.5,
+        translation - 0.5,
         Vec3::new(0.0, -1.0, 0.0),
         1.0,
         true,
         QueryFilter::only_fixed(),
     );
-    let grounded = hit.is_some();
+    hit.is_some()
+}
+
+//the components of a player that step_player moves
+pub struct PlayerBody<'a> {
+    pub transform: &'a mut Transform,
+    pub damping: &'a mut Damping,
+    pub velocity: &'a mut Velocity,
+}
+
+/**
+ * Moves a player by one fixed tick of input, shared by local movement, the authority
+ * and replays. Gravity and damping are integrated the way rapier integrates dynamic
+ * bodies, and the movement slides along fixed colliders instead of passing through them.
+ */
+pub fn step_player(
+    input: &PlayerInput,
+    rapier_context: &mut RapierContext,
+    gravity: Vec3,
+    collider: &Collider,
+    fps_movement: &FPSMovement,
+    body: PlayerBody,
+    delta_seconds: f32,
+) {
+    let PlayerBody {
+        transform,
+        damping,
+        velocity,
+    } = body;
+    let grounded = is_grounded(rapier_context, transform.translation);
+    apply_input(
+        input,
+        grounded,
+        fps_movement,
+        damping,
+        velocity,
+        delta_seconds,
+    );
+
+    velocity.linvel += gravity * delta_seconds;
+    velocity.linvel *= 1.0 / (1.0 + delta_seconds * damping.linear_damping);
+
+    //players are kinematic, so only fixed colliders block them, like the grounded check
+    let output = rapier_context.move_shape(
+        velocity.linvel * delta_seconds,
+        collider,
+        transform.translation,
+        transform.rotation,
+        0.0,
+        &MoveShapeOptions::default(),
+        QueryFilter::only_fixed(),
+        |_| {},
+    );
+    transform.translation += output.effective_translation;
+    //whatever a collision stopped is lost, like landing on the ground
+    velocity.linvel = output.effective_translation / delta_seconds;
+}
+
+fn apply_input(
+    input: &PlayerInput,
+    grounded: bool,
+    fps_movement: &FPSMovement,
+    damping: &mut Damping,
+    velocity: &mut Velocity,
+    delta_seconds: f32,
+) {
+    let mut air_mod = 1.0;
 
     if grounded {
         damping.linear_damping = 15.0;
@@ -47,22 +223,10 @@ pub fn player_movement(
         air_mod = 0.05;
     }
 
-    if key.pressed(KeyCode::KeyW) {
-        dir.y -= 1.0;
-    }
-    if key.pressed(KeyCode::KeyS) {
-        dir.y += 1.0;
-    }
-    if key.pressed(KeyCode::KeyA) {
-        dir.x -= 1.0;
-    }
-    if key.pressed(KeyCode::KeyD) {
-        dir.x += 1.0;
-    }
-
+    let mut dir = input.direction;
     dir = Vec2::new(
-        dir.x * f32::cos(-fps_camera.rotation.y) - dir.y * f32::sin(-fps_camera.rotation.y),
-        dir.x * f32::sin(-fps_camera.rotation.y) + dir.y * f32::cos(-fps_camera.rotation.y),
+        dir.x * f32::cos(-input.yaw) - dir.y * f32::sin(-input.yaw),
+        dir.x * f32::sin(-input.yaw) + dir.y * f32::cos(-input.yaw),
     );
 
     let mut halt = false;
@@ -77,10 +241,8 @@ pub fn player_movement(
         }
     }
 
-    if key.just_pressed(KeyCode::Space) {
-        if grounded {
-            velocity.linvel.y = 4.;
-        }
+    if input.jump && grounded {
+        velocity.linvel.y = 4.;
     }
     if halt {
         velocity.linvel.x = 0.0;
@@ -89,8 +251,8 @@ pub fn player_movement(
     }
     let vel = velocity.linvel.length();
     let mult = 1.0 / if vel > 0.1 { vel } else { 0.1 };
-    velocity.linvel.x += dir.x * fps_movement.power * mult * time.delta_seconds() * air_mod;
-    velocity.linvel.z += dir.y * fps_movement.power * mult * time.delta_seconds() * air_mod;
+    velocity.linvel.x += dir.x * fps_movement.power * mult * delta_seconds * air_mod;
+    velocity.linvel.z += dir.y * fps_movement.power * mult * delta_seconds * air_mod;
 
     let net_velocity = Vec2::new(velocity.linvel.x, velocity.linvel.z).length();
     let multiplier;
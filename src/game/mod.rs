@@ -2,8 +2,9 @@ use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
 
 mod fps_camera;
-mod fps_movement;
+pub mod fps_movement;
 mod lock_cursor;
+pub mod prediction;
 
 pub struct GamePlugin;
 
@@ -15,11 +16,62 @@ impl Plugin for GamePlugin {
         })
         .add_systems(Startup, setup_scene)
         .add_systems(Update, lock_cursor::lock_cursor_position)
+        .init_resource::<fps_movement::JumpLatch>()
         .add_systems(Update, fps_camera::move_camera)
-        .add_systems(Update, fps_movement::player_movement);
+        .add_systems(Update, fps_movement::latch_jump)
+        //rapier steps in FixedUpdate too, players move right before it
+        .add_systems(
+            FixedUpdate,
+            (
+                fps_movement::player_movement,
+                prediction::remote_player_movement,
+            )
+                .before(PhysicsSet::SyncBackend),
+        )
+        .add_systems(Update, prediction::spawn_local_controller)
+        .add_systems(Update, prediction::setup_controlled_players)
+        .add_systems(Update, prediction::despawn_departed_players)
+        .add_systems(Update, prediction::receive_inputs)
+        .add_systems(
+            Update,
+            prediction::reconcile_predicted_player.after(crate::networking::sync_slave_entities),
+        );
     }
 }
 
+//runs the authoritative simulation without rendering, input or a local player
+pub struct HeadlessGamePlugin;
+
+impl Plugin for HeadlessGamePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, setup_headless_scene)
+            .add_systems(
+                FixedUpdate,
+                prediction::remote_player_movement.before(PhysicsSet::SyncBackend),
+            )
+            .add_systems(Update, prediction::setup_controlled_players)
+            .add_systems(Update, prediction::despawn_departed_players)
+            .add_systems(Update, prediction::receive_inputs);
+    }
+}
+
+//the same physical world as setup_scene, without meshes, lights or a camera
+fn setup_headless_scene(mut commands: Commands) {
+    commands
+        .spawn(Collider::cuboid(1.0, 0.01, 1.0))
+        .insert(TransformBundle::from_transform(
+            Transform::from_xyz(0.0, -2.0, 0.0).with_scale(Vec3::splat(10.0)),
+        ));
+
+    commands
+        .spawn(RigidBody::Dynamic)
+        .insert(Collider::ball(0.5))
+        .insert(Restitution::coefficient(0.9))
+        .insert(TransformBundle::from_transform(Transform::from_xyz(
+            0.0, 5.0, 0.0,
+        )));
+}
+
 fn setup_scene(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -85,28 +137,15 @@ fn setup_scene(
 
                     ..default()
                 },
-                RigidBody::Dynamic,
-                LockedAxes::ROTATION_LOCKED_X
-                    | LockedAxes::ROTATION_LOCKED_Y
-                    | LockedAxes::ROTATION_LOCKED_Z,
+                fps_movement::player_body(),
                 Velocity {
                     linvel: Vec3::new(0.0, 0.0, 0.0),
                     angvel: Vec3::new(0.0, 0.0, 0.0),
                 },
-                Collider::cuboid(0.2, 1.4, 0.2),
                 fps_camera::FPSCamera {
                     rotation: Vec3::new(0., 0., 0.),
                     sensitivity: (0.173) / 300.0,
                 },
-                Damping {
-                    linear_damping: 4.,
-                    angular_damping: 1.0,
-                },
-                GravityScale(1.),
-                fps_movement::FPSMovement {
-                    speed: 5.6,
-                    power: 300.0,
-                },
             ));
         });
 }
@@ -0,0 +1,321 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use super::fps_camera::FPSCamera;
+use super::fps_movement::{
+    player_body, step_player, FPSMovement, PlayerBody, PlayerInput, PLAYER_SPEED,
+};
+use crate::networking::authority::SpeedLimit;
+use crate::networking::interpolation::{Predicted, SnapshotBuffer};
+use crate::networking::relevancy::Viewer;
+use crate::networking::transport::PeerId;
+use crate::networking::{NetworkingState, OwnedByHost, SynchronizedMaster, SynchronizedSlave};
+
+//inputs are resent until acknowledged, this caps how many go in one packet
+const MAX_INPUTS_PER_PACKET: usize = 8;
+
+//marks the player controlled by a peer, synced so the authority can report processed inputs
+#[derive(Component, Serialize, Deserialize)]
+pub struct PlayerController {
+    pub peer: PeerId,
+    pub last_processed_input: u32,
+}
+
+//inputs the local player has applied but the authority hasn't processed yet
+#[derive(Component, Default)]
+pub struct InputHistory {
+    pending: VecDeque<PlayerInput>,
+    next_sequence: u32,
+}
+
+impl InputHistory {
+    //sequences start at 1 so that a last processed input of 0 means none
+    pub fn push(&mut self, mut input: PlayerInput) {
+        self.next_sequence += 1;
+        input.sequence = self.next_sequence;
+        self.pending.push_back(input);
+    }
+
+    //the newest unacknowledged inputs, sent redundantly because they travel unreliably
+    pub fn unacknowledged_bytes(&self) -> Vec<u8> {
+        let skip = self.pending.len().saturating_sub(MAX_INPUTS_PER_PACKET);
+        let inputs: Vec<PlayerInput> = self.pending.iter().skip(skip).copied().collect();
+        bincode::serialize(&inputs).unwrap()
+    }
+}
+
+//inputs received from the peer controlling a player this machine is the authority for
+#[derive(Component, Default)]
+pub struct RemoteInputs {
+    queue: VecDeque<PlayerInput>,
+}
+
+//networks the local player once this machine can allocate static ids in a session,
+//  it is handed to the host, which moves it with this machine's inputs from then on
+pub fn spawn_local_controller(
+    networking: Option<ResMut<NetworkingState>>,
+    mut commands: Commands,
+    players: Query<Entity, (With<FPSCamera>, Without<PlayerController>)>,
+) {
+    let Some(mut networking) = networking else {
+        return;
+    };
+    if !networking.connected {
+        return;
+    }
+
+    for entity in players.iter() {
+        if networking
+            .create_networked_entity(&mut commands, &entity, true, false)
+            .is_none()
+        {
+            continue;
+        }
+        commands.entity(entity).insert((
+            PlayerController {
+                peer: networking.player_id,
+                last_processed_input: 0,
+            },
+            OwnedByHost,
+        ));
+    }
+}
+
+//players owned by this machine that don't have an input queue yet
+type UnqueuedPlayers<'w, 's> = Query<
+    'w,
+    's,
+    (Entity, &'static PlayerController),
+    (With<SynchronizedMaster>, Without<RemoteInputs>),
+>;
+//players synced from other machines that don't have an input history yet
+type UnpredictedPlayers<'w, 's> = Query<
+    'w,
+    's,
+    (Entity, &'static PlayerController),
+    (With<SynchronizedSlave>, Without<InputHistory>),
+>;
+//players that aren't used for relevancy yet
+type NewViewers<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static PlayerController,
+        Option<&'static FPSMovement>,
+    ),
+    Without<Viewer>,
+>;
+
+//gives the authority an input queue for every player controlled by another peer,
+//  and the controlling peer an input history for its own player
+pub fn setup_controlled_players(
+    networking: Option<Res<NetworkingState>>,
+    mut commands: Commands,
+    masters: UnqueuedPlayers,
+    slaves: UnpredictedPlayers,
+    viewers: NewViewers,
+    bodies: Query<Entity, (With<PlayerController>, Without<Collider>)>,
+) {
+    let Some(networking) = networking else {
+        return;
+    };
+
+    //players synced from other machines get the body the local player has,
+    //  so they can be hit and moved with step_player
+    for entity in bodies.iter() {
+        commands.entity(entity).insert(player_body());
+    }
+
+    //entities are only sent to a peer while they are near its player,
+    //  and players other machines move can't go faster than they could walk
+    for (entity, controller, movement) in viewers.iter() {
+        commands.entity(entity).insert((
+            Viewer {
+                peer: controller.peer,
+            },
+            SpeedLimit(movement.map_or(PLAYER_SPEED, |movement| movement.speed)),
+        ));
+    }
+
+    for (entity, controller) in masters.iter() {
+        if controller.peer != networking.player_id {
+            commands.entity(entity).insert(RemoteInputs::default());
+        }
+    }
+
+    for (entity, controller) in slaves.iter() {
+        if controller.peer == networking.player_id {
+            commands
+                .entity(entity)
+                .insert((InputHistory::default(), Predicted))
+                .remove::<SnapshotBuffer>();
+        }
+    }
+}
+
+//players outlive the host that owns them, but not the peer controlling them
+pub fn despawn_departed_players(
+    networking: Option<Res<NetworkingState>>,
+    mut query: Query<(&PlayerController, &mut SynchronizedMaster)>,
+) {
+    let Some(networking) = networking else {
+        return;
+    };
+
+    for (controller, mut master) in query.iter_mut() {
+        if controller.peer != networking.player_id
+            && !networking.active_players.contains(&controller.peer)
+        {
+            master.destroy();
+        }
+    }
+}
+
+pub fn receive_inputs(
+    networking: Option<ResMut<NetworkingState>>,
+    mut query: Query<(&PlayerController, &mut RemoteInputs)>,
+) {
+    let Some(mut networking) = networking else {
+        return;
+    };
+
+    for (sender, bytes) in networking.drain_inputs() {
+        let Ok(inputs) = bincode::deserialize::<Vec<PlayerInput>>(&bytes) else {
+            println!("Rejected malformed input from {:?}", sender);
+            continue;
+        };
+
+        for (controller, mut remote_inputs) in query.iter_mut() {
+            if controller.peer != sender {
+                continue;
+            }
+
+            //inputs are resent, so only ones newer than anything queued or processed are kept
+            let newest = remote_inputs
+                .queue
+                .back()
+                .map_or(controller.last_processed_input, |input| input.sequence);
+            for input in inputs.iter() {
+                if input.sequence > newest {
+                    remote_inputs.queue.push_back(*input);
+                }
+            }
+            break;
+        }
+    }
+}
+
+//moves players controlled by other peers, one queued input per fixed tick
+pub fn remote_player_movement(
+    time: Res<Time>,
+    mut rapier_context: ResMut<RapierContext>,
+    rapier_config: Res<RapierConfiguration>,
+    mut query: Query<(
+        &mut Transform,
+        &Collider,
+        &mut Damping,
+        &FPSMovement,
+        &mut Velocity,
+        &mut RemoteInputs,
+        &mut PlayerController,
+    )>,
+) {
+    for (
+        mut transform,
+        collider,
+        mut damping,
+        fps_movement,
+        mut velocity,
+        mut inputs,
+        mut controller,
+    ) in query.iter_mut()
+    {
+        let Some(input) = inputs.queue.pop_front() else {
+            continue;
+        };
+
+        step_player(
+            &input,
+            &mut rapier_context,
+            rapier_config.gravity,
+            collider,
+            fps_movement,
+            PlayerBody {
+                transform: &mut transform,
+                damping: &mut damping,
+                velocity: &mut velocity,
+            },
+            time.delta_seconds(),
+        );
+        controller.last_processed_input = input.sequence;
+    }
+}
+
+//the local player as synced from the authority, with everything step_player moves
+type PredictedPlayers<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static mut SynchronizedSlave,
+        &'static PlayerController,
+        &'static mut InputHistory,
+        &'static Collider,
+        &'static FPSMovement,
+        &'static mut Transform,
+        &'static mut Damping,
+        &'static mut Velocity,
+    ),
+>;
+
+/**
+ * When an authoritative state arrives for the local player, drops the inputs it
+ * already includes and replays the rest on top of it with step_player, the same
+ * step the authority and the live prediction use.
+ */
+pub fn reconcile_predicted_player(
+    fixed_time: Res<Time<Fixed>>,
+    mut rapier_context: ResMut<RapierContext>,
+    rapier_config: Res<RapierConfiguration>,
+    mut query: PredictedPlayers,
+) {
+    let delta_seconds = fixed_time.timestep().as_secs_f32();
+
+    for (
+        mut slave,
+        controller,
+        mut history,
+        collider,
+        fps_movement,
+        mut transform,
+        mut damping,
+        mut velocity,
+    ) in query.iter_mut()
+    {
+        if slave.take_update().is_none() {
+            continue;
+        }
+
+        history
+            .pending
+            .retain(|input| input.sequence > controller.last_processed_input);
+
+        for input in history.pending.iter() {
+            step_player(
+                input,
+                &mut rapier_context,
+                rapier_config.gravity,
+                collider,
+                fps_movement,
+                PlayerBody {
+                    transform: &mut transform,
+                    damping: &mut damping,
+                    velocity: &mut velocity,
+                },
+                delta_seconds,
+            );
+        }
+    }
+}
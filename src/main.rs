@@ -1,9 +1,12 @@
 #![feature(async_closure)]
+use bevy::app::ScheduleRunnerPlugin;
 use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
-use game::GamePlugin;
+use game::{GamePlugin, HeadlessGamePlugin};
 use serde::{Deserialize, Serialize};
 use std::env::set_var;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use tokio::runtime::Runtime;
 use toml;
 
@@ -15,6 +18,9 @@ mod rpg;
 mod utils;
 
 use ai::AiPlugin;
+use networking::session::{LobbyAddress, SessionCommand};
+use networking::transport::{NetworkConditions, TransportKind};
+use networking::NetworkingPlugin;
 use rpg::RPGPlugin;
 use utils::UtilPlugin;
 
@@ -27,7 +33,71 @@ struct Config {
     pub elevenlabs_key: String,
 }
 
+//how often the dedicated server runs its schedule
+const SERVER_TICK_RATE: f64 = 60.0;
+const DEFAULT_SERVER_ADDRESS: &str = "0.0.0.0:7777";
+//7778 is taken by lan discovery
+const DEFAULT_CLIENT_ADDRESS: &str = "0.0.0.0:7779";
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(path) = arg_value(&args, "--inspect") {
+        if let Err(error) = networking::inspect::print_capture(Path::new(path)) {
+            println!("Failed to read {}, {}", path, error);
+        }
+    } else if let Some(path) = arg_value(&args, "--replay") {
+        run_replay(PathBuf::from(path));
+    } else if args.iter().any(|arg| arg == "--server") {
+        run_server(&args);
+    } else {
+        run_client(&args);
+    }
+
+    std::process::exit(0);
+}
+
+//the value after a flag, e.g. the address in --bind 0.0.0.0:7777
+fn arg_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+        .map(|value| value.as_str())
+}
+
+//wraps the transport to simulate a bad connection if --simulate is given, e.g. --simulate latency=0.1,loss=0.05
+fn simulated(args: &[String], transport: TransportKind) -> TransportKind {
+    let Some(settings) = arg_value(args, "--simulate") else {
+        return transport;
+    };
+    let conditions = NetworkConditions::parse(settings)
+        .expect("invalid --simulate, expected e.g. latency=0.1,jitter=0.02,loss=0.05");
+
+    TransportKind::Conditioned {
+        inner: Box::new(transport),
+        conditions,
+        seed: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as usize,
+    }
+}
+
+/**
+ * Opens the game, usage: space_cowboy_rpg [--udp] [--bind address:port]
+ * [--connect address:port | --invite code] [--simulate conditions]. Players play over Steam
+ * unless --udp is given, --connect joins a dedicated server and always uses udp.
+ */
+fn run_client(args: &[String]) {
+    let mut networking = NetworkingPlugin::default();
+    if args.iter().any(|arg| arg == "--udp") || arg_value(args, "--connect").is_some() {
+        let bind_address = arg_value(args, "--bind")
+            .unwrap_or(DEFAULT_CLIENT_ADDRESS)
+            .parse()
+            .expect("invalid bind address");
+        networking.transport = TransportKind::Udp { bind_address };
+    }
+    networking.transport = simulated(args, networking.transport);
+
     let config = std::fs::read_to_string("config.toml").unwrap();
     let config: Config = toml::from_str(&config).unwrap();
     unsafe {
@@ -37,19 +107,110 @@ fn main() {
 
     let runtime = Runtime::new().unwrap();
 
-    App::new()
-        //.add_systems(Startup, test)
-        .insert_resource(RT(runtime))
+    let mut app = App::new();
+    //.add_systems(Startup, test)
+    app.insert_resource(RT(runtime))
         .add_plugins(DefaultPlugins)
-        .add_plugins(RapierPhysicsPlugin::<NoUserData>::default())
+        .add_plugins(RapierPhysicsPlugin::<NoUserData>::default().in_fixed_schedule())
         //.add_plugins(RapierDebugRenderPlugin::default())
+        .add_plugins(networking)
         .add_plugins(AiPlugin::from_config(config))
         .add_plugins(GamePlugin)
         .add_plugins(UtilPlugin)
-        .add_plugins(RPGPlugin)
-        .run();
+        .add_plugins(RPGPlugin);
 
-    std::process::exit(0);
+    if let Some(address) = arg_value(args, "--connect") {
+        let address = address.parse().expect("invalid server address");
+        app.world
+            .send_event(SessionCommand::Join(LobbyAddress::Lan(address)));
+    } else if let Some(code) = arg_value(args, "--invite") {
+        app.world
+            .send_event(SessionCommand::JoinInvite(code.to_string()));
+    }
+
+    app.run();
+}
+
+/**
+ * Runs without a window, audio or microphone and hosts a session over udp,
+ * usage: space_cowboy_rpg --server [--bind address:port] [--name name] [--capture file]
+ * [--simulate conditions]
+ */
+fn run_server(args: &[String]) {
+    let bind_address = arg_value(args, "--bind")
+        .unwrap_or(DEFAULT_SERVER_ADDRESS)
+        .parse()
+        .expect("invalid bind address");
+
+    let mut app = headless_app(NetworkingPlugin {
+        transport: simulated(args, TransportKind::Udp { bind_address }),
+        dedicated_server: true,
+        capture: arg_value(args, "--capture").map(PathBuf::from),
+        ..default()
+    });
+    //announces the server so players on the local network can find it
+    app.world.send_event(SessionCommand::Host {
+        name: arg_value(args, "--name")
+            .unwrap_or("Dedicated server")
+            .to_string(),
+    });
+
+    println!("Server listening on {}", bind_address);
+    app.run();
+}
+
+/**
+ * Feeds the packets a capture received into a headless app to reproduce a session,
+ * usage: space_cowboy_rpg --replay file
+ */
+fn run_replay(path: PathBuf) {
+    let mut app = headless_app(NetworkingPlugin {
+        transport: TransportKind::Replay { path },
+        ..default()
+    });
+
+    println!("Replaying capture");
+    app.run();
+}
+
+fn headless_app(networking: NetworkingPlugin) -> App {
+    let runtime = Runtime::new().unwrap();
+
+    let mut app = App::new();
+    app.insert_resource(RT(runtime))
+        .add_plugins(
+            MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+                1.0 / SERVER_TICK_RATE,
+            ))),
+        )
+        //rapier needs transforms, hierarchy and mesh assets even without rendering
+        .add_plugins(TransformPlugin)
+        .add_plugins(HierarchyPlugin)
+        .add_plugins(AssetPlugin::default())
+        .init_asset::<Mesh>()
+        .add_plugins(RapierPhysicsPlugin::<NoUserData>::default().in_fixed_schedule())
+        .add_plugins(networking)
+        .add_plugins(HeadlessGamePlugin)
+        .add_plugins(UtilPlugin)
+        .add_plugins(RPGPlugin);
+
+    //npcs can still talk if api keys are available, but the server doesn't need them to run
+    match std::fs::read_to_string("config.toml") {
+        Ok(config) => {
+            let config: Config = toml::from_str(&config).unwrap();
+            unsafe {
+                // This is safe because it is called before any other threads are spawned
+                set_var("ELEVEN_API_KEY", config.elevenlabs_key.as_str());
+            }
+            app.add_plugins(AiPlugin {
+                headless: true,
+                ..AiPlugin::from_config(config)
+            });
+        }
+        Err(_) => println!("No config.toml found, running without AI"),
+    }
+
+    app
 }
 
 fn test(
@@ -0,0 +1,194 @@
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+use super::transport::PeerId;
+use super::type_registry::Registered;
+
+//horizontal speed allowed above an entity's SpeedLimit, covers jitter in the owner's timestamps
+const SPEED_TOLERANCE: f32 = 1.5;
+//updates closer together than this are measured as if this far apart
+const MIN_ELAPSED: f64 = 1.0 / 60.0;
+//how much more time the owner's timestamps may claim has passed than this machine measured,
+//  so a peer can't pretend a long time passed to cover a teleport
+const MAX_TIMESTAMP_LEAD: f64 = 0.25;
+
+#[derive(Resource, Clone, Copy)]
+pub struct AuthoritySettings {
+    //rejected updates a peer may have against it before it is kicked
+    pub max_strikes: u32,
+    //seconds until a strike is forgiven, so occasional rejections never add up to a kick
+    pub strike_decay: f64,
+}
+
+impl Default for AuthoritySettings {
+    fn default() -> Self {
+        Self {
+            max_strikes: 20,
+            strike_decay: 5.0,
+        }
+    }
+}
+
+//the fastest a slave may move horizontally in units per second, checked by validate_speed
+#[derive(Component, Clone, Copy)]
+pub struct SpeedLimit(pub f32);
+
+//everything a validator knows about one component of an incoming update,
+//  the sender and entity of a rejected update are logged by the caller
+pub struct UpdateContext<'a> {
+    pub type_id: u16,
+    //the component as it was in the last update accepted from its owner,
+    //  not the interpolated value this machine renders
+    pub previous: &'a [u8],
+    pub next: &'a [u8],
+    //seconds between the owner's timestamps of the previous and this update
+    pub elapsed: f64,
+    pub speed_limit: Option<f32>,
+}
+
+pub enum Verdict {
+    Accept,
+    //drops the update and counts a strike against the sender
+    Reject(String),
+}
+
+pub type Validator = fn(&UpdateContext) -> Verdict;
+
+#[derive(Resource, Default)]
+pub struct Validators(pub Vec<Validator>);
+
+pub trait AddValidator {
+    //runs the validator on every component of every update received from another peer
+    fn add_validator(&mut self, validator: Validator) -> &mut Self;
+}
+
+impl AddValidator for App {
+    fn add_validator(&mut self, validator: Validator) -> &mut Self {
+        self.init_resource::<Validators>();
+        self.world.resource_mut::<Validators>().0.push(validator);
+        self
+    }
+}
+
+//what updates received from other peers are checked against
+#[derive(SystemParam)]
+pub struct UpdateChecks<'w> {
+    pub validators: Res<'w, Validators>,
+    pub settings: Res<'w, AuthoritySettings>,
+}
+
+impl UpdateChecks<'_> {
+    //the reason given by the first validator rejecting the update
+    pub fn rejection(&self, context: &UpdateContext) -> Option<String> {
+        self.validators
+            .0
+            .iter()
+            .find_map(|validator| match validator(context) {
+                Verdict::Accept => None,
+                Verdict::Reject(reason) => Some(reason),
+            })
+    }
+}
+
+//rejects transforms that moved further than the entity's speed limit allows
+pub fn validate_speed(context: &UpdateContext) -> Verdict {
+    let Some(speed_limit) = context.speed_limit else {
+        return Verdict::Accept;
+    };
+    if context.type_id != Transform::ID {
+        return Verdict::Accept;
+    }
+    let (Ok(previous), Ok(next)) = (
+        bincode::deserialize::<Transform>(context.previous),
+        bincode::deserialize::<Transform>(context.next),
+    ) else {
+        return Verdict::Accept;
+    };
+
+    //only horizontal movement is limited, falling is left to physics
+    let distance = previous.translation.xz().distance(next.translation.xz());
+    let speed = distance / context.elapsed.max(MIN_ELAPSED) as f32;
+    if speed > speed_limit * SPEED_TOLERANCE {
+        return Verdict::Reject(format!(
+            "moved at {:.1} units per second, the limit is {:.1}",
+            speed, speed_limit
+        ));
+    }
+
+    Verdict::Accept
+}
+
+/**
+ * Tracks which peer owns every slave entity so that only the owner can update
+ * or delete it, and counts rejected updates per peer.
+ */
+#[derive(Default)]
+pub(super) struct AuthorityState {
+    owners: HashMap<u16, PeerId>,
+    //owner's timestamp and local time of the last accepted update per static id
+    last_accepted: HashMap<u16, (f64, f64)>,
+    //bytes of every component as last accepted, by static id and type id
+    accepted_state: HashMap<(u16, u16), Vec<u8>>,
+    //strikes against each peer and the local time they were last counted
+    strikes: HashMap<PeerId, (f64, f64)>,
+}
+
+impl AuthorityState {
+    pub fn owner(&self, static_id: u16) -> Option<PeerId> {
+        self.owners.get(&static_id).copied()
+    }
+
+    pub fn set_owner(&mut self, static_id: u16, owner: PeerId) {
+        self.owners.insert(static_id, owner);
+    }
+
+    //seconds since the last accepted update of the entity, None if this is the first one
+    pub fn elapsed(&self, static_id: u16, timestamp: f64, now: f64) -> Option<f64> {
+        let (previous_timestamp, previous_now) = self.last_accepted.get(&static_id)?;
+        let elapsed = timestamp - previous_timestamp;
+
+        Some(elapsed.min(now - previous_now + MAX_TIMESTAMP_LEAD))
+    }
+
+    pub fn accept(&mut self, static_id: u16, timestamp: f64, now: f64, state: &[(u16, Vec<u8>)]) {
+        self.last_accepted.insert(static_id, (timestamp, now));
+        for (type_id, bytes) in state.iter() {
+            self.accepted_state
+                .insert((static_id, *type_id), bytes.clone());
+        }
+    }
+
+    //the state the entity was created with counts as accepted
+    pub fn accept_component(&mut self, static_id: u16, type_id: u16, bytes: &[u8]) {
+        self.accepted_state
+            .insert((static_id, type_id), bytes.to_vec());
+    }
+
+    pub fn accepted(&self, static_id: u16, type_id: u16) -> Option<&[u8]> {
+        self.accepted_state
+            .get(&(static_id, type_id))
+            .map(|bytes| bytes.as_slice())
+    }
+
+    //returns true once the peer has used up its strikes
+    pub fn strike(&mut self, peer: PeerId, settings: &AuthoritySettings, now: f64) -> bool {
+        let (strikes, counted) = self.strikes.entry(peer).or_insert((0.0, now));
+        let forgiven = (now - *counted) / settings.strike_decay.max(f64::EPSILON);
+        *strikes = (*strikes - forgiven).max(0.0) + 1.0;
+        *counted = now;
+
+        *strikes > settings.max_strikes as f64
+    }
+
+    pub fn forget_peer(&mut self, peer: PeerId) {
+        self.strikes.remove(&peer);
+    }
+
+    pub fn forget_entity(&mut self, static_id: u16) {
+        self.owners.remove(&static_id);
+        self.last_accepted.remove(&static_id);
+        self.accepted_state
+            .retain(|(accepted_id, _), _| *accepted_id != static_id);
+    }
+}
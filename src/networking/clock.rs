@@ -0,0 +1,147 @@
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+use super::transport::{PeerId, Reliability};
+use super::*;
+
+//seconds between pings to each peer
+const PING_INTERVAL: f64 = 0.5;
+//how quickly the round trip and offset estimates follow new samples
+const SMOOTHING: f64 = 0.1;
+
+/**
+ * Time shared by every machine in the session. The host's clock is the reference,
+ * every other machine estimates its offset to it from ping round trips.
+ * When the host changes the new host keeps its estimate, so shared time never jumps.
+ */
+#[derive(Resource)]
+pub struct NetworkClock {
+    //ticks per second of the shared tick counter
+    pub tick_rate: f64,
+    //ticks elapsed on the host's clock, only ever increases
+    pub tick: u64,
+    local_time: f64,
+    //estimated host time minus local time
+    offset: Option<f64>,
+    //smoothed round trip time to each peer, in seconds
+    rtt: HashMap<PeerId, f64>,
+    last_ping: f64,
+}
+
+impl NetworkClock {
+    pub fn new(tick_rate: f64) -> Self {
+        Self {
+            tick_rate,
+            tick: 0,
+            local_time: 0.0,
+            offset: None,
+            rtt: HashMap::new(),
+            last_ping: 0.0,
+        }
+    }
+
+    //a clock that already agrees with the host, so tests don't have to exchange pings
+    #[cfg(test)]
+    pub fn synchronized(tick_rate: f64) -> Self {
+        Self {
+            offset: Some(0.0),
+            ..Self::new(tick_rate)
+        }
+    }
+
+    //seconds on the host's clock
+    pub fn host_time(&self) -> f64 {
+        self.local_time + self.offset.unwrap_or(0.0)
+    }
+
+    //false until the first reply from the host arrives
+    pub fn is_synchronized(&self) -> bool {
+        self.offset.is_some()
+    }
+
+    pub fn rtt(&self, peer: PeerId) -> Option<f64> {
+        self.rtt.get(&peer).copied()
+    }
+
+    pub fn tick_at(&self, host_time: f64) -> u64 {
+        (host_time.max(0.0) * self.tick_rate) as u64
+    }
+}
+
+fn smooth(estimate: Option<f64>, sample: f64) -> f64 {
+    match estimate {
+        Some(estimate) => estimate + (sample - estimate) * SMOOTHING,
+        None => sample,
+    }
+}
+
+//answers pings, updates the estimates from pongs and advances the shared tick
+pub(super) fn sync_clock(
+    time: Res<Time<Real>>,
+    mut clock: ResMut<NetworkClock>,
+    mut networking: ResMut<NetworkingState>,
+) {
+    clock.local_time = time.elapsed_seconds_f64();
+
+    let host = networking.heir();
+    if host == networking.player_id {
+        clock.offset = Some(clock.offset.unwrap_or(0.0));
+    }
+
+    let messages: Vec<(PeerId, Vec<u8>)> = networking.clock_messages.drain(..).collect();
+    for (sender, data) in messages {
+        let mut reader = Reader::new(&data);
+        match reader.u8().map(EventType::try_from) {
+            Some(Ok(Ping)) => {
+                let Some(sent) = reader.u64() else {
+                    continue;
+                };
+
+                //echoes the sender's time so it can measure the round trip
+                let mut bytes = vec![Pong as u8];
+                bytes.extend_from_slice(&sent.to_le_bytes());
+                bytes.extend_from_slice(&clock.host_time().to_bits().to_le_bytes());
+                networking
+                    .transport
+                    .send_packet(sender, Reliability::Unreliable, &bytes);
+            }
+            Some(Ok(Pong)) => {
+                let (Some(sent), Some(remote)) = (reader.u64(), reader.u64()) else {
+                    continue;
+                };
+                let sent = f64::from_bits(sent);
+                let remote = f64::from_bits(remote);
+
+                let rtt = (clock.local_time - sent).max(0.0);
+                let smoothed = smooth(clock.rtt(sender), rtt);
+                clock.rtt.insert(sender, smoothed);
+
+                //the host's clock advanced by about half the round trip since it replied
+                if sender == host {
+                    let sample = remote + rtt / 2.0 - clock.local_time;
+                    clock.offset = Some(smooth(clock.offset, sample));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    clock
+        .rtt
+        .retain(|peer, _| networking.active_players.contains(peer));
+
+    if networking.connected && clock.local_time - clock.last_ping >= PING_INTERVAL {
+        clock.last_ping = clock.local_time;
+
+        let mut bytes = vec![Ping as u8];
+        bytes.extend_from_slice(&clock.local_time.to_bits().to_le_bytes());
+        for player in networking.active_players.iter() {
+            networking
+                .transport
+                .send_packet(*player, Reliability::Unreliable, &bytes);
+        }
+    }
+
+    let tick = clock.tick_at(clock.host_time());
+    clock.tick = clock.tick.max(tick);
+}
@@ -0,0 +1,354 @@
+use std::collections::{HashMap, VecDeque};
+
+use super::transport::PeerId;
+use super::wire::*;
+use super::EventType;
+
+//number of sent or received states kept per entity to delta against
+const HISTORY_LENGTH: usize = 32;
+
+//(type id, serialized bytes) for every synchronized component of an entity
+pub(super) type ComponentState = Vec<(u16, Vec<u8>)>;
+
+//true if sequence a was sent after sequence b, accounting for wrap around
+pub(super) fn sequence_newer(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+#[derive(Default)]
+struct History {
+    latest: u16,
+    states: VecDeque<(u16, ComponentState)>,
+}
+
+impl History {
+    fn push(&mut self, sequence: u16, state: ComponentState) {
+        self.latest = sequence;
+        self.states.push_back((sequence, state));
+        if self.states.len() > HISTORY_LENGTH {
+            self.states.pop_front();
+        }
+    }
+
+    fn get(&self, sequence: u16) -> Option<&ComponentState> {
+        self.states
+            .iter()
+            .find(|(s, _)| *s == sequence)
+            .map(|(_, state)| state)
+    }
+}
+
+/**
+ * Tracks which states of each entity every peer has acknowledged so that
+ * updates only carry the components that changed since then.
+ */
+#[derive(Default)]
+pub(super) struct DeltaState {
+    //states sent for master entities, by static id
+    sent: HashMap<u16, History>,
+    //latest acknowledged state per peer and static id
+    acked: HashMap<(PeerId, u16), (u16, ComponentState)>,
+    //states received for slave entities, by static id
+    received: HashMap<u16, History>,
+    //acks waiting to be sent to each owner as (static id, sequence, baseline found)
+    pending_acks: HashMap<PeerId, Vec<(u16, u16, bool)>>,
+}
+
+impl DeltaState {
+    //the sequence the next state sent for a master entity gets
+    pub fn next_sequence(&self, static_id: u16) -> u16 {
+        match self.sent.get(&static_id) {
+            Some(history) if !history.states.is_empty() => history.latest.wrapping_add(1),
+            _ => 0,
+        }
+    }
+
+    /**
+     * Records a state that was sent to at least one peer. Sequences only advance here,
+     * an entity that stands still for longer than half the sequence space would otherwise
+     * come back with a sequence its receivers take for a stale one.
+     */
+    pub fn record_sent(&mut self, static_id: u16, sequence: u16, state: ComponentState) {
+        self.sent
+            .entry(static_id)
+            .or_default()
+            .push(sequence, state);
+    }
+
+    //builds the update for one peer, None if nothing changed since the peer's baseline
+    pub fn update_message(
+        &self,
+        peer: PeerId,
+        static_id: u16,
+        sequence: u16,
+        timestamp: u32,
+        state: &ComponentState,
+    ) -> Option<Vec<u8>> {
+        let baseline = self.acked.get(&(peer, static_id));
+
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.push(EventType::EntityUpdate as u8);
+        bytes.extend_from_slice(&static_id.to_le_bytes());
+        bytes.extend_from_slice(&sequence.to_le_bytes());
+        bytes.push(baseline.is_some() as u8);
+        bytes.extend_from_slice(&baseline.map_or(0, |(s, _)| *s).to_le_bytes());
+        bytes.extend_from_slice(&timestamp.to_le_bytes());
+
+        let mut changed = 0;
+        for (type_id, data) in state.iter() {
+            //components that are identical in the baseline are left out
+            if let Some((_, baseline_state)) = baseline {
+                if baseline_state
+                    .iter()
+                    .any(|(id, baseline_data)| id == type_id && baseline_data == data)
+                {
+                    continue;
+                }
+            }
+
+            if write_component(&mut bytes, *type_id, data) {
+                changed += 1;
+            } else {
+                println!("Component with type id {} is too large to sync", type_id);
+            }
+        }
+
+        if baseline.is_some() && changed == 0 {
+            return None;
+        }
+        Some(bytes)
+    }
+
+    /**
+     * Rebuilds the full state of a slave entity from an update,
+     * returns None if the update is stale or its baseline is unknown.
+     */
+    pub fn receive_update(
+        &mut self,
+        sender: PeerId,
+        static_id: u16,
+        sequence: u16,
+        baseline: Option<u16>,
+        components: Vec<(u16, &[u8])>,
+    ) -> Option<ComponentState> {
+        let history = self.received.entry(static_id).or_default();
+        if !history.states.is_empty() && !sequence_newer(sequence, history.latest) {
+            return None;
+        }
+
+        let mut state = match baseline {
+            Some(baseline) => match history.get(baseline) {
+                Some(state) => state.clone(),
+                None => {
+                    //tells the owner to fall back to sending the full state
+                    self.pending_acks
+                        .entry(sender)
+                        .or_default()
+                        .push((static_id, sequence, false));
+                    return None;
+                }
+            },
+            None => Vec::new(),
+        };
+
+        for (type_id, data) in components {
+            match state.iter_mut().find(|(id, _)| *id == type_id) {
+                Some(component) => component.1 = data.to_vec(),
+                None => state.push((type_id, data.to_vec())),
+            }
+        }
+
+        history.push(sequence, state.clone());
+        self.pending_acks
+            .entry(sender)
+            .or_default()
+            .push((static_id, sequence, true));
+
+        Some(state)
+    }
+
+    pub fn handle_acks(&mut self, sender: PeerId, data: &[u8]) {
+        let mut reader = Reader::new(data);
+        //skips the message type
+        reader.u8();
+
+        while let (Some(static_id), Some(sequence), Some(found)) =
+            (reader.u16(), reader.u16(), reader.u8())
+        {
+            if found == 0 {
+                self.acked.remove(&(sender, static_id));
+                continue;
+            }
+
+            if let Some((current, _)) = self.acked.get(&(sender, static_id)) {
+                if !sequence_newer(sequence, *current) {
+                    continue;
+                }
+            }
+
+            //acks for states that have already left the history are ignored
+            if let Some(state) = self.sent.get(&static_id).and_then(|h| h.get(sequence)) {
+                self.acked
+                    .insert((sender, static_id), (sequence, state.clone()));
+            }
+        }
+    }
+
+    //ack messages for every owner that sent updates since the last call
+    pub fn drain_acks(&mut self) -> Vec<(PeerId, Vec<u8>)> {
+        self.pending_acks
+            .drain()
+            .map(|(owner, acks)| {
+                let mut bytes: Vec<u8> = Vec::new();
+                bytes.push(EventType::EntityAck as u8);
+                for (static_id, sequence, found) in acks {
+                    bytes.extend_from_slice(&static_id.to_le_bytes());
+                    bytes.extend_from_slice(&sequence.to_le_bytes());
+                    bytes.push(found as u8);
+                }
+                (owner, bytes)
+            })
+            .collect()
+    }
+
+    pub fn forget_peer(&mut self, peer: PeerId) {
+        self.acked.retain(|(p, _), _| *p != peer);
+        self.pending_acks.remove(&peer);
+    }
+
+    //the next update to the peer carries the full state
+    pub fn forget_peer_entity(&mut self, peer: PeerId, static_id: u16) {
+        self.acked.remove(&(peer, static_id));
+    }
+
+    pub fn forget_entity(&mut self, static_id: u16) {
+        self.sent.remove(&static_id);
+        self.received.remove(&static_id);
+        self.acked.retain(|(_, id), _| *id != static_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: PeerId = PeerId(1);
+    const PEER: PeerId = PeerId(2);
+
+    //the baseline and components of an update_message
+    fn parse_update(bytes: &[u8]) -> (Option<u16>, Vec<(u16, &[u8])>) {
+        let mut reader = Reader::new(&bytes[1..]);
+        reader.u16().unwrap();
+        reader.u16().unwrap();
+        let has_baseline = reader.u8().unwrap() != 0;
+        let baseline = reader.u16().unwrap();
+        reader.u32().unwrap();
+
+        (
+            has_baseline.then_some(baseline),
+            read_components(&mut reader).unwrap(),
+        )
+    }
+
+    #[test]
+    fn sequences_compare_across_wrap_around() {
+        assert!(sequence_newer(1, 0));
+        assert!(sequence_newer(0, u16::MAX));
+        assert!(sequence_newer(10, u16::MAX - 10));
+        assert!(!sequence_newer(u16::MAX, 0));
+        assert!(!sequence_newer(5, 5));
+        assert!(!sequence_newer(0, 0x8000));
+    }
+
+    #[test]
+    fn updates_only_carry_components_changed_since_the_acked_baseline() {
+        let mut owner = DeltaState::default();
+        let mut peer = DeltaState::default();
+
+        let first: ComponentState = vec![(1, vec![1, 1]), (2, vec![2, 2])];
+        let sequence = owner.next_sequence(9);
+        let bytes = owner.update_message(PEER, 9, sequence, 0, &first).unwrap();
+        owner.record_sent(9, sequence, first.clone());
+        let (baseline, components) = parse_update(&bytes);
+        assert_eq!(baseline, None);
+        assert_eq!(components.len(), 2);
+
+        let received = peer.receive_update(OWNER, 9, sequence, baseline, components);
+        assert_eq!(received, Some(first.clone()));
+        for (_, acks) in peer.drain_acks() {
+            owner.handle_acks(PEER, &acks);
+        }
+
+        //unchanged states aren't sent again
+        assert!(owner
+            .update_message(PEER, 9, owner.next_sequence(9), 0, &first)
+            .is_none());
+
+        let second: ComponentState = vec![(1, vec![1, 1]), (2, vec![3, 3])];
+        let sequence = owner.next_sequence(9);
+        let bytes = owner.update_message(PEER, 9, sequence, 0, &second).unwrap();
+        owner.record_sent(9, sequence, second.clone());
+        let (baseline, components) = parse_update(&bytes);
+        assert_eq!(baseline, Some(0));
+        assert_eq!(components, vec![(2, &[3u8, 3][..])]);
+
+        let received = peer.receive_update(OWNER, 9, sequence, baseline, components);
+        assert_eq!(received, Some(second));
+    }
+
+    //what sync_master_entities does with one entity and one peer
+    fn sync(owner: &mut DeltaState, peer: &mut DeltaState, state: &ComponentState) -> bool {
+        let sequence = owner.next_sequence(9);
+        let Some(bytes) = owner.update_message(PEER, 9, sequence, 0, state) else {
+            return false;
+        };
+        owner.record_sent(9, sequence, state.clone());
+
+        let (baseline, components) = parse_update(&bytes);
+        let received = peer.receive_update(OWNER, 9, sequence, baseline, components);
+        for (_, acks) in peer.drain_acks() {
+            owner.handle_acks(PEER, &acks);
+        }
+        received.as_ref() == Some(state)
+    }
+
+    #[test]
+    fn an_entity_standing_still_keeps_its_sequence() {
+        let mut owner = DeltaState::default();
+        let mut peer = DeltaState::default();
+
+        let still: ComponentState = vec![(1, vec![1])];
+        assert!(sync(&mut owner, &mut peer, &still));
+        //longer than half the sequence space without anything to send
+        for _ in 0..0x8001 {
+            assert!(!sync(&mut owner, &mut peer, &still));
+        }
+
+        let moved: ComponentState = vec![(1, vec![2])];
+        assert!(sync(&mut owner, &mut peer, &moved));
+    }
+
+    #[test]
+    fn stale_updates_and_unknown_baselines_are_rejected() {
+        let mut peer = DeltaState::default();
+        let component: &[u8] = &[1];
+
+        assert!(peer
+            .receive_update(OWNER, 3, 5, None, vec![(1, component)])
+            .is_some());
+        assert!(peer
+            .receive_update(OWNER, 3, 4, None, vec![(1, component)])
+            .is_none());
+        assert!(peer
+            .receive_update(OWNER, 3, 6, Some(2), vec![(1, component)])
+            .is_none());
+
+        //the owner is told to fall back to a full state
+        let acks = peer.drain_acks();
+        let (_, bytes) = &acks[0];
+        let mut reader = Reader::new(&bytes[1..]);
+        let acked: Vec<(u16, u16, u8)> =
+            std::iter::from_fn(|| Some((reader.u16()?, reader.u16()?, reader.u8()?))).collect();
+        assert_eq!(acked, vec![(3, 5, 1), (3, 6, 0)]);
+    }
+}
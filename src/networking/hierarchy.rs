@@ -0,0 +1,95 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::ids::EntityIndex;
+use super::{SynchronizedMaster, SynchronizedSlave};
+
+//parent chains longer than this are cut off, guards against cycles in bad data
+const MAX_DEPTH: usize = 32;
+
+/**
+ * Static id of the synchronized parent of an entity, kept up to date on masters
+ * from bevy's Parent and turned back into a Parent on slaves. None once unparented,
+ * since a component that stops being sent is never removed on the slave.
+ */
+#[derive(Component, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct NetworkParent(pub Option<u16>);
+
+//follows parents up to the root, returns the root's static id and how deep the entity is
+pub(super) fn root_of(parents: &HashMap<u16, u16>, static_id: u16) -> (u16, usize) {
+    let mut root = static_id;
+    let mut depth = 0;
+    while let Some(parent) = parents.get(&root) {
+        if depth >= MAX_DEPTH {
+            break;
+        }
+        root = *parent;
+        depth += 1;
+    }
+
+    (root, depth)
+}
+
+//every master with its parent in the hierarchy and the one last sent to other players
+type ParentedMasters<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        Option<&'static Parent>,
+        Option<&'static mut NetworkParent>,
+    ),
+    With<SynchronizedMaster>,
+>;
+
+//mirrors the parent of every master into its NetworkParent, only parents that are masters themselves are synced
+pub(super) fn update_network_parents(
+    mut commands: Commands,
+    mut masters: ParentedMasters,
+    parents: Query<&SynchronizedMaster>,
+) {
+    for (entity, parent, network_parent) in masters.iter_mut() {
+        let parent_id = parent
+            .and_then(|parent| parents.get(parent.get()).ok())
+            .map(|parent| parent.static_id);
+
+        match network_parent {
+            Some(mut network_parent) => {
+                if network_parent.0 != parent_id {
+                    network_parent.0 = parent_id;
+                }
+            }
+            None => {
+                if parent_id.is_some() {
+                    commands.entity(entity).insert(NetworkParent(parent_id));
+                }
+            }
+        }
+    }
+}
+
+//parents slaves to the slave of their master's parent, waiting for the parent to be created if needed
+pub(super) fn apply_network_parents(
+    mut commands: Commands,
+    index: Res<EntityIndex>,
+    slaves: Query<(Entity, &NetworkParent, Option<&Parent>), With<SynchronizedSlave>>,
+) {
+    for (entity, network_parent, parent) in slaves.iter() {
+        match network_parent.0 {
+            Some(parent_id) => {
+                let Some(parent_entity) = index.get(parent_id) else {
+                    continue;
+                };
+                if parent.map(|parent| parent.get()) != Some(parent_entity) {
+                    commands.entity(entity).set_parent(parent_entity);
+                }
+            }
+            None => {
+                if parent.is_some() {
+                    commands.entity(entity).remove_parent();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+use super::transport::PeerId;
+use super::{NetworkingState, SynchronizedMaster, SynchronizedSlave};
+
+//sent in place of a slot by players that haven't been given one yet
+pub(super) const NO_SLOT: u16 = u16::MAX;
+
+/**
+ * Hands out static ids without asking anyone. The id space is split into one
+ * range per player slot, the first player in a session takes slot 0 and assigns
+ * a free slot to everyone who joins through it.
+ * Ids of destroyed entities are reused once every peer that had the entity confirmed deleting it.
+ * Slots of players that left are only handed out again once no other slot is free,
+ * since whoever inherited their entities keeps using ids from those ranges.
+ */
+pub(super) struct IdAllocator {
+    ids_per_slot: u32,
+    max_slots: u16,
+    slot: Option<u16>,
+    //slots of the other players in the session
+    slots: HashMap<PeerId, u16>,
+    //slots of players that left, oldest first
+    retired: Vec<u16>,
+    next: u32,
+    free: Vec<u16>,
+    //peers that still have to confirm deleting each destroyed entity
+    pending_deletes: HashMap<u16, Vec<PeerId>>,
+}
+
+impl IdAllocator {
+    pub fn new(max_players: u16) -> Self {
+        let max_slots = max_players.max(1);
+
+        Self {
+            ids_per_slot: (u16::MAX as u32 + 1) / max_slots as u32,
+            max_slots,
+            slot: None,
+            slots: HashMap::new(),
+            retired: Vec::new(),
+            next: 0,
+            free: Vec::new(),
+            pending_deletes: HashMap::new(),
+        }
+    }
+
+    pub fn slot(&self) -> Option<u16> {
+        self.slot
+    }
+
+    pub fn slot_of(&self, peer: PeerId) -> Option<u16> {
+        self.slots.get(&peer).copied()
+    }
+
+    //a machine alone in its session is the one handing out slots
+    pub fn claim_first_slot(&mut self) {
+        if self.slot.is_none() {
+            self.slot = Some(0);
+        }
+    }
+
+    //moving to another slot starts over at the beginning of its range,
+    //  ids allocated before joining a session may still collide with other players'
+    pub fn set_slot(&mut self, slot: u16) {
+        if slot == NO_SLOT || slot >= self.max_slots || self.slot == Some(slot) {
+            return;
+        }
+
+        self.slot = Some(slot);
+        self.retired.retain(|retired| *retired != slot);
+        self.next = 0;
+        self.free.clear();
+    }
+
+    pub fn set_peer_slot(&mut self, peer: PeerId, slot: u16) {
+        if slot != NO_SLOT && slot < self.max_slots {
+            self.slots.insert(peer, slot);
+            //someone else handed the slot out again
+            self.retired.retain(|retired| *retired != slot);
+        }
+    }
+
+    //keeps the slot a joining peer asks for if nobody else uses it, otherwise assigns a free one
+    pub fn claim_peer_slot(&mut self, peer: PeerId, requested: u16) -> Option<u16> {
+        let taken = self.slot == Some(requested)
+            || self.retired.contains(&requested)
+            || self
+                .slots
+                .iter()
+                .any(|(other, slot)| *other != peer && *slot == requested);
+        if requested == NO_SLOT || requested >= self.max_slots || taken {
+            self.slots.remove(&peer);
+            return self.assign_slot(peer);
+        }
+
+        self.slots.insert(peer, requested);
+        Some(requested)
+    }
+
+    //gives a joining peer the lowest slot nobody in the session uses
+    pub fn assign_slot(&mut self, peer: PeerId) -> Option<u16> {
+        if let Some(slot) = self.slot_of(peer) {
+            return Some(slot);
+        }
+
+        let slot = (0..self.max_slots)
+            .find(|slot| {
+                self.slot != Some(*slot)
+                    && !self.slots.values().any(|s| s == slot)
+                    && !self.retired.contains(slot)
+            })
+            .or_else(|| (!self.retired.is_empty()).then(|| self.retired.remove(0)))?;
+        self.slots.insert(peer, slot);
+
+        Some(slot)
+    }
+
+    //None until this machine has a slot, or once every id in its range is in use
+    pub fn allocate(&mut self) -> Option<u16> {
+        let slot = self.slot? as u32;
+        if let Some(id) = self.free.pop() {
+            return Some(id);
+        }
+        if self.next >= self.ids_per_slot {
+            return None;
+        }
+
+        let id = slot * self.ids_per_slot + self.next;
+        self.next += 1;
+        Some(id as u16)
+    }
+
+    //the id is reused once every one of the peers confirmed deleting the entity
+    pub fn release_after(&mut self, static_id: u16, peers: Vec<PeerId>) {
+        if peers.is_empty() {
+            self.release(static_id);
+        } else {
+            self.pending_deletes.insert(static_id, peers);
+        }
+    }
+
+    pub fn confirm_delete(&mut self, peer: PeerId, static_id: u16) {
+        let Some(peers) = self.pending_deletes.get_mut(&static_id) else {
+            return;
+        };
+
+        peers.retain(|p| *p != peer);
+        if peers.is_empty() {
+            self.pending_deletes.remove(&static_id);
+            self.release(static_id);
+        }
+    }
+
+    fn release(&mut self, static_id: u16) {
+        //entities inherited from other players keep ids from their range, which aren't ours to reuse
+        if self.slot == Some((static_id as u32 / self.ids_per_slot) as u16) {
+            self.free.push(static_id);
+        }
+    }
+
+    pub fn forget_peer(&mut self, peer: PeerId) {
+        if let Some(slot) = self.slots.remove(&peer) {
+            self.retired.push(slot);
+        }
+
+        let confirmed: Vec<u16> = self
+            .pending_deletes
+            .iter()
+            .filter(|(_, peers)| peers.len() == 1 && peers[0] == peer)
+            .map(|(static_id, _)| *static_id)
+            .collect();
+        for peers in self.pending_deletes.values_mut() {
+            peers.retain(|p| *p != peer);
+        }
+        for static_id in confirmed {
+            self.pending_deletes.remove(&static_id);
+            self.release(static_id);
+        }
+    }
+}
+
+//every synchronized entity on this machine by static id
+#[derive(Resource, Default)]
+pub struct EntityIndex {
+    entities: HashMap<u16, Entity>,
+}
+
+impl EntityIndex {
+    pub fn get(&self, static_id: u16) -> Option<Entity> {
+        self.entities.get(&static_id).copied()
+    }
+
+    pub fn contains(&self, static_id: u16) -> bool {
+        self.entities.contains_key(&static_id)
+    }
+}
+
+//keeps the index up to date, bevy has no component hooks so changes are picked up once per frame
+pub(super) fn index_entities(
+    mut index: ResMut<EntityIndex>,
+    mut networking: ResMut<NetworkingState>,
+    mut removed_slaves: RemovedComponents<SynchronizedSlave>,
+    mut removed_masters: RemovedComponents<SynchronizedMaster>,
+    slaves: Query<(Entity, &SynchronizedSlave), Added<SynchronizedSlave>>,
+    masters: Query<(Entity, &SynchronizedMaster), Added<SynchronizedMaster>>,
+) {
+    //removals go first so a slave that became a master this frame stays indexed
+    for entity in removed_slaves.read().chain(removed_masters.read()) {
+        index.entities.retain(|_, indexed| *indexed != entity);
+        networking.networked.remove(&entity);
+    }
+
+    for (entity, slave) in slaves.iter() {
+        index.entities.insert(slave.static_id, entity);
+        networking
+            .networked
+            .insert(entity, (slave.static_id, false));
+    }
+    for (entity, master) in masters.iter() {
+        index.entities.insert(master.static_id, entity);
+        networking
+            .networked
+            .insert(entity, (master.static_id, true));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_come_from_the_range_of_the_slot() {
+        let mut ids = IdAllocator::new(4);
+        assert_eq!(ids.allocate(), None);
+
+        ids.set_slot(2);
+        assert_eq!(ids.allocate(), Some(2 * 16384));
+        assert_eq!(ids.allocate(), Some(2 * 16384 + 1));
+    }
+
+    #[test]
+    fn a_full_range_stops_allocating() {
+        let mut ids = IdAllocator::new(32768);
+        ids.claim_first_slot();
+        assert_eq!(ids.allocate(), Some(0));
+        assert_eq!(ids.allocate(), Some(1));
+        assert_eq!(ids.allocate(), None);
+    }
+
+    #[test]
+    fn deleted_ids_are_reused_once_every_peer_confirmed() {
+        let mut ids = IdAllocator::new(4);
+        ids.claim_first_slot();
+        let id = ids.allocate().unwrap();
+
+        ids.release_after(id, vec![PeerId(1), PeerId(2)]);
+        ids.confirm_delete(PeerId(1), id);
+        assert_ne!(ids.allocate(), Some(id));
+
+        //a peer that leaves doesn't have to confirm anymore
+        ids.forget_peer(PeerId(2));
+        assert_eq!(ids.allocate(), Some(id));
+    }
+
+    #[test]
+    fn inherited_ids_are_not_reused() {
+        let mut ids = IdAllocator::new(4);
+        ids.claim_first_slot();
+        ids.release_after(16384, Vec::new());
+
+        assert_eq!(ids.allocate(), Some(0));
+    }
+
+    #[test]
+    fn joining_peers_get_free_slots() {
+        let mut ids = IdAllocator::new(3);
+        ids.claim_first_slot();
+
+        assert_eq!(ids.claim_peer_slot(PeerId(1), 2), Some(2));
+        //the requested slot is taken, the lowest free one is assigned instead
+        assert_eq!(ids.claim_peer_slot(PeerId(2), 2), Some(1));
+        assert_eq!(ids.assign_slot(PeerId(3)), None);
+    }
+
+    #[test]
+    fn slots_of_players_that_left_are_handed_out_last() {
+        let mut ids = IdAllocator::new(3);
+        ids.claim_first_slot();
+        ids.assign_slot(PeerId(1));
+        ids.forget_peer(PeerId(1));
+
+        assert_eq!(ids.assign_slot(PeerId(2)), Some(2));
+        assert_eq!(ids.assign_slot(PeerId(3)), Some(1));
+    }
+}
@@ -0,0 +1,148 @@
+/*!
+ * Prints packet captures. Only uses the protocol, wire and capture file modules
+ * so the inspector in src/bin can share it without the rest of the game.
+ */
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::transport::{read_capture, CaptureRecord, Direction, PeerId};
+use super::*;
+
+//names components with the type names the recording build wrote to the capture
+type TypeNames = HashMap<u16, String>;
+
+pub fn print_capture(path: &Path) -> std::io::Result<()> {
+    let (header, records) = read_capture(path)?;
+    let type_names: TypeNames = header.type_names.into_iter().collect();
+
+    println!(
+        "Capture of {:?}, protocol version {}, {} packets",
+        header.local_peer,
+        header.protocol_version,
+        records.len()
+    );
+    if header.protocol_version != PROTOCOL_VERSION {
+        println!(
+            "Warning: this build uses protocol version {}, packets may be misread",
+            PROTOCOL_VERSION
+        );
+    }
+
+    for record in records.iter() {
+        println!("{}", describe_record(record, &type_names));
+    }
+
+    Ok(())
+}
+
+fn describe_record(record: &CaptureRecord, type_names: &TypeNames) -> String {
+    let direction = match record.direction {
+        Direction::Sent => "to",
+        Direction::Received => "from",
+    };
+    let reliability = record
+        .reliability
+        .map_or(String::new(), |reliability| format!(" {:?}", reliability));
+
+    format!(
+        "{:>10.3}s {} {:?}{} {}",
+        record.time,
+        direction,
+        record.peer,
+        reliability,
+        describe_message(&record.bytes, type_names)
+            .unwrap_or_else(|| format!("truncated message, {} bytes", record.bytes.len()))
+    )
+}
+
+//None if the message is shorter than its type requires
+fn describe_message(bytes: &[u8], type_names: &TypeNames) -> Option<String> {
+    let mut reader = Reader::new(bytes);
+    let message_type = match EventType::try_from(reader.u8()?) {
+        Ok(message_type) => message_type,
+        Err(value) => return Some(format!("unknown message type {}", value)),
+    };
+
+    let details = match message_type {
+        EntityCreate => {
+            let static_id = reader.u16()?;
+            let object_info = reader.u8()?;
+            format!(
+                "static id {}, object info {:08b}, {}",
+                static_id,
+                object_info,
+                describe_components(&mut reader, type_names)?
+            )
+        }
+        EntityUpdate => {
+            let static_id = reader.u16()?;
+            let sequence = reader.u16()?;
+            let is_delta = reader.u8()? != 0;
+            let baseline = reader.u16()?;
+            let timestamp = reader.u32()?;
+            format!(
+                "static id {}, sequence {}, {}, timestamp {}ms, {}",
+                static_id,
+                sequence,
+                if is_delta {
+                    format!("delta from {}", baseline)
+                } else {
+                    "full state".to_string()
+                },
+                timestamp,
+                describe_components(&mut reader, type_names)?
+            )
+        }
+        EntityDelete | EntityDeleteAck => format!("static id {}", reader.u16()?),
+        EntityAck => format!("{} acks", reader.remaining().len() / 5),
+        PlayerJoin => {
+            let version = reader.u16()?;
+            let _registry_hash = reader.u64()?;
+            let dedicated_server = reader.u8()? != 0;
+            let host = PeerId(reader.u64()?);
+            let receiver = PeerId(reader.u64()?);
+            let slot = reader.u16()?;
+            let count = reader.u16()?;
+            format!(
+                "protocol version {}, dedicated server {}, host {:?}, receiver {:?}, id slot {}, knows {} players",
+                version, dedicated_server, host, receiver, slot, count
+            )
+        }
+        Reject => format!("{}", RejectReason::from(reader.u8()?)),
+        Event => format!(
+            "event id {}, {} bytes",
+            reader.u16()?,
+            reader.remaining().len()
+        ),
+        Resume => {
+            let mut static_ids: Vec<String> = Vec::new();
+            while let Some(static_id) = reader.u16() {
+                static_ids.push(static_id.to_string());
+            }
+            format!("static ids {}", static_ids.join(", "))
+        }
+        Handoff => format!(
+            "static id {}, new owner {:?}",
+            reader.u16()?,
+            PeerId(reader.u64()?)
+        ),
+        PlayerLeave | Input | Ping | Pong => format!("{} bytes", bytes.len()),
+    };
+
+    Some(format!("{:?} {}", message_type, details))
+}
+
+fn describe_components(reader: &mut Reader, type_names: &TypeNames) -> Option<String> {
+    let components: Vec<String> = read_components(reader)?
+        .iter()
+        .map(|(type_id, data)| match type_names.get(type_id) {
+            Some(name) => format!("{} ({}) {} bytes", name, type_id, data.len()),
+            None => format!("unknown type {} {} bytes", type_id, data.len()),
+        })
+        .collect();
+
+    if components.is_empty() {
+        return Some("no components".to_string());
+    }
+    Some(components.join(", "))
+}
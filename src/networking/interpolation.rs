@@ -0,0 +1,122 @@
+use bevy::prelude::*;
+use std::collections::VecDeque;
+
+use super::clock::NetworkClock;
+use super::SynchronizedSlave;
+
+//number of snapshots kept per entity, older ones are dropped first
+const MAX_SNAPSHOTS: usize = 32;
+//how quickly the estimated offset to the owner's clock follows new samples
+const OFFSET_SMOOTHING: f64 = 0.1;
+
+#[derive(Resource, Clone, Copy)]
+pub struct InterpolationSettings {
+    //how far in the past slaves are rendered, in seconds, larger values hide more jitter
+    pub delay: f64,
+    //how far past the newest snapshot a slave may be extrapolated, in seconds
+    pub max_extrapolation: f64,
+}
+
+impl Default for InterpolationSettings {
+    fn default() -> Self {
+        Self {
+            delay: 0.1,
+            max_extrapolation: 0.25,
+        }
+    }
+}
+
+//slaves whose transform is predicted locally instead of interpolated
+#[derive(Component)]
+pub struct Predicted;
+
+//timestamped transforms received for a slave, in the owner's clock
+#[derive(Component, Default)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<(f64, Transform)>,
+    //estimated owner time minus local time
+    offset: Option<f64>,
+}
+
+impl SnapshotBuffer {
+    fn push(&mut self, timestamp: f64, transform: Transform, now: f64) {
+        if let Some((newest, _)) = self.snapshots.back() {
+            if timestamp <= *newest {
+                return;
+            }
+        }
+
+        let sample = timestamp - now;
+        self.offset = Some(match self.offset {
+            Some(offset) => offset + (sample - offset) * OFFSET_SMOOTHING,
+            None => sample,
+        });
+
+        self.snapshots.push_back((timestamp, transform));
+        if self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+    }
+
+    fn sample(&mut self, render_time: f64, max_extrapolation: f64) -> Option<Transform> {
+        //drops snapshots that are no longer needed to interpolate, always keeping two
+        while self.snapshots.len() > 2 && self.snapshots[1].0 <= render_time {
+            self.snapshots.pop_front();
+        }
+
+        let (first_time, first) = *self.snapshots.front()?;
+        if self.snapshots.len() == 1 || render_time <= first_time {
+            return Some(first);
+        }
+
+        let (second_time, second) = self.snapshots[1];
+        //past the newest snapshot this extrapolates along the last known motion
+        let render_time = render_time.min(second_time + max_extrapolation);
+        let t = ((render_time - first_time) / (second_time - first_time)) as f32;
+
+        Some(Transform {
+            translation: first.translation.lerp(second.translation, t),
+            rotation: if t <= 1.0 {
+                first.rotation.slerp(second.rotation, t)
+            } else {
+                second.rotation
+            },
+            scale: first.scale.lerp(second.scale, t.min(1.0)),
+        })
+    }
+}
+
+//renders slaves slightly in the past so there is always a snapshot on either side
+pub(super) fn interpolate_slaves(
+    time: Res<Time>,
+    clock: Res<NetworkClock>,
+    settings: Res<InterpolationSettings>,
+    mut query: Query<
+        (&mut SynchronizedSlave, &mut SnapshotBuffer, &mut Transform),
+        Without<Predicted>,
+    >,
+) {
+    let now = time.elapsed_seconds_f64();
+
+    for (mut slave, mut buffer, mut transform) in query.iter_mut() {
+        //sync_slave_entities wrote the newest transform this frame
+        if let Some(timestamp) = slave.take_update() {
+            buffer.push(timestamp, *transform, now);
+        }
+
+        //timestamps are on the host's clock, until this machine knows it the offset
+        //  to each owner is estimated from the snapshots themselves
+        let render_time = if clock.is_synchronized() {
+            clock.host_time() - settings.delay
+        } else {
+            let Some(offset) = buffer.offset else {
+                continue;
+            };
+            now + offset - settings.delay
+        };
+
+        if let Some(sampled) = buffer.sample(render_time, settings.max_extrapolation) {
+            *transform = sampled;
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::clock::NetworkClock;
+use super::ids::EntityIndex;
+use super::interpolation::InterpolationSettings;
+use super::*;
+
+#[derive(Resource, Clone, Copy)]
+pub struct LagCompensationSettings {
+    //how far back hits can be judged, in seconds, older shots are judged at the oldest pose kept
+    pub max_rewind: f64,
+}
+
+impl Default for LagCompensationSettings {
+    fn default() -> Self {
+        Self { max_rewind: 0.5 }
+    }
+}
+
+//where every synchronized entity with a collider was at each recent tick, by static id
+#[derive(Resource, Default)]
+pub struct ColliderHistory {
+    poses: HashMap<u16, VecDeque<(u64, Vec3, Quat)>>,
+}
+
+impl ColliderHistory {
+    //interpolated between the recorded ticks around it, None if the entity didn't exist yet
+    pub fn pose_at(&self, static_id: u16, tick: u64) -> Option<(Vec3, Quat)> {
+        let poses = self.poses.get(&static_id)?;
+        let (first_tick, _, _) = poses.front()?;
+        if tick < *first_tick {
+            return None;
+        }
+
+        match poses.iter().position(|(recorded, _, _)| *recorded >= tick) {
+            Some(i) if poses[i].0 == tick => Some((poses[i].1, poses[i].2)),
+            Some(i) => {
+                let (before, before_translation, before_rotation) = poses[i - 1];
+                let (after, after_translation, after_rotation) = poses[i];
+                let t = (tick - before) as f32 / (after - before) as f32;
+                Some((
+                    before_translation.lerp(after_translation, t),
+                    before_rotation.slerp(after_rotation, t),
+                ))
+            }
+            //the newest pose, nothing was recorded after it
+            None => poses
+                .back()
+                .map(|(_, translation, rotation)| (*translation, *rotation)),
+        }
+    }
+}
+
+fn rewind_ticks(settings: &LagCompensationSettings, clock: &NetworkClock) -> u64 {
+    (settings.max_rewind * clock.tick_rate).ceil() as u64
+}
+
+//runs after transforms are propagated so the poses are the ones rendered this frame,
+//  nothing is recorded until the clock is synchronized since the tick jumps once it is
+pub(super) fn record_collider_history(
+    settings: Res<LagCompensationSettings>,
+    clock: Res<NetworkClock>,
+    mut history: ResMut<ColliderHistory>,
+    masters: Query<(&SynchronizedMaster, &GlobalTransform), With<Collider>>,
+    slaves: Query<(&SynchronizedSlave, &GlobalTransform), With<Collider>>,
+) {
+    if !clock.is_synchronized() {
+        return;
+    }
+    let tick = clock.tick;
+    let oldest = tick.saturating_sub(rewind_ticks(&settings, &clock));
+
+    let entities = masters
+        .iter()
+        .map(|(master, transform)| (master.static_id, transform))
+        .chain(
+            slaves
+                .iter()
+                .map(|(slave, transform)| (slave.static_id, transform)),
+        );
+
+    let mut recorded: HashSet<u16> = HashSet::new();
+    for (static_id, transform) in entities {
+        let (_, rotation, translation) = transform.to_scale_rotation_translation();
+        let poses = history.poses.entry(static_id).or_default();
+
+        //several frames can fall on the same tick, the last one wins
+        if poses.back().is_some_and(|(last, _, _)| *last == tick) {
+            poses.pop_back();
+        }
+        poses.push_back((tick, translation, rotation));
+
+        //keeps one pose at or before the oldest tick so rewinding that far can interpolate
+        while poses.len() > 1 && poses[1].0 <= oldest {
+            poses.pop_front();
+        }
+        recorded.insert(static_id);
+    }
+
+    history
+        .poses
+        .retain(|static_id, _| recorded.contains(static_id));
+}
+
+//whether a rewound collider passes the parts of a filter that don't need its rigid body
+fn passes_filter(filter: &QueryFilter, entity: Entity, groups: Option<&CollisionGroups>) -> bool {
+    let groups = groups.copied().unwrap_or_default();
+
+    filter.exclude_collider != Some(entity)
+        && filter.exclude_rigid_body != Some(entity)
+        && filter.groups.is_none_or(|filter_groups| {
+            groups.memberships.intersects(filter_groups.filters)
+                && filter_groups.memberships.intersects(groups.filters)
+        })
+        && filter.predicate.is_none_or(|predicate| predicate(entity))
+}
+
+/**
+ * Raycasts against colliders as they were at a past tick, so the authority can judge a shot
+ * against where the targets were on the shooter's screen. The shooter sends view_tick along
+ * with the shot, the authority casts at that tick. Synchronized entities are rewound using
+ * ColliderHistory, everything else is cast against in its current pose through rapier.
+ * The flags of the filter only apply to the colliders that aren't rewound.
+ */
+//nothing fires hitscan weapons yet, they will judge their hits through this
+#[allow(dead_code)]
+#[derive(SystemParam)]
+pub struct LagCompensation<'w, 's> {
+    settings: Res<'w, LagCompensationSettings>,
+    interpolation: Res<'w, InterpolationSettings>,
+    clock: Res<'w, NetworkClock>,
+    history: Res<'w, ColliderHistory>,
+    index: Res<'w, EntityIndex>,
+    rapier_context: Res<'w, RapierContext>,
+    colliders: Query<'w, 's, (&'static Collider, Option<&'static CollisionGroups>)>,
+}
+
+#[allow(dead_code)]
+impl LagCompensation<'_, '_> {
+    //the tick this machine sees other players' entities at, slaves are rendered in the past
+    pub fn view_tick(&self) -> u64 {
+        self.clock
+            .tick_at(self.clock.host_time() - self.interpolation.delay)
+    }
+
+    //ticks older than max_rewind are judged at the oldest tick kept, future ones at the current tick
+    pub fn clamp_tick(&self, tick: u64) -> u64 {
+        let oldest = self
+            .clock
+            .tick
+            .saturating_sub(rewind_ticks(&self.settings, &self.clock));
+        tick.clamp(oldest, self.clock.tick)
+    }
+
+    //the nearest hit and its time of impact, like RapierContext::cast_ray
+    pub fn cast_ray(
+        &self,
+        tick: u64,
+        origin: Vec3,
+        direction: Vec3,
+        max_toi: f32,
+        solid: bool,
+        filter: QueryFilter,
+    ) -> Option<(Entity, f32)> {
+        let tick = self.clamp_tick(tick);
+
+        let mut nearest: Option<(Entity, f32)> = None;
+        let mut rewound: HashSet<Entity> = HashSet::new();
+        for static_id in self.history.poses.keys() {
+            let Some(entity) = self.index.get(*static_id) else {
+                continue;
+            };
+            let Ok((collider, groups)) = self.colliders.get(entity) else {
+                continue;
+            };
+            rewound.insert(entity);
+
+            if !passes_filter(&filter, entity, groups) {
+                continue;
+            }
+            let Some((translation, rotation)) = self.history.pose_at(*static_id, tick) else {
+                continue;
+            };
+
+            let toi = collider.cast_ray(translation, rotation, origin, direction, max_toi, solid);
+            if let Some(toi) = toi {
+                if nearest.is_none_or(|(_, nearest)| toi < nearest) {
+                    nearest = Some((entity, toi));
+                }
+            }
+        }
+
+        //the rest of the world hasn't moved, rewound entities are excluded from it
+        let predicate = |entity: Entity| {
+            !rewound.contains(&entity) && filter.predicate.is_none_or(|predicate| predicate(entity))
+        };
+        let world_filter = QueryFilter {
+            predicate: Some(&predicate),
+            ..filter
+        };
+        let world_hit =
+            self.rapier_context
+                .cast_ray(origin, direction, max_toi, solid, world_filter);
+        if let Some((entity, toi)) = world_hit {
+            if nearest.is_none_or(|(_, nearest)| toi < nearest) {
+                nearest = Some((entity, toi));
+            }
+        }
+
+        nearest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+
+    fn record(world: &mut World, tick: u64, x: f32) {
+        world.resource_mut::<NetworkClock>().tick = tick;
+        for mut transform in world
+            .query_filtered::<&mut GlobalTransform, With<SynchronizedMaster>>()
+            .iter_mut(world)
+        {
+            *transform = GlobalTransform::from_translation(Vec3::new(x, 0.0, 0.0));
+        }
+        world.run_system_once(record_collider_history);
+    }
+
+    fn history_world() -> (World, Entity) {
+        let mut world = World::new();
+        world.insert_resource(LagCompensationSettings { max_rewind: 0.5 });
+        world.insert_resource(NetworkClock::synchronized(10.0));
+        world.init_resource::<ColliderHistory>();
+        let entity = world
+            .spawn((
+                SynchronizedMaster {
+                    object_info: 0,
+                    static_id: 3,
+                },
+                GlobalTransform::IDENTITY,
+                Collider::ball(0.5),
+            ))
+            .id();
+
+        (world, entity)
+    }
+
+    #[test]
+    fn poses_between_recorded_ticks_are_interpolated() {
+        let mut history = ColliderHistory::default();
+        history.poses.insert(
+            1,
+            VecDeque::from([
+                (10, Vec3::ZERO, Quat::IDENTITY),
+                (14, Vec3::new(4.0, 0.0, 0.0), Quat::IDENTITY),
+            ]),
+        );
+
+        assert_eq!(history.pose_at(1, 9), None);
+        assert_eq!(history.pose_at(1, 10).unwrap().0, Vec3::ZERO);
+        assert_eq!(history.pose_at(1, 11).unwrap().0, Vec3::new(1.0, 0.0, 0.0));
+        //the newest pose is used for ticks after it
+        assert_eq!(history.pose_at(1, 20).unwrap().0, Vec3::new(4.0, 0.0, 0.0));
+        assert_eq!(history.pose_at(2, 10), None);
+    }
+
+    #[test]
+    fn rewinding_finds_where_the_entity_was() {
+        let (mut world, _) = history_world();
+        for tick in 0..=20 {
+            record(&mut world, tick, tick as f32);
+        }
+
+        let history = world.resource::<ColliderHistory>();
+        assert_eq!(history.pose_at(3, 17).unwrap().0.x, 17.0);
+        assert_eq!(history.pose_at(3, 20).unwrap().0.x, 20.0);
+        //only max_rewind seconds are kept
+        assert_eq!(history.pose_at(3, 15).unwrap().0.x, 15.0);
+        assert_eq!(history.pose_at(3, 14), None);
+    }
+
+    #[test]
+    fn frames_within_one_tick_keep_the_last_pose() {
+        let (mut world, _) = history_world();
+        record(&mut world, 4, 1.0);
+        record(&mut world, 4, 2.0);
+
+        let history = world.resource::<ColliderHistory>();
+        assert_eq!(history.poses[&3].len(), 1);
+        assert_eq!(history.pose_at(3, 4).unwrap().0.x, 2.0);
+    }
+
+    #[test]
+    fn despawned_entities_are_forgotten() {
+        let (mut world, entity) = history_world();
+        record(&mut world, 1, 0.0);
+        world.despawn(entity);
+        record(&mut world, 2, 0.0);
+
+        assert!(world.resource::<ColliderHistory>().poses.is_empty());
+    }
+}
@@ -0,0 +1,341 @@
+use bevy::prelude::*;
+use std::time::{Duration, Instant};
+
+use super::ids::EntityIndex;
+use super::transport::{PeerId, Reliability};
+use super::*;
+
+//how long the heir waits to notice a departure itself before treating a resumed entity as unknown
+const RESUME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+ * Host migration. When the host leaves or times out every remaining machine elects the same
+ * new host from its list of players, see NetworkingState::heir. The new host turns the slaves
+ * the old host owned into masters, which works for every entity because each machine keeps its
+ * successor relevant to all of its entities that outlive their owner. Everyone else hands
+ * those slaves to the new host and resumes them with a Resume message listing their static ids,
+ * so the new host continues updating them instead of creating them again.
+ * The shared clock doesn't jump because the new host keeps its estimate of the old host's time.
+ * This event is sent on every machine once it has elected the new host.
+ */
+#[derive(Event)]
+pub struct HostMigrated {
+    pub previous: PeerId,
+    pub host: PeerId,
+}
+
+/**
+ * Master entities with this are handed to the session's host as soon as it has their slave,
+ * so the host simulates them for everyone. Players are, the peer controlling one only sends
+ * its inputs and predicts the result.
+ * Every machine learns the new owner from a Handoff message. Peers that already have the slave
+ * resume it with the new owner, the same way they do after a host migration.
+ */
+#[derive(Component)]
+pub struct OwnedByHost;
+
+fn handoff_message(static_id: u16, owner: PeerId) -> Vec<u8> {
+    let mut bytes = vec![Handoff as u8];
+    bytes.extend_from_slice(&static_id.to_le_bytes());
+    bytes.extend_from_slice(&owner.0.to_le_bytes());
+
+    bytes
+}
+
+pub(super) fn resume_message(static_ids: &[u16]) -> Vec<u8> {
+    let mut bytes = vec![Resume as u8];
+    for static_id in static_ids {
+        bytes.extend_from_slice(&static_id.to_le_bytes());
+    }
+
+    bytes
+}
+
+impl NetworkingState {
+    pub(super) fn handle_resume(&mut self, sender: PeerId, data: &[u8]) {
+        let mut reader = Reader::new(data);
+        //skips the message type
+        reader.u8();
+
+        let now = Instant::now();
+        while let Some(static_id) = reader.u16() {
+            self.resumes.push((sender, static_id, now));
+        }
+    }
+}
+
+impl NetworkingState {
+    pub(super) fn handle_handoff(&mut self, sender: PeerId, data: &[u8]) {
+        let mut reader = Reader::new(data);
+        //skips the message type
+        reader.u8();
+
+        let (Some(static_id), Some(owner)) = (reader.u16(), reader.u64()) else {
+            println!("Rejected truncated handoff from {:?}", sender);
+            return;
+        };
+        self.handoffs
+            .push((sender, static_id, PeerId(owner), Instant::now()));
+    }
+}
+
+//gives masters marked OwnedByHost to the host, the create went out reliably before the handoff
+pub(super) fn hand_off_to_host(
+    mut networking: ResMut<NetworkingState>,
+    mut commands: Commands,
+    query: Query<(Entity, &SynchronizedMaster), With<OwnedByHost>>,
+) {
+    if !networking.connected {
+        return;
+    }
+    let host = networking.heir();
+    if host == networking.player_id || !networking.active_players.contains(&host) {
+        return;
+    }
+
+    for (entity, master) in query.iter() {
+        let static_id = master.static_id;
+        if !networking.relevancy.is_relevant(host, static_id) {
+            continue;
+        }
+
+        networking.send_all_reliable(handoff_message(static_id, host));
+        networking.delta.forget_entity(static_id);
+        networking.relevancy.forget_entity(static_id);
+        networking.priority.forget_entity(static_id);
+        networking.authority.set_owner(static_id, host);
+        commands
+            .entity(entity)
+            .remove::<SynchronizedMaster>()
+            .insert(SynchronizedSlave {
+                object_info: master.object_info,
+                static_id,
+                owner: host,
+                last_update: None,
+            });
+    }
+}
+
+//applies the new owner of entities other players gave away, the receiving one turns its slave into a master
+pub(super) fn handle_handoffs(
+    mut networking: ResMut<NetworkingState>,
+    mut commands: Commands,
+    index: Res<EntityIndex>,
+    mut slaves: Query<&mut SynchronizedSlave>,
+) {
+    if networking.handoffs.is_empty() {
+        return;
+    }
+
+    let handoffs: Vec<(PeerId, u16, PeerId, Instant)> = networking.handoffs.drain(..).collect();
+    for (sender, static_id, owner, received) in handoffs {
+        //only the owner can give an entity away
+        if let Some(current) = networking.authority.owner(static_id) {
+            if current != sender {
+                println!(
+                    "Rejected handoff of entity {} from {:?}, which doesn't own it",
+                    static_id, sender
+                );
+                continue;
+            }
+        }
+
+        let Some((entity, mut slave)) = index
+            .get(static_id)
+            .and_then(|entity| Some((entity, slaves.get_mut(entity).ok()?)))
+        else {
+            //the create from the sender was read this frame, its slave is indexed next frame
+            if owner == networking.player_id && received.elapsed() < RESUME_TIMEOUT {
+                networking
+                    .handoffs
+                    .push((sender, static_id, owner, received));
+            } else {
+                networking.authority.set_owner(static_id, owner);
+            }
+            continue;
+        };
+
+        //the new owner numbers its updates from the start again
+        networking.delta.forget_entity(static_id);
+        if owner == networking.player_id {
+            networking.authority.forget_entity(static_id);
+            networking.relevancy.mark_relevant(sender, static_id);
+            commands
+                .entity(entity)
+                .remove::<SynchronizedSlave>()
+                .insert(SynchronizedMaster {
+                    object_info: slave.object_info,
+                    static_id,
+                });
+        } else {
+            slave.owner = owner;
+            networking.authority.set_owner(static_id, owner);
+            if networking.active_players.contains(&owner) {
+                networking.transport.send_packet(
+                    owner,
+                    Reliability::Reliable,
+                    &resume_message(&[static_id]),
+                );
+            }
+        }
+    }
+}
+
+//takes over the entities other players resumed, entities this machine has no master of are deleted
+pub(super) fn handle_resumes(
+    mut networking: ResMut<NetworkingState>,
+    index: Res<EntityIndex>,
+    masters: Query<(), With<SynchronizedMaster>>,
+) {
+    if networking.resumes.is_empty() {
+        return;
+    }
+
+    let resumes: Vec<(PeerId, u16, Instant)> = networking.resumes.drain(..).collect();
+    for (peer, static_id, received) in resumes {
+        if !networking.active_players.contains(&peer) {
+            continue;
+        }
+
+        match index.get(static_id) {
+            //the peer already has the slave, relevancy deletes it later if it's too far away
+            Some(entity) if masters.contains(entity) => {
+                networking.relevancy.mark_relevant(peer, static_id);
+            }
+            //this machine hasn't noticed the owner leaving yet
+            Some(_) if received.elapsed() < RESUME_TIMEOUT => {
+                networking.resumes.push((peer, static_id, received));
+            }
+            _ => {
+                networking.transport.send_packet(
+                    peer,
+                    Reliability::Reliable,
+                    &entity_delete_message(static_id),
+                );
+            }
+        }
+    }
+}
+
+pub(super) fn emit_host_changes(
+    mut networking: ResMut<NetworkingState>,
+    mut writer: EventWriter<HostMigrated>,
+) {
+    for (previous, host) in networking.host_changes.drain(..) {
+        writer.send(HostMigrated { previous, host });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+
+    const LOCAL: PeerId = PeerId(1);
+    const OTHER: PeerId = PeerId(2);
+
+    //a world as seen from LOCAL, in a session with OTHER
+    fn session_world(network: &LoopbackNetwork) -> World {
+        let mut networking = NetworkingState::new(4, 256, Box::new(network.endpoint()), 64);
+        networking.connected = true;
+        networking.active_players.push(OTHER);
+
+        let mut world = World::new();
+        world.insert_resource(networking);
+        world.init_resource::<EntityIndex>();
+        world
+    }
+
+    fn spawn_slave(world: &mut World, static_id: u16, owner: PeerId) -> Entity {
+        world
+            .resource_mut::<NetworkingState>()
+            .authority
+            .set_owner(static_id, owner);
+        let entity = world
+            .spawn(SynchronizedSlave {
+                object_info: 0,
+                static_id,
+                owner,
+                last_update: None,
+            })
+            .id();
+        world.run_system_once(ids::index_entities);
+
+        entity
+    }
+
+    #[test]
+    fn masters_are_handed_to_the_host_once_it_has_them() {
+        let network = LoopbackNetwork::new();
+        let mut world = session_world(&network);
+        let host = network.endpoint();
+        world.resource_mut::<NetworkingState>().session_host = Some(OTHER);
+
+        let entity = world
+            .spawn((
+                SynchronizedMaster {
+                    object_info: 0,
+                    static_id: 5,
+                },
+                OwnedByHost,
+            ))
+            .id();
+        world.run_system_once(hand_off_to_host);
+        assert!(world.get::<SynchronizedMaster>(entity).is_some());
+        assert_eq!(host.is_packet_available(), None);
+
+        world
+            .resource_mut::<NetworkingState>()
+            .relevancy
+            .mark_relevant(OTHER, 5);
+        world.run_system_once(hand_off_to_host);
+
+        let slave = world.get::<SynchronizedSlave>(entity).unwrap();
+        assert_eq!(slave.owner(), OTHER);
+        assert!(world.get::<SynchronizedMaster>(entity).is_none());
+        let mut buffer = [0; 16];
+        let (sender, len) = host.read_packet(&mut buffer).unwrap();
+        assert_eq!(sender, LOCAL);
+        assert_eq!(&buffer[..len], &handoff_message(5, OTHER)[..]);
+    }
+
+    #[test]
+    fn the_new_owner_turns_its_slave_into_a_master() {
+        let network = LoopbackNetwork::new();
+        let mut world = session_world(&network);
+        let entity = spawn_slave(&mut world, 5, OTHER);
+
+        world
+            .resource_mut::<NetworkingState>()
+            .handle_handoff(OTHER, &handoff_message(5, LOCAL));
+        world.run_system_once(handle_handoffs);
+
+        assert_eq!(
+            world
+                .get::<SynchronizedMaster>(entity)
+                .map(|m| m.static_id()),
+            Some(5)
+        );
+        let networking = world.resource::<NetworkingState>();
+        assert!(networking.relevancy.is_relevant(OTHER, 5));
+        assert_eq!(networking.authority.owner(5), None);
+    }
+
+    #[test]
+    fn only_the_owner_can_hand_an_entity_off() {
+        let network = LoopbackNetwork::new();
+        let mut world = session_world(&network);
+        let entity = spawn_slave(&mut world, 5, PeerId(3));
+
+        world
+            .resource_mut::<NetworkingState>()
+            .handle_handoff(OTHER, &handoff_message(5, LOCAL));
+        world.run_system_once(handle_handoffs);
+
+        assert_eq!(
+            world.get::<SynchronizedSlave>(entity).unwrap().owner(),
+            PeerId(3)
+        );
+        assert!(world.resource::<NetworkingState>().handoffs.is_empty());
+    }
+}
@@ -1,17 +1,65 @@
+use bevy::app::AppExit;
 use bevy::prelude::*;
 use std::any::Any;
+use std::collections::{HashMap, HashSet};
 use std::sync::Mutex;
-use steamworks::*;
+use std::time::Instant;
 
+pub mod authority;
+pub mod clock;
+mod delta;
+pub mod hierarchy;
+pub mod ids;
+pub mod inspect;
+pub mod interpolation;
+pub mod lag_compensation;
+mod migration;
+mod players;
+pub mod priority;
+mod protocol;
+pub mod relevancy;
+pub mod rpc;
+pub mod session;
+pub mod transport;
 mod type_registry;
+mod wire;
 
-use type_registry::Registered;
+use authority::{
+    validate_speed, AddValidator, AuthoritySettings, AuthorityState, SpeedLimit, UpdateChecks,
+    UpdateContext,
+};
+use clock::NetworkClock;
+use delta::DeltaState;
+use ids::{EntityIndex, IdAllocator};
+use interpolation::{InterpolationSettings, SnapshotBuffer};
+use lag_compensation::{ColliderHistory, LagCompensationSettings};
+pub use migration::{HostMigrated, OwnedByHost};
+pub use players::ConnectionRejected;
+use priority::{PrioritySettings, PriorityState};
+pub use protocol::*;
+use relevancy::{RelevancySettings, RelevancyState, Viewer};
+use rpc::{AddRpc, RpcRegistry};
+use session::{ReadyState, Session, SessionBackendResource, SessionCommand, SessionEvent};
+use transport::*;
+use type_registry::{Registered, TypeRegistry};
+use wire::*;
 
 pub struct NetworkingPlugin {
     pub max_players: u16,
     pub max_synced_objects: u32,
-    pub app_id: u32,
+    pub transport: TransportKind,
     pub packet_per_frame_limit: u32,
+    pub interpolation: InterpolationSettings,
+    pub relevancy: RelevancySettings,
+    pub priority: PrioritySettings,
+    pub authority: AuthoritySettings,
+    pub lag_compensation: LagCompensationSettings,
+    //ticks per second of the shared tick in NetworkClock
+    pub tick_rate: f64,
+    //listens for players from startup and owns every entity other players leave behind
+    pub dedicated_server: bool,
+    //writes every packet sent and received to this file, see inspect::print_capture
+    pub capture: Option<std::path::PathBuf>,
 }
 
 impl Default for NetworkingPlugin {
@@ -19,28 +67,170 @@ impl Default for NetworkingPlugin {
         NetworkingPlugin {
             max_players: 32,
             max_synced_objects: 1024,
-            app_id: 480,
+            transport: TransportKind::Steam { app_id: 480 },
             packet_per_frame_limit: 64,
+            interpolation: InterpolationSettings::default(),
+            relevancy: RelevancySettings::default(),
+            priority: PrioritySettings::default(),
+            authority: AuthoritySettings::default(),
+            lag_compensation: LagCompensationSettings::default(),
+            tick_rate: 60.0,
+            dedicated_server: false,
+            capture: None,
         }
     }
 }
 
 impl Plugin for NetworkingPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(NetworkingState::new(
+        type_registry::register_all(app);
+        //peers with a different set of networked types can't decode each other's entities
+        let registry = app.world.resource::<TypeRegistry>();
+        let registry_hash = registry.table_hash();
+        let type_names = registry.type_names();
+
+        //steam callbacks have to run on the main thread, including the ones accepting p2p sessions
+        if let Some(app_id) = self.transport.steam_app_id() {
+            if let (_, Some(callbacks)) = steam_client(app_id) {
+                app.insert_non_send_resource(callbacks)
+                    .add_systems(Update, run_steam_callbacks.before(handle_networking));
+            }
+        }
+
+        let mut transport: Box<dyn Transport> = match &self.transport {
+            //captures hold whole messages, so they are played back above the channel layer
+            TransportKind::Replay { .. } => self.transport.create(),
+            //every other backend goes through the channel layer so large messages are fragmented
+            //  and reliable messages arrive in order even over plain udp
+            _ => Box::new(ChanneledTransport::new(self.transport.create())),
+        };
+        if let Some(path) = &self.capture {
+            transport = Box::new(
+                CaptureTransport::create(transport, path, PROTOCOL_VERSION, &type_names)
+                    .expect("failed to create the packet capture"),
+            );
+        }
+
+        let mut state = NetworkingState::new(
             self.max_players,
             self.max_synced_objects,
-            self.app_id,
+            transport,
             self.packet_per_frame_limit,
-        ))
-        .add_systems(Update, handle_networking)
-        .add_systems(Update, sync_slave_entities)
-        .add_systems(Update, sync_master_entities)
-        .add_systems(Update, delete_marked_slaves)
-        .add_systems(Update, delete_marked_masters);
+        );
+        state.registry_hash = registry_hash;
+        if self.dedicated_server {
+            state.dedicated_server = true;
+            state.host();
+        }
+        //a replay starts with packets from a session that is already running
+        if matches!(self.transport, TransportKind::Replay { .. }) {
+            state.connected = true;
+        }
+
+        app.add_event::<ConnectionRejected>()
+            .add_event::<HostMigrated>()
+            .insert_resource(state)
+            .insert_resource(self.interpolation)
+            .insert_resource(self.relevancy)
+            .insert_resource(self.priority)
+            .insert_resource(self.authority)
+            .insert_resource(self.lag_compensation)
+            .init_resource::<ColliderHistory>()
+            .add_validator(validate_speed)
+            .insert_resource(NetworkClock::new(self.tick_rate))
+            .init_resource::<EntityIndex>()
+            .add_event::<SessionCommand>()
+            .add_event::<SessionEvent>()
+            .init_resource::<Session>()
+            .insert_resource(SessionBackendResource::for_transport(&self.transport))
+            .add_rpc::<ReadyState>(Reliability::Reliable)
+            .add_systems(
+                Update,
+                (
+                    session::run_console_commands,
+                    session::handle_session_commands,
+                    session::update_session,
+                    session::print_session_events,
+                )
+                    .chain()
+                    .after(handle_networking)
+                    .after(players::emit_rejections),
+            )
+            .add_systems(Update, handle_networking)
+            .add_systems(Update, ids::index_entities.before(sync_slave_entities))
+            .add_systems(
+                Update,
+                clock::sync_clock
+                    .after(handle_networking)
+                    .before(sync_master_entities),
+            )
+            .add_systems(Update, sync_slave_entities)
+            .add_systems(
+                Update,
+                hierarchy::update_network_parents.before(relevancy::update_relevancy),
+            )
+            .add_systems(
+                Update,
+                relevancy::update_relevancy.before(sync_master_entities),
+            )
+            .add_systems(
+                Update,
+                hierarchy::apply_network_parents.after(sync_slave_entities),
+            )
+            .add_systems(Update, sync_master_entities)
+            .add_systems(Update, delete_marked_slaves)
+            .add_systems(Update, delete_marked_masters)
+            .add_systems(
+                Update,
+                players::drop_silent_players
+                    .after(handle_networking)
+                    .before(players::handle_departed_players),
+            )
+            .add_systems(Update, players::handle_departed_players)
+            .add_systems(
+                Update,
+                migration::handle_resumes
+                    .after(players::handle_departed_players)
+                    .before(relevancy::update_relevancy),
+            )
+            .add_systems(
+                Update,
+                migration::handle_handoffs
+                    .after(sync_slave_entities)
+                    .before(relevancy::update_relevancy),
+            )
+            .add_systems(
+                Update,
+                migration::hand_off_to_host
+                    .after(relevancy::update_relevancy)
+                    .after(sync_master_entities),
+            )
+            .add_systems(Update, migration::emit_host_changes)
+            .add_systems(Update, players::emit_rejections)
+            .add_systems(
+                Update,
+                interpolation::interpolate_slaves.after(sync_slave_entities),
+            )
+            .add_systems(
+                PostUpdate,
+                lag_compensation::record_collider_history
+                    .after(bevy::transform::TransformSystem::TransformPropagate),
+            )
+            .add_systems(Last, flush_transport_on_exit);
     }
 }
 
+//buffered writes like the packet capture would be lost if the app exits without this
+fn flush_transport_on_exit(mut exits: EventReader<AppExit>, networking: Res<NetworkingState>) {
+    if exits.read().next().is_some() {
+        networking.transport.flush();
+    }
+}
+
+fn run_steam_callbacks(callbacks: NonSend<SteamCallbacks>) {
+    callbacks.run();
+}
+
 #[derive(Resource)]
 pub struct NetworkingState {
     pub max_players: u16,
@@ -48,135 +238,182 @@ pub struct NetworkingState {
     pub packet_per_frame_limit: u32,
 
     pub connected: bool,
-    pub client: Client,
-    pub player_id: SteamId,
-    pub active_players: Vec<SteamId>,
+    pub transport: Box<dyn Transport>,
+    pub player_id: PeerId,
+    pub active_players: Vec<PeerId>,
+    pub dedicated_server: bool,
+    //the dedicated server of the session, if it has one
+    pub server: Option<PeerId>,
+    //the player hosting the session, a new one is elected when it leaves
+    session_host: Option<PeerId>,
+    registry_hash: u64,
 
     sync_messages: Vec<SyncMessage>,
-    event_queue_out: Mutex<Vec<NetworkingEvent>>,
-    event_queue_in: Vec<Mutex<Vec<NetworkingEvent>>>, // The index of the outer vector is the event type
+    departed_players: Vec<PeerId>,
+    rejections: Vec<(PeerId, RejectReason)>,
+    //players that completed the join handshake with this machine
+    handshakes: HashSet<PeerId>,
+    //when anything was last received from each player, used to notice players that vanished
+    last_heard: HashMap<PeerId, Instant>,
+    host_changes: Vec<(PeerId, PeerId)>,
+    //entities other players still hold after their owner left, see migration::handle_resumes
+    resumes: Vec<(PeerId, u16, Instant)>,
+    //(sender, static id, new owner) of entities given away, see migration::handle_handoffs
+    handoffs: Vec<(PeerId, u16, PeerId, Instant)>,
+    inputs_in: Vec<(PeerId, Vec<u8>)>,
+    clock_messages: Vec<(PeerId, Vec<u8>)>,
+    delta: DeltaState,
+    relevancy: RelevancyState,
+    priority: PriorityState,
+    authority: AuthorityState,
+    ids: IdAllocator,
+    //static id of every synchronized entity and whether this machine is its master,
+    //  masters are added as soon as they are created, before their component is inserted
+    networked: HashMap<Entity, (u16, bool)>,
+    event_queue_out: Mutex<Vec<OutgoingEvent>>,
+    event_queue_in: Mutex<HashMap<u16, Vec<(PeerId, NetworkingEvent)>>>, // The key is the event id
 }
 
 impl NetworkingState {
     pub fn new(
         max_players: u16,
         max_synced_objects: u32,
-        app_id: u32,
+        transport: Box<dyn Transport>,
         packet_per_frame_limit: u32,
     ) -> Self {
-        let (client, _) = Client::init_app(app_id).unwrap();
-        let player_id = client.user().steam_id();
+        let player_id = transport.local_peer();
 
         Self {
             max_players,
             max_synced_objects,
             packet_per_frame_limit,
             connected: false,
-            client,
+            transport,
             player_id,
             active_players: Vec::new(),
+            dedicated_server: false,
+            server: None,
+            session_host: None,
+            registry_hash: 0,
             sync_messages: Vec::new(),
+            departed_players: Vec::new(),
+            rejections: Vec::new(),
+            handshakes: HashSet::new(),
+            last_heard: HashMap::new(),
+            host_changes: Vec::new(),
+            resumes: Vec::new(),
+            handoffs: Vec::new(),
+            inputs_in: Vec::new(),
+            clock_messages: Vec::new(),
+            delta: DeltaState::default(),
+            relevancy: RelevancyState::default(),
+            priority: PriorityState::default(),
+            authority: AuthorityState::default(),
+            ids: IdAllocator::new(max_players),
+            networked: HashMap::new(),
             event_queue_out: Mutex::new(Vec::new()),
-            event_queue_in: vec![0; EventType::num_variants() as usize]
-                .into_iter()
-                .map(|_| Mutex::new(Vec::new()))
-                .collect::<Vec<Mutex<Vec<NetworkingEvent>>>>(),
+            event_queue_in: Mutex::new(HashMap::new()),
         }
     }
 }
 
 impl NetworkingState {
+    //sends an event reliably to every player
     pub fn queue_event_out(&self, event: NetworkingEvent) {
-        self.event_queue_out.lock().unwrap().push(event);
+        self.event_queue_out.lock().unwrap().push(OutgoingEvent {
+            target: None,
+            reliability: Reliability::Reliable,
+            event,
+        });
+    }
+    pub fn queue_event_to(&self, peer: PeerId, reliability: Reliability, event: NetworkingEvent) {
+        self.event_queue_out.lock().unwrap().push(OutgoingEvent {
+            target: Some(peer),
+            reliability,
+            event,
+        });
+    }
+    pub fn queue_event_all(&self, reliability: Reliability, event: NetworkingEvent) {
+        self.event_queue_out.lock().unwrap().push(OutgoingEvent {
+            target: None,
+            reliability,
+            event,
+        });
     }
-    pub fn get_event_in(&self, event_type: u8) -> Vec<NetworkingEvent> {
-        self.event_queue_in[event_type as usize]
+    //returns every event received with the given id along with its sender
+    pub fn get_event_in(&self, event_id: u16) -> Vec<(PeerId, NetworkingEvent)> {
+        self.event_queue_in
             .lock()
             .unwrap()
-            .drain(..)
-            .collect()
+            .remove(&event_id)
+            .unwrap_or_default()
+    }
+
+    //sends player input to the owner of the player, unreliable because inputs are resent until processed,
+    //  sequenced because a late packet only carries inputs a newer one already has
+    pub fn send_input(&self, owner: PeerId, bytes: Vec<u8>) {
+        let mut message = vec![Input as u8];
+        message.extend_from_slice(&bytes);
+        self.transport
+            .send_packet(owner, Reliability::UnreliableSequenced, &message);
+    }
+    pub fn drain_inputs(&mut self) -> Vec<(PeerId, Vec<u8>)> {
+        self.inputs_in.drain(..).collect()
     }
 }
 
 #[derive(Clone)]
 struct SyncMessage {
+    sender: PeerId,
     data: Vec<u8>,
 }
 
 #[derive(Clone)]
 pub struct NetworkingEvent {
-    pub event_type: EventType,
-    length: u16,
+    //identifies what the data is, see Rpc::ID
+    pub event_id: u16,
     pub data: Vec<u8>,
 }
 
 impl NetworkingEvent {
-    fn new(event_type: EventType, data: Vec<u8>) -> NetworkingEvent {
-        NetworkingEvent {
-            event_type,
-            length: data.len() as u16 + 3,
-            data,
-        }
+    pub fn new(event_id: u16, data: Vec<u8>) -> NetworkingEvent {
+        NetworkingEvent { event_id, data }
     }
     fn to_bytes(&self) -> Vec<u8> {
         let mut bytes: Vec<u8> = Vec::new();
-        bytes.push(self.event_type as u8);
-        bytes.push(self.length.to_le_bytes()[0]);
-        bytes.push(self.length.to_le_bytes()[1]);
-        bytes.append(&mut self.data.clone().into());
+        bytes.push(Event as u8);
+        bytes.extend_from_slice(&self.event_id.to_le_bytes());
+        bytes.extend_from_slice(&self.data);
         bytes
     }
-    fn from_bytes(bytes: &[u8]) -> NetworkingEvent {
-        let event_type = bytes[0].into();
-        let length = u16::from_le_bytes([bytes[1], bytes[2]]);
-        let data = bytes[3..(length as usize)].to_vec();
-        NetworkingEvent {
-            event_type,
-            length,
-            data,
-        }
-    }
-}
-
-use EventType::*;
-#[repr(u8)]
-#[derive(Copy, Clone)]
-pub enum EventType {
-    EntityCreate,
-    EntityDelete,
-    EntityUpdate,
-    PlayerJoin,
-    PlayerLeave,
-    Event,
-}
+    //the data runs until the end of the packet so no length is needed
+    fn from_bytes(bytes: &[u8]) -> Option<NetworkingEvent> {
+        let mut reader = Reader::new(bytes);
+        //skips the message type
+        reader.u8()?;
+        let event_id = reader.u16()?;
 
-impl EventType {
-    #[inline]
-    fn num_variants() -> u8 {
-        6
+        Some(NetworkingEvent {
+            event_id,
+            data: reader.remaining().to_vec(),
+        })
     }
 }
 
-impl From<u8> for EventType {
-    fn from(value: u8) -> Self {
-        match value {
-            0 => EventType::EntityCreate,
-            1 => EventType::EntityDelete,
-            2 => EventType::EntityUpdate,
-            3 => EventType::PlayerJoin,
-            4 => EventType::PlayerLeave,
-            5 => EventType::Event,
-            _ => panic!("Invalid EventType"),
-        }
-    }
+struct OutgoingEvent {
+    //None sends to every player
+    target: Option<PeerId>,
+    reliability: Reliability,
+    event: NetworkingEvent,
 }
 
+use EventType::*;
+
 #[bevy_trait_query::queryable]
 pub trait Serializable: Send + Sync + Any {
-    fn from_bytes(&mut self, bytes: &[u8]);
+    fn from_bytes(&mut self, bytes: &[u8]) -> bincode::Result<()>;
     fn to_bytes(&self) -> Vec<u8>;
 
-    fn get_length(&self) -> usize;
     //used to identify the type of the component when synchronizing
     fn get_type_id(&self) -> u16;
 }
@@ -185,15 +422,13 @@ impl<T> Serializable for T
 where
     T: serde::Serialize + serde::de::DeserializeOwned + Send + Sync + Any + Registered + 'static,
 {
-    fn from_bytes(&mut self, bytes: &[u8]) {
-        *self = bincode::deserialize(bytes).unwrap();
+    fn from_bytes(&mut self, bytes: &[u8]) -> bincode::Result<()> {
+        *self = bincode::deserialize(bytes)?;
+        Ok(())
     }
     fn to_bytes(&self) -> Vec<u8> {
         bincode::serialize(self).unwrap()
     }
-    fn get_length(&self) -> usize {
-        bincode::serialize(self).unwrap().len()
-    }
     fn get_type_id(&self) -> u16 {
         self.registered_id()
     }
@@ -206,7 +441,24 @@ pub struct SynchronizedSlave {
                       *Third bit marks whether or not to destroy on owner disconnect
                       */
     static_id: u16,
-    owner: u16,
+    owner: PeerId,
+    //owner's timestamp of an update applied this frame, in seconds
+    last_update: Option<f64>,
+}
+
+impl SynchronizedSlave {
+    pub fn owner(&self) -> PeerId {
+        self.owner
+    }
+
+    pub fn static_id(&self) -> u16 {
+        self.static_id
+    }
+
+    //returns the owner's timestamp if an update was applied since the last call
+    pub fn take_update(&mut self) -> Option<f64> {
+        self.last_update.take()
+    }
 }
 
 #[derive(Component)]
@@ -218,38 +470,52 @@ pub struct SynchronizedMaster {
     static_id: u16,
 }
 impl SynchronizedMaster {
-    pub fn destroy(&mut self, networking: &NetworkingState) {
+    //the entity is deleted on every peer that has it by delete_marked_masters
+    pub fn destroy(&mut self) {
         //sets the first bit which signifies whether to delete to 1 marking it for deletion
         self.object_info |= 0b10000000;
+    }
 
-        let mut bytes: Vec<u8> = Vec::new();
-
-        bytes.push(EntityDelete as u8);
-        bytes.push(self.static_id.to_le_bytes()[0]);
-        bytes.push(self.static_id.to_le_bytes()[1]);
-
-        networking.send_all_reliable(bytes);
+    pub fn static_id(&self) -> u16 {
+        self.static_id
     }
 }
 
-fn handle_networking(mut networking_res: ResMut<NetworkingState>) {
+fn handle_networking(
+    mut networking_res: ResMut<NetworkingState>,
+    rpc_registry: Res<RpcRegistry>,
+    mut unknown_rpcs: Local<HashSet<u16>>,
+) {
+    //every registered rpc reads its events right after this system, whatever is left has no reader
+    networking_res.event_queue_in.lock().unwrap().clear();
+
     if !networking_res.connected {
         return;
     }
 
-    let networking = networking_res.client.networking();
-
     let mut guard = networking_res.event_queue_out.lock().unwrap();
-    let events_to_send: Vec<NetworkingEvent> = guard.drain(..).collect();
+    let events_to_send: Vec<OutgoingEvent> = guard.drain(..).collect();
 
     drop(guard); //unlocks the mutex
 
-    events_to_send
-        .into_iter()
-        .map(|event| {
-            networking_res.send_all_reliable(event.to_bytes());
-        })
-        .for_each(drop);
+    for outgoing in events_to_send {
+        let bytes = outgoing.event.to_bytes();
+        match (outgoing.target, outgoing.reliability) {
+            (Some(peer), reliability) => {
+                networking_res
+                    .transport
+                    .send_packet(peer, reliability, &bytes);
+            }
+            (None, Reliability::Reliable) => networking_res.send_all_reliable(bytes),
+            (None, reliability) => {
+                for player in networking_res.active_players.iter() {
+                    networking_res
+                        .transport
+                        .send_packet(*player, reliability, &bytes);
+                }
+            }
+        }
+    }
 
     let mut i: u32 = 0;
     loop {
@@ -259,147 +525,440 @@ fn handle_networking(mut networking_res: ResMut<NetworkingState>) {
         }
         i += 1;
 
-        let is_packet_available = networking.is_p2p_packet_available();
+        let is_packet_available = networking_res.transport.is_packet_available();
         //if no packet is available, return
         if !is_packet_available.is_some() {
             return;
         }
 
         //creates a buffer with the size of the packet
-        let mut buffer: Vec<u8> = Vec::with_capacity(is_packet_available.unwrap());
+        let mut buffer: Vec<u8> = vec![0; is_packet_available.unwrap()];
 
         //reads the packet into the buffer
-        let (_sender, len) = networking.read_p2p_packet(buffer.as_mut_slice()).unwrap();
-
-        //if the sender is not in the active players list, add them
-        match EventType::from(buffer[0]) {
-            EntityUpdate => networking_res.sync_messages.push(SyncMessage {
-                data: buffer[..len].into(),
-            }),
-            EntityDelete => networking_res.sync_messages.push(SyncMessage {
-                data: buffer[..len].into(),
-            }),
-            EntityCreate => {
-                //TODO create entity
-            }
-            PlayerJoin => {
-                //TODO add player
+        let Some((sender, len)) = networking_res.transport.read_packet(&mut buffer) else {
+            return;
+        };
+        if len == 0 {
+            continue;
+        }
+
+        let message_type = match EventType::try_from(buffer[0]) {
+            Ok(message_type) => message_type,
+            Err(value) => {
+                println!("Ignored unknown message type {} from {:?}", value, sender);
+                continue;
             }
-            PlayerLeave => {
-                //TODO remove player and all their entities that are destroy_on_owner_disconnect
-                //TODO decide who inherits their entities
+        };
+
+        //only joins and rejections are accepted from peers that haven't completed the handshake
+        if !networking_res.active_players.contains(&sender)
+            && message_type != PlayerJoin
+            && message_type != Reject
+        {
+            continue;
+        }
+        if networking_res.active_players.contains(&sender) {
+            networking_res.last_heard.insert(sender, Instant::now());
+        }
+
+        match message_type {
+            EntityUpdate | EntityDelete | EntityCreate => {
+                networking_res.sync_messages.push(SyncMessage {
+                    sender,
+                    data: buffer[..len].into(),
+                })
             }
-            Event => {
-                //doesn't include the first byte which is the msg type
-                let event = NetworkingEvent::from_bytes(&buffer[1..]);
-
-                let mut queue_in = networking_res.event_queue_in[event.event_type as usize]
-                    .lock()
-                    .unwrap();
-                queue_in.push(event);
+            PlayerJoin => networking_res.handle_player_join(sender, &buffer[..len]),
+            PlayerLeave => networking_res.handle_player_leave(sender),
+            Reject => networking_res.handle_reject(sender, &buffer[..len]),
+            EntityAck => networking_res.delta.handle_acks(sender, &buffer[..len]),
+            EntityDeleteAck => {
+                if let Some(static_id) = Reader::new(&buffer[1..len]).u16() {
+                    networking_res.ids.confirm_delete(sender, static_id);
+                }
             }
+            //doesn't include the first byte which is the msg type
+            Input => networking_res
+                .inputs_in
+                .push((sender, buffer[1..len].to_vec())),
+            Resume => networking_res.handle_resume(sender, &buffer[..len]),
+            Handoff => networking_res.handle_handoff(sender, &buffer[..len]),
+            Ping | Pong => networking_res
+                .clock_messages
+                .push((sender, buffer[..len].to_vec())),
+            Event => match NetworkingEvent::from_bytes(&buffer[..len]) {
+                //rpcs only registered on some machines, like voice on a dedicated server
+                Some(event) if rpc_registry.rpc_name(event.event_id).is_none() => {
+                    if unknown_rpcs.insert(event.event_id) {
+                        println!(
+                            "Ignored rpc {} from {:?}, it isn't registered on this machine",
+                            event.event_id, sender
+                        );
+                    }
+                }
+                Some(event) => {
+                    let mut queue_in = networking_res.event_queue_in.lock().unwrap();
+                    queue_in
+                        .entry(event.event_id)
+                        .or_default()
+                        .push((sender, event));
+                }
+                None => println!("Rejected truncated event from {:?}", sender),
+            },
         }
     }
 }
 
-fn sync_slave_entities(
+pub(crate) fn sync_slave_entities(
+    time: Res<Time>,
     mut networking: ResMut<NetworkingState>,
-    mut query: Query<(&mut dyn Serializable, &mut SynchronizedSlave)>,
+    mut commands: Commands,
+    registry: Res<TypeRegistry>,
+    index: Res<EntityIndex>,
+    checks: UpdateChecks,
+    mut query: Query<(
+        &mut dyn Serializable,
+        &mut SynchronizedSlave,
+        Option<&SpeedLimit>,
+    )>,
 ) {
     if !networking.connected {
         return;
     }
     //clones the sync messages to prevent borrowing issues
     let sync_messages = networking.sync_messages.clone();
+    let now = time.elapsed_seconds_f64();
+    let mut kicked: Vec<PeerId> = Vec::new();
 
     for message in sync_messages.into_iter() {
-        match message.data[0].into() {
-            EntityUpdate => {
-                let static_id = u16::from_le_bytes([message.data[1], message.data[2]]);
-                for mut entity in query.iter_mut() {
-                    if entity.1.static_id == static_id {
-                        //skips the first 3 bytes which are the message type and static id
-                        let mut i = 3;
-
-                        //used to keep track of the number of components updated
-                        let mut n = 0;
-                        while i < message.data.len() {
-                            const MAX_COMPONENTS_PER_ENTITY: usize = 16; //arbitrary number, should be removed after testing
-                            if n > MAX_COMPONENTS_PER_ENTITY {
-                                println!("Exceeded maximum ");
-                                break;
+        let mut reader = Reader::new(&message.data);
+        //every sync message starts with the message type and static id
+        let (Some(message_type), Some(static_id)) = (reader.u8(), reader.u16()) else {
+            println!("Rejected truncated sync message");
+            continue;
+        };
+        if kicked.contains(&message.sender) {
+            continue;
+        }
+
+        match EventType::try_from(message_type) {
+            Ok(EntityUpdate) => {
+                //late updates from a previous owner are expected after ownership changes
+                if networking.authority.owner(static_id) != Some(message.sender) {
+                    continue;
+                }
+
+                let (Some(sequence), Some(is_delta), Some(baseline), Some(timestamp)) =
+                    (reader.u16(), reader.u8(), reader.u16(), reader.u32())
+                else {
+                    println!("Rejected truncated update for entity {}", static_id);
+                    continue;
+                };
+                let Some(components) = read_components(&mut reader) else {
+                    println!("Rejected truncated update for entity {}", static_id);
+                    continue;
+                };
+
+                //combines the delta with the baseline it was made against
+                let Some(state) = networking.delta.receive_update(
+                    message.sender,
+                    static_id,
+                    sequence,
+                    (is_delta != 0).then_some(baseline),
+                    components,
+                ) else {
+                    continue;
+                };
+
+                let Some(Ok(mut entity)) = index.get(static_id).map(|entity| query.get_mut(entity))
+                else {
+                    continue;
+                };
+                let timestamp = timestamp as f64 / 1000.0;
+
+                //every changed component goes through the validators before anything is applied
+                let rejection = networking
+                    .authority
+                    .elapsed(static_id, timestamp, now)
+                    .and_then(|elapsed| {
+                        state.iter().find_map(|(component_id, bytes)| {
+                            let component = entity
+                                .0
+                                .iter()
+                                .find(|component| component.get_type_id() == *component_id)?;
+                            //the rendered component is interpolated behind the owner, so moves are
+                            //  measured from the last accepted state instead
+                            let previous = networking
+                                .authority
+                                .accepted(static_id, *component_id)
+                                .map_or_else(|| component.to_bytes(), |bytes| bytes.to_vec());
+                            if previous == *bytes {
+                                return None;
                             }
-                            let component_id =
-                                u16::from_le_bytes([message.data[i], message.data[i + 1]]);
-                            i += 2;
-
-                            //finds the component with the matching id and updates it
-                            for mut component in &mut entity.0 {
-                                if component.get_type_id() == component_id {
-                                    let len = component.get_length();
-                                    component.from_bytes(&message.data[i..i + len]);
-                                    i += len;
-                                    break;
-                                }
+
+                            let context = UpdateContext {
+                                type_id: *component_id,
+                                previous: &previous,
+                                next: bytes,
+                                elapsed,
+                                speed_limit: entity.2.map(|limit| limit.0),
+                            };
+                            checks.rejection(&context)
+                        })
+                    });
+
+                if let Some(reason) = rejection {
+                    println!(
+                        "Rejected update for entity {} from {:?}, {}",
+                        static_id, message.sender, reason
+                    );
+                    if networking
+                        .authority
+                        .strike(message.sender, &checks.settings, now)
+                    {
+                        kicked.push(message.sender);
+                    }
+                    continue;
+                }
+                networking
+                    .authority
+                    .accept(static_id, timestamp, now, &state);
+
+                entity.1.last_update = Some(timestamp);
+                for (component_id, bytes) in state.iter() {
+                    //finds the component with the matching id and updates it,
+                    //  components this machine doesn't have are skipped
+                    for mut component in &mut entity.0 {
+                        if component.get_type_id() == *component_id {
+                            if component.from_bytes(bytes).is_err() {
+                                println!(
+                                    "Failed to decode component {}",
+                                    registry.describe(*component_id)
+                                );
                             }
-                            n += 1;
+                            break;
                         }
-                        break;
                     }
                 }
             }
-            EntityDelete => {
-                let static_id = u16::from_le_bytes([message.data[1], message.data[2]]);
-                for mut entity in query.iter_mut() {
-                    if entity.1.static_id == static_id {
-                        entity.1.object_info |= 0b10000000;
-                        break;
+            Ok(EntityCreate) => {
+                //ignores duplicate creates for an entity that already exists
+                if index.contains(static_id) {
+                    continue;
+                }
+
+                //static ids can't be taken over by creating the entity again
+                if let Some(owner) = networking.authority.owner(static_id) {
+                    if owner != message.sender {
+                        println!(
+                            "Rejected create for entity {} from {:?}, it belongs to {:?}",
+                            static_id, message.sender, owner
+                        );
+                        continue;
+                    }
+                }
+
+                let Some(object_info) = reader.u8() else {
+                    println!("Rejected truncated create for entity {}", static_id);
+                    continue;
+                };
+                let Some(components) = read_components(&mut reader) else {
+                    println!("Rejected truncated create for entity {}", static_id);
+                    continue;
+                };
+
+                networking.authority.set_owner(static_id, message.sender);
+                let mut entity = commands.spawn((
+                    SynchronizedSlave {
+                        object_info,
+                        static_id,
+                        owner: message.sender,
+                        last_update: None,
+                    },
+                    SnapshotBuffer::default(),
+                ));
+
+                for (component_id, bytes) in components {
+                    networking
+                        .authority
+                        .accept_component(static_id, component_id, bytes);
+                    if !registry.construct(component_id, &mut entity, bytes) {
+                        println!(
+                            "Failed to construct component {}",
+                            registry.describe(component_id)
+                        );
+                    }
+                }
+            }
+            Ok(EntityDelete) => {
+                if let Some(owner) = networking.authority.owner(static_id) {
+                    if owner != message.sender {
+                        println!(
+                            "Rejected delete for entity {} from {:?}, which doesn't own it",
+                            static_id, message.sender
+                        );
+                        continue;
                     }
                 }
+
+                if let Some(Ok(mut entity)) =
+                    index.get(static_id).map(|entity| query.get_mut(entity))
+                {
+                    entity.1.object_info |= 0b10000000;
+                }
+
+                //lets the owner reuse the static id
+                let mut bytes = vec![EntityDeleteAck as u8];
+                bytes.extend_from_slice(&static_id.to_le_bytes());
+                networking
+                    .transport
+                    .send_packet(message.sender, Reliability::Reliable, &bytes);
             }
-            _ => {
-                panic!("Invalid sync message");
-            } //Should be impossible to reach
+            _ => println!("Ignored invalid sync message type {}", message_type),
         }
     }
 
     networking.sync_messages.clear();
+
+    for peer in kicked {
+        networking.kick(peer);
+    }
+
+    //acks are unreliable because a lost ack only delays the next baseline
+    let acks = networking.delta.drain_acks();
+    for (owner, bytes) in acks {
+        networking
+            .transport
+            .send_packet(owner, Reliability::Unreliable, &bytes);
+    }
+}
+
+struct PeriodicState {
+    static_id: u16,
+    sequence: u16,
+    state: Vec<(u16, Vec<u8>)>,
+    position: Option<Vec3>,
+    speed: f32,
 }
 
+//sends each peer the updates with the highest accumulated priority that fit in its budget
 fn sync_master_entities(
-    networking: Res<NetworkingState>,
-    query: Query<(&dyn Serializable, &SynchronizedMaster)>,
+    clock: Res<NetworkClock>,
+    time: Res<Time>,
+    settings: Res<PrioritySettings>,
+    mut networking: ResMut<NetworkingState>,
+    query: Query<(
+        &dyn Serializable,
+        &SynchronizedMaster,
+        Option<&GlobalTransform>,
+    )>,
+    viewers: Query<(&Viewer, &GlobalTransform)>,
 ) {
     if !networking.connected {
         return;
     }
+    //borrows the fields separately
+    let networking = &mut *networking;
 
-    for entity in query.iter() {
-        if (entity.1.object_info & 0b01000000) != 0 {
-            //checks whether or not to sync periodically
-            let mut bytes: Vec<u8> = Vec::new();
-
-            //Adds header data (message type and static id)
-            bytes.push(EntityUpdate as u8);
-            bytes.extend_from_slice(&entity.1.static_id.to_le_bytes());
-
-            for component in entity.0 {
-                //Adds component type id and component data
-                //  (length is constant per type and
-                //   therefore doesn't need to be sent)
-                bytes.extend_from_slice(&component.get_type_id().to_le_bytes());
-                bytes.extend_from_slice(&component.to_bytes());
+    //milliseconds on the host's clock, used by receivers to interpolate
+    let timestamp = (clock.host_time() * 1000.0) as u32;
+
+    let mut entities: Vec<PeriodicState> = Vec::new();
+    for (components, master, transform) in query.iter() {
+        //checks whether or not to sync periodically
+        if (master.object_info & 0b01000000) == 0 {
+            continue;
+        }
+
+        let static_id = master.static_id;
+        let state: Vec<(u16, Vec<u8>)> = components
+            .into_iter()
+            .map(|component| (component.get_type_id(), component.to_bytes()))
+            .collect();
+        let position = transform.map(|transform| transform.translation());
+
+        entities.push(PeriodicState {
+            static_id,
+            sequence: networking.delta.next_sequence(static_id),
+            state,
+            position,
+            speed: networking
+                .priority
+                .speed(static_id, position, time.delta_seconds()),
+        });
+    }
+
+    //entities that went out to at least one peer this frame
+    let mut sent_entities: HashSet<u16> = HashSet::new();
+    for player in networking.active_players.iter() {
+        let viewer = viewers
+            .iter()
+            .find(|(viewer, _)| viewer.peer == *player)
+            .map(|(_, transform)| transform.translation());
+
+        //peers that don't have a slave of the entity would ignore the update
+        let mut candidates: Vec<(f32, &PeriodicState)> = entities
+            .iter()
+            .filter(|entity| networking.relevancy.is_relevant(*player, entity.static_id))
+            .map(|entity| {
+                let distance = viewer
+                    .zip(entity.position)
+                    .map(|(viewer, position)| viewer.distance(position));
+                let priority = networking.priority.accumulate(
+                    &settings,
+                    *player,
+                    entity.static_id,
+                    distance,
+                    entity.speed,
+                );
+                (priority, entity)
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let mut sent = 0;
+        for (_, entity) in candidates {
+            //only the components that changed since the player's last ack are sent
+            let Some(bytes) = networking.delta.update_message(
+                *player,
+                entity.static_id,
+                entity.sequence,
+                timestamp,
+                &entity.state,
+            ) else {
+                networking.priority.reset(*player, entity.static_id);
+                continue;
+            };
+
+            //the first update always goes out so a single large entity can't stall forever,
+            //  the rest keep their priority and compete again next frame
+            if sent != 0 && sent + bytes.len() > settings.budget {
+                break;
             }
+            sent += bytes.len();
 
-            //sends all unreliable because it's ok if some packets are dropped
-            //because they are sent every frame anyways
-            networking.send_all_unreliable(bytes);
+            //sends unreliable because it's ok if some packets are dropped,
+            //  the next update is made against whatever was last acknowledged
+            networking
+                .transport
+                .send_packet(*player, Reliability::Unreliable, &bytes);
+            networking.priority.reset(*player, entity.static_id);
+            sent_entities.insert(entity.static_id);
+        }
+    }
+
+    //only states that were sent take up a sequence
+    for entity in entities {
+        if sent_entities.contains(&entity.static_id) {
+            networking
+                .delta
+                .record_sent(entity.static_id, entity.sequence, entity.state);
         }
     }
 }
 
 fn delete_marked_slaves(
-    networking: Res<NetworkingState>,
+    mut networking: ResMut<NetworkingState>,
     mut commands: Commands,
     query: Query<(Entity, &SynchronizedSlave)>,
 ) {
@@ -409,78 +968,149 @@ fn delete_marked_slaves(
 
     for entity in query.iter() {
         if (entity.1.object_info & 0b10000000) != 0 {
+            networking.delta.forget_entity(entity.1.static_id);
+            networking.authority.forget_entity(entity.1.static_id);
             commands.entity(entity.0).despawn_recursive();
         }
     }
 }
 
 fn delete_marked_masters(
-    networking: Res<NetworkingState>,
+    mut networking: ResMut<NetworkingState>,
     mut commands: Commands,
     query: Query<(Entity, &SynchronizedMaster)>,
+    children: Query<&Children>,
 ) {
     if !networking.connected {
         return;
     }
 
+    let mut deleted: Vec<u16> = Vec::new();
     for entity in query.iter() {
         if (entity.1.object_info & 0b10000000) != 0 {
+            //children are despawned along with the entity, so they are deleted on every peer too
+            for descendant in children.iter_descendants(entity.0) {
+                if let Ok((_, child)) = query.get(descendant) {
+                    deleted.push(child.static_id);
+                }
+            }
+            deleted.push(entity.1.static_id);
             commands.entity(entity.0).despawn_recursive();
         }
     }
+    //children that were marked themselves are found twice
+    let mut seen = HashSet::new();
+    deleted.retain(|static_id| seen.insert(*static_id));
+
+    for static_id in deleted {
+        //only peers the entity is relevant to have a slave of it
+        let peers: Vec<PeerId> = networking
+            .active_players
+            .iter()
+            .copied()
+            .filter(|peer| networking.relevancy.is_relevant(*peer, static_id))
+            .collect();
+        let bytes = entity_delete_message(static_id);
+        for peer in peers.iter() {
+            networking
+                .transport
+                .send_packet(*peer, Reliability::Reliable, &bytes);
+        }
+        networking.ids.release_after(static_id, peers);
+
+        networking.delta.forget_entity(static_id);
+        networking.relevancy.forget_entity(static_id);
+        networking.priority.forget_entity(static_id);
+    }
 }
 
 impl NetworkingState {
-    fn send_all_unreliable(&self, bytes: Vec<u8>) {
-        let networking = self.client.networking();
-
+    fn send_all_reliable(&self, bytes: Vec<u8>) {
         for player in self.active_players.iter() {
-            networking.send_p2p_packet(*player, SendType::Unreliable, &bytes);
+            self.transport
+                .send_packet(*player, Reliability::Reliable, &bytes);
         }
     }
 
-    fn send_all_reliable(&self, bytes: Vec<u8>) {
-        let networking = self.client.networking();
-
-        for player in self.active_players.iter() {
-            networking.send_p2p_packet(*player, SendType::Reliable, &bytes);
+    //gives this machine's next free static id, None while waiting for the session to assign an id range
+    pub fn allocate_static_id(&mut self) -> Option<u16> {
+        //nobody else can hand out a range to a machine that is alone
+        if self.active_players.is_empty() {
+            self.ids.claim_first_slot();
         }
+        self.ids.allocate()
     }
 
+    /**
+     * Makes the entity a master with a newly allocated static id and returns the id.
+     * The entity is sent to each peer once it becomes relevant to them.
+     * Masters keep their id when created again, even within the same frame,
+     * slaves belong to another machine and return None.
+     */
     pub fn create_networked_entity(
-        &self,
+        &mut self,
         commands: &mut Commands,
-        components: &[Box<impl Serializable>],
         entity: &Entity,
         sync_periodically: bool,
-        static_id: u16,
-    ) {
+        destroy_on_owner_disconnect: bool,
+    ) -> Option<u16> {
+        match self.networked.get(entity) {
+            Some((static_id, true)) => return Some(*static_id),
+            Some((static_id, false)) => {
+                println!(
+                    "Can't create entity {:?} again, it is the slave {}",
+                    entity, static_id
+                );
+                return None;
+            }
+            None => {}
+        }
+        let static_id = self.allocate_static_id()?;
+        self.networked.insert(*entity, (static_id, true));
+
         let mut object_info: u8 = 0;
         if sync_periodically {
             object_info |= 0b01000000;
         }
+        if destroy_on_owner_disconnect {
+            object_info |= 0b00100000;
+        }
 
         commands.entity(*entity).insert(SynchronizedMaster {
             object_info,
             static_id,
         });
 
-        let mut bytes: Vec<u8> = Vec::new();
+        Some(static_id)
+    }
+}
 
-        bytes.push(EntityCreate as u8);
-        bytes.extend_from_slice(&static_id.to_le_bytes());
-        bytes.push(object_info);
-        bytes.push(components.len().try_into().unwrap());
+//components are passed as (type id, serialized bytes) pairs
+fn entity_create_message(
+    static_id: u16,
+    object_info: u8,
+    components: &[(u16, Vec<u8>)],
+) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
 
-        for component in components {
-            let type_id = component.get_type_id().to_le_bytes();
-            let comp_bytes = component.to_bytes();
+    bytes.push(EntityCreate as u8);
+    bytes.extend_from_slice(&static_id.to_le_bytes());
+    bytes.push(object_info);
 
-            bytes.extend_from_slice(&type_id);
-            bytes.push(comp_bytes.len().try_into().unwrap());
-            bytes.extend_from_slice(&comp_bytes);
+    for (type_id, comp_bytes) in components {
+        if !write_component(&mut bytes, *type_id, comp_bytes) {
+            println!("Component with type id {} is too large to sync", type_id);
         }
-
-        self.send_all_reliable(bytes);
     }
+
+    bytes
+}
+
+fn entity_delete_message(static_id: u16) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+
+    bytes.push(EntityDelete as u8);
+    bytes.extend_from_slice(&static_id.to_le_bytes());
+
+    bytes
 }
@@ -0,0 +1,397 @@
+use bevy::prelude::*;
+
+use std::time::{Duration, Instant};
+
+use super::ids::NO_SLOT;
+use super::transport::{PeerId, Reliability};
+use super::*;
+
+//players that haven't sent anything for this long are treated as if they left,
+//  every player is pinged twice a second so only a lost connection stays this quiet
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+//sent when a peer refuses to let this machine join
+#[derive(Event)]
+pub struct ConnectionRejected {
+    pub peer: PeerId,
+    pub reason: RejectReason,
+}
+
+impl NetworkingState {
+    //starts a session on this machine that other players can join
+    pub fn host(&mut self) {
+        self.ids.claim_first_slot();
+        self.session_host = Some(self.player_id);
+        self.connected = true;
+    }
+
+    //connects to a peer that is already in a session, they reply with the rest of the session's players
+    pub fn join(&mut self, peer: PeerId) {
+        //the host of the session being joined is learned from the reply
+        if self.active_players.is_empty() {
+            self.session_host = None;
+        }
+        self.add_player(peer);
+        self.connected = true;
+
+        let bytes = self.player_join_message(peer);
+        self.transport
+            .send_packet(peer, Reliability::Reliable, &bytes);
+    }
+
+    //tells every player that this machine is leaving the session
+    pub fn leave(&mut self) {
+        let bytes = vec![PlayerLeave as u8];
+        self.send_all_reliable(bytes);
+
+        //the leave message went out with the first send, only its resends are dropped,
+        //  a player that misses it times this machine out instead
+        for player in std::mem::take(&mut self.active_players) {
+            self.delta.forget_peer(player);
+            self.authority.forget_peer(player);
+            self.ids.forget_peer(player);
+            self.transport.forget_peer(player);
+        }
+        self.handshakes.clear();
+        self.last_heard.clear();
+        self.session_host = None;
+        self.relevancy.clear();
+        self.priority.clear();
+        self.connected = false;
+    }
+
+    //true once the peer answered this machine's join or joined through it
+    pub fn has_joined(&self, peer: PeerId) -> bool {
+        self.handshakes.contains(&peer)
+    }
+
+    fn add_player(&mut self, peer: PeerId) {
+        //the relevancy system sends the new player the entities it needs
+        if !self.active_players.contains(&peer) {
+            self.active_players.push(peer);
+            self.last_heard.insert(peer, Instant::now());
+        }
+    }
+
+    //the message contains every other player in the session and their id slots
+    //  so that the receiver can connect to all of them, and the id this machine reaches it at
+    fn player_join_message(&self, receiver: PeerId) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+
+        bytes.push(PlayerJoin as u8);
+        bytes.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.registry_hash.to_le_bytes());
+        bytes.push(self.dedicated_server as u8);
+        bytes.extend_from_slice(&self.session_host.unwrap_or(self.player_id).0.to_le_bytes());
+        bytes.extend_from_slice(&receiver.0.to_le_bytes());
+        bytes.extend_from_slice(&self.ids.slot().unwrap_or(NO_SLOT).to_le_bytes());
+        bytes.extend_from_slice(&(self.active_players.len() as u16).to_le_bytes());
+        for player in self.active_players.iter() {
+            bytes.extend_from_slice(&player.0.to_le_bytes());
+            bytes.extend_from_slice(&self.ids.slot_of(*player).unwrap_or(NO_SLOT).to_le_bytes());
+        }
+
+        bytes
+    }
+
+    pub(super) fn handle_player_join(&mut self, sender: PeerId, data: &[u8]) {
+        let mut reader = Reader::new(data);
+        //skips the message type
+        reader.u8();
+
+        //peers running a different build would misread each other's packets
+        let (Some(version), Some(registry_hash)) = (reader.u16(), reader.u64()) else {
+            self.reject(sender, RejectReason::Malformed);
+            return;
+        };
+        if version != PROTOCOL_VERSION {
+            self.reject(sender, RejectReason::ProtocolMismatch);
+            return;
+        }
+        if registry_hash != self.registry_hash {
+            self.reject(sender, RejectReason::RegistryMismatch);
+            return;
+        }
+
+        let (Some(dedicated_server), Some(host), Some(observed), Some(sender_slot), Some(count)) = (
+            reader.u8(),
+            reader.u64(),
+            reader.u64(),
+            reader.u16(),
+            reader.u16(),
+        ) else {
+            self.reject(sender, RejectReason::Malformed);
+            return;
+        };
+        //before anything that compares ids with this machine's
+        self.observe_local_peer(PeerId(observed));
+
+        let mut peers: Vec<(PeerId, u16)> = Vec::new();
+        for _ in 0..count {
+            let (Some(peer), Some(slot)) = (reader.u64(), reader.u16()) else {
+                break;
+            };
+            peers.push((PeerId(peer), slot));
+        }
+
+        if !self.active_players.contains(&sender) {
+            //counts this machine as one of the players
+            if self.active_players.len() + 1 >= self.max_players as usize {
+                self.reject(sender, RejectReason::SessionFull);
+                return;
+            }
+
+            //the first player to accept someone hands out the id slots
+            if self.active_players.is_empty() {
+                self.ids.claim_first_slot();
+            }
+            if self.session_host.is_none() {
+                self.session_host = Some(self.player_id);
+            }
+            self.ids.claim_peer_slot(sender, sender_slot);
+
+            self.add_player(sender);
+            self.connected = true;
+
+            let bytes = self.player_join_message(sender);
+            self.transport
+                .send_packet(sender, Reliability::Reliable, &bytes);
+        } else {
+            self.ids.set_peer_slot(sender, sender_slot);
+            if self.session_host.is_none() {
+                self.session_host = Some(PeerId(host));
+            }
+        }
+        self.handshakes.insert(sender);
+        self.transport.accept_peer(sender);
+
+        if dedicated_server == 1 {
+            self.server = Some(sender);
+        }
+
+        //takes the slot the sender assigned before connecting to anyone, so the joins carry it
+        if let Some((_, slot)) = peers.iter().find(|(peer, _)| *peer == self.player_id) {
+            self.ids.set_slot(*slot);
+        }
+
+        //connects to every player the sender knows about that this machine doesn't
+        for (peer, slot) in peers {
+            if peer == self.player_id {
+                continue;
+            }
+
+            self.ids.set_peer_slot(peer, slot);
+            if !self.active_players.contains(&peer) {
+                self.join(peer);
+            }
+        }
+    }
+
+    //takes the id the sender reached this machine at if the transport doesn't know its own,
+    //  a udp socket bound to 0.0.0.0 would otherwise tell everyone an address they can't reach
+    fn observe_local_peer(&mut self, observed: PeerId) {
+        let Some(player_id) = self.transport.observe_local_peer(observed) else {
+            return;
+        };
+        println!("Other players reach this machine as {:?}", player_id);
+
+        if self.session_host == Some(self.player_id) {
+            self.session_host = Some(player_id);
+        }
+        self.player_id = player_id;
+    }
+
+    fn reject(&mut self, peer: PeerId, reason: RejectReason) {
+        println!("Rejected player {:?}, {}", peer, reason);
+        self.transport
+            .send_packet(peer, Reliability::Reliable, &[Reject as u8, reason as u8]);
+
+        //forgets the peer in case this machine was the one trying to join
+        self.active_players.retain(|player| *player != peer);
+        self.handshakes.remove(&peer);
+        self.relevancy.forget_peer(peer);
+        self.priority.forget_peer(peer);
+    }
+
+    //removes a peer from the session as if it had left, it is told why with a rejection
+    pub fn kick(&mut self, peer: PeerId) {
+        println!("Kicked player {:?}", peer);
+        self.transport.send_packet(
+            peer,
+            Reliability::Reliable,
+            &[Reject as u8, RejectReason::Kicked as u8],
+        );
+        self.handle_player_leave(peer);
+    }
+
+    pub(super) fn handle_reject(&mut self, sender: PeerId, data: &[u8]) {
+        let reason = data
+            .get(1)
+            .map_or(RejectReason::Unknown, |code| RejectReason::from(*code));
+        println!("Rejected by {:?}, {}", sender, reason);
+
+        self.active_players.retain(|player| *player != sender);
+        self.handshakes.remove(&sender);
+        self.relevancy.forget_peer(sender);
+        self.priority.forget_peer(sender);
+        //a later join starts the channels from scratch instead of resending the rejected join
+        self.transport.forget_peer(sender);
+        if self.active_players.is_empty() && !self.dedicated_server {
+            self.connected = false;
+            self.session_host = None;
+        }
+        self.rejections.push((sender, reason));
+    }
+
+    pub(super) fn handle_player_leave(&mut self, sender: PeerId) {
+        self.active_players.retain(|player| *player != sender);
+        self.handshakes.remove(&sender);
+        self.last_heard.remove(&sender);
+        if self.server == Some(sender) {
+            self.server = None;
+        }
+        if self.session_host == Some(sender) {
+            let host = self.elect_host();
+            println!("Host {:?} left, {:?} takes over the session", sender, host);
+            self.session_host = Some(host);
+            self.host_changes.push((sender, host));
+        }
+        self.delta.forget_peer(sender);
+        self.relevancy.forget_peer(sender);
+        self.priority.forget_peer(sender);
+        self.authority.forget_peer(sender);
+        self.ids.forget_peer(sender);
+        self.transport.forget_peer(sender);
+        self.departed_players.push(sender);
+    }
+
+    //inherits the entities of players that leave and is the reference of the shared clock
+    pub(super) fn heir(&self) -> PeerId {
+        self.session_host.unwrap_or_else(|| self.elect_host())
+    }
+
+    //the machine that inherits this one's entities if it leaves
+    pub(super) fn successor(&self) -> Option<PeerId> {
+        let heir = self.heir();
+        if heir != self.player_id {
+            return Some(heir);
+        }
+
+        match self.server {
+            Some(server) if self.active_players.contains(&server) => Some(server),
+            _ => self.active_players.iter().copied().min(),
+        }
+    }
+
+    //every machine elects the same host because they all share the same list of players,
+    //  a dedicated server always wins so it stays authoritative
+    fn elect_host(&self) -> PeerId {
+        if self.dedicated_server {
+            return self.player_id;
+        }
+        if let Some(server) = self.server {
+            if self.active_players.contains(&server) {
+                return server;
+            }
+        }
+
+        self.active_players
+            .iter()
+            .copied()
+            .chain(std::iter::once(self.player_id))
+            .min()
+            .unwrap()
+    }
+}
+
+//destroys or hands off the entities owned by players that left
+pub(super) fn handle_departed_players(
+    mut networking: ResMut<NetworkingState>,
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut SynchronizedSlave)>,
+) {
+    if networking.departed_players.is_empty() {
+        return;
+    }
+    let departed: Vec<PeerId> = networking.departed_players.drain(..).collect();
+    let heir = networking.heir();
+    let mut inherited: Vec<u16> = Vec::new();
+
+    for (entity, mut slave) in query.iter_mut() {
+        if !departed.contains(&slave.owner) {
+            continue;
+        }
+
+        if (slave.object_info & 0b00100000) != 0 {
+            //marks the entity for deletion
+            slave.object_info |= 0b10000000;
+            continue;
+        }
+
+        //the new owner numbers its updates from the start again
+        networking.delta.forget_entity(slave.static_id);
+        if heir == networking.player_id {
+            networking.authority.forget_entity(slave.static_id);
+            commands
+                .entity(entity)
+                .remove::<SynchronizedSlave>()
+                .insert(SynchronizedMaster {
+                    object_info: slave.object_info,
+                    static_id: slave.static_id,
+                });
+        } else {
+            slave.owner = heir;
+            networking.authority.set_owner(slave.static_id, heir);
+            inherited.push(slave.static_id);
+        }
+    }
+
+    //tells the heir which of its new entities this machine already has, so they aren't created again
+    if heir != networking.player_id && networking.active_players.contains(&heir) {
+        let bytes = migration::resume_message(&inherited);
+        networking
+            .transport
+            .send_packet(heir, Reliability::Reliable, &bytes);
+    }
+}
+
+//treats players that stopped sending anything as if they had left
+pub(super) fn drop_silent_players(mut networking: ResMut<NetworkingState>) {
+    let silent: Vec<PeerId> = networking
+        .active_players
+        .iter()
+        .copied()
+        .filter(|peer| {
+            networking
+                .last_heard
+                .get(peer)
+                .is_some_and(|heard| heard.elapsed() >= PEER_TIMEOUT)
+        })
+        .collect();
+
+    for peer in silent {
+        println!("Lost connection to player {:?}", peer);
+        networking.handle_player_leave(peer);
+    }
+    for peer in networking.transport.lost_peers() {
+        if networking.active_players.contains(&peer) {
+            networking.handle_player_leave(peer);
+        }
+    }
+
+    //joins that were never accepted don't need to be remembered
+    let networking = &mut *networking;
+    let active_players = &networking.active_players;
+    networking
+        .last_heard
+        .retain(|peer, _| active_players.contains(peer));
+}
+
+pub(super) fn emit_rejections(
+    mut networking: ResMut<NetworkingState>,
+    mut writer: EventWriter<ConnectionRejected>,
+) {
+    for (peer, reason) in networking.rejections.drain(..) {
+        writer.send(ConnectionRejected { peer, reason });
+    }
+}
@@ -0,0 +1,86 @@
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+use super::transport::PeerId;
+
+#[derive(Resource, Clone, Copy)]
+pub struct PrioritySettings {
+    //bytes of entity updates sent to each peer per frame, entities that don't fit wait for a later frame
+    pub budget: usize,
+    //priority gained per frame by an entity right next to the peer's viewer, falls off with distance
+    pub distance_weight: f32,
+    //priority gained per frame for every unit per second the entity moves
+    pub speed_weight: f32,
+}
+
+impl Default for PrioritySettings {
+    fn default() -> Self {
+        Self {
+            budget: 4096,
+            distance_weight: 10.0,
+            speed_weight: 0.1,
+        }
+    }
+}
+
+/**
+ * Accumulates priority for every entity a peer hasn't received an update of yet,
+ * so entities that keep missing the budget are eventually sent.
+ */
+#[derive(Default)]
+pub(super) struct PriorityState {
+    accumulated: HashMap<(PeerId, u16), f32>,
+    //position of each master entity last frame, to estimate its speed
+    positions: HashMap<u16, Vec3>,
+}
+
+impl PriorityState {
+    //returns how fast the entity moved since the last call, in units per second
+    pub fn speed(&mut self, static_id: u16, position: Option<Vec3>, delta_seconds: f32) -> f32 {
+        let Some(position) = position else {
+            return 0.0;
+        };
+
+        let previous = self.positions.insert(static_id, position);
+        match previous {
+            Some(previous) if delta_seconds > 0.0 => previous.distance(position) / delta_seconds,
+            _ => 0.0,
+        }
+    }
+
+    //adds this frame's priority and returns the total
+    pub fn accumulate(
+        &mut self,
+        settings: &PrioritySettings,
+        peer: PeerId,
+        static_id: u16,
+        distance: Option<f32>,
+        speed: f32,
+    ) -> f32 {
+        //entities without a distance are treated as being next to the viewer
+        let gain = 1.0
+            + settings.distance_weight / (1.0 + distance.unwrap_or(0.0))
+            + settings.speed_weight * speed;
+
+        let priority = self.accumulated.entry((peer, static_id)).or_default();
+        *priority += gain;
+        *priority
+    }
+
+    pub fn reset(&mut self, peer: PeerId, static_id: u16) {
+        self.accumulated.remove(&(peer, static_id));
+    }
+
+    pub fn forget_peer(&mut self, peer: PeerId) {
+        self.accumulated.retain(|(p, _), _| *p != peer);
+    }
+
+    pub fn forget_entity(&mut self, static_id: u16) {
+        self.accumulated.retain(|(_, id), _| *id != static_id);
+        self.positions.remove(&static_id);
+    }
+
+    pub fn clear(&mut self) {
+        self.accumulated.clear();
+    }
+}
@@ -0,0 +1,95 @@
+/*!
+ * Message types and constants of the wire protocol. Kept free of bevy and the game's types
+ * so the capture inspector in src/bin can share it.
+ */
+use std::fmt;
+
+//bump whenever the wire format changes so that older builds are rejected when joining
+pub const PROTOCOL_VERSION: u16 = 8;
+
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EventType {
+    EntityCreate,
+    EntityDelete,
+    EntityUpdate,
+    PlayerJoin,
+    PlayerLeave,
+    Event,
+    EntityAck,
+    Input,
+    Reject,
+    Ping,
+    Pong,
+    EntityDeleteAck,
+    Resume,
+    Handoff,
+}
+
+//fails with the original value for message types this build doesn't know about
+impl TryFrom<u8> for EventType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        Ok(match value {
+            0 => EventType::EntityCreate,
+            1 => EventType::EntityDelete,
+            2 => EventType::EntityUpdate,
+            3 => EventType::PlayerJoin,
+            4 => EventType::PlayerLeave,
+            5 => EventType::Event,
+            6 => EventType::EntityAck,
+            7 => EventType::Input,
+            8 => EventType::Reject,
+            9 => EventType::Ping,
+            10 => EventType::Pong,
+            11 => EventType::EntityDeleteAck,
+            12 => EventType::Resume,
+            13 => EventType::Handoff,
+            _ => return Err(value),
+        })
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RejectReason {
+    SessionFull,
+    ProtocolMismatch,
+    RegistryMismatch,
+    Malformed,
+    Kicked,
+    //sent by a newer build with a reason this one doesn't know
+    Unknown,
+}
+
+impl From<u8> for RejectReason {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => RejectReason::SessionFull,
+            1 => RejectReason::ProtocolMismatch,
+            2 => RejectReason::RegistryMismatch,
+            3 => RejectReason::Malformed,
+            4 => RejectReason::Kicked,
+            _ => RejectReason::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            RejectReason::SessionFull => "the session is full",
+            RejectReason::ProtocolMismatch => {
+                "the peer uses a different protocol version, make sure both run the same build"
+            }
+            RejectReason::RegistryMismatch => {
+                "the peer has different networked types, make sure both run the same build"
+            }
+            RejectReason::Malformed => "the join request was malformed",
+            RejectReason::Kicked => "the peer removed this machine from the session",
+            RejectReason::Unknown => "the peer gave a reason this build doesn't understand",
+        };
+        write!(f, "{}", message)
+    }
+}
@@ -0,0 +1,175 @@
+use bevy::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+use super::hierarchy::{root_of, NetworkParent};
+use super::transport::{PeerId, Reliability};
+use super::*;
+
+#[derive(Resource, Clone, Copy)]
+pub struct RelevancySettings {
+    //entities closer than this to a peer's viewer are sent to that peer
+    pub radius: f32,
+    //relevant entities stay relevant until they are this much further away,
+    //  so ones on the edge aren't created and deleted every frame
+    pub hysteresis: f32,
+}
+
+impl Default for RelevancySettings {
+    fn default() -> Self {
+        Self {
+            radius: 100.0,
+            hysteresis: 10.0,
+        }
+    }
+}
+
+//master entities with this are sent to every peer regardless of distance
+#[derive(Component)]
+pub struct AlwaysRelevant;
+
+//the entity a peer sees the world from, relevancy for that peer is measured from it
+#[derive(Component)]
+pub struct Viewer {
+    pub peer: PeerId,
+}
+
+/**
+ * Tracks which master entities each peer currently has a slave of.
+ * Peers without a viewer and entities without a transform are always relevant,
+ * and so is every entity that outlives its owner to the machine that would inherit it.
+ */
+#[derive(Default)]
+pub(super) struct RelevancyState {
+    relevant: HashMap<PeerId, HashSet<u16>>,
+}
+
+impl RelevancyState {
+    pub fn is_relevant(&self, peer: PeerId, static_id: u16) -> bool {
+        self.relevant
+            .get(&peer)
+            .is_some_and(|relevant| relevant.contains(&static_id))
+    }
+
+    //for entities the peer got from someone else, e.g. the previous owner
+    pub fn mark_relevant(&mut self, peer: PeerId, static_id: u16) {
+        self.relevant.entry(peer).or_default().insert(static_id);
+    }
+
+    pub fn forget_peer(&mut self, peer: PeerId) {
+        self.relevant.remove(&peer);
+    }
+
+    pub fn forget_entity(&mut self, static_id: u16) {
+        for relevant in self.relevant.values_mut() {
+            relevant.remove(&static_id);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.relevant.clear();
+    }
+}
+
+//every master with what its relevancy depends on
+type RelevancyMasters<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static dyn Serializable,
+        &'static SynchronizedMaster,
+        Option<&'static GlobalTransform>,
+        Has<AlwaysRelevant>,
+        Option<&'static NetworkParent>,
+    ),
+>;
+
+/**
+ * Sends a create message when a master entity becomes relevant to a peer and a delete message
+ * when it stops being relevant. Children follow the relevancy of their root so whole subtrees
+ * are created together, parents before their children.
+ */
+pub(super) fn update_relevancy(
+    settings: Res<RelevancySettings>,
+    mut networking: ResMut<NetworkingState>,
+    viewers: Query<(&Viewer, &GlobalTransform)>,
+    masters: RelevancyMasters,
+) {
+    if !networking.connected {
+        return;
+    }
+    //borrows the fields separately
+    let networking = &mut *networking;
+
+    let parents: HashMap<u16, u16> = masters
+        .iter()
+        .filter_map(|(_, master, _, _, parent)| Some((master.static_id, parent?.0?)))
+        .collect();
+
+    //entities being destroyed are deleted by delete_marked_masters
+    let mut entities: Vec<_> = masters
+        .iter()
+        .filter(|(_, master, _, _, _)| (master.object_info & 0b10000000) == 0)
+        .map(|entity| (root_of(&parents, entity.1.static_id), entity))
+        .collect();
+    entities.sort_by_key(|((_, depth), _)| *depth);
+
+    let successor = networking.successor();
+    for peer in networking.active_players.iter() {
+        let viewer = viewers
+            .iter()
+            .find(|(viewer, _)| viewer.peer == *peer)
+            .map(|(_, transform)| transform.translation());
+        let relevant = networking.relevancy.relevant.entry(*peer).or_default();
+
+        let mut decisions: HashMap<u16, bool> = HashMap::new();
+        for (_, (_, master, transform, always_relevant, _)) in entities.iter() {
+            let static_id = master.static_id;
+            let was_relevant = relevant.contains(&static_id);
+            let is_relevant = match (viewer, transform) {
+                (Some(viewer), Some(transform)) if !always_relevant => {
+                    let mut radius = settings.radius;
+                    if was_relevant {
+                        radius += settings.hysteresis;
+                    }
+                    viewer.distance_squared(transform.translation()) <= radius * radius
+                }
+                _ => true,
+            };
+            decisions.insert(static_id, is_relevant);
+        }
+
+        for ((root, _), (components, master, _, _, _)) in entities.iter() {
+            let static_id = master.static_id;
+            let was_relevant = relevant.contains(&static_id);
+            //the root decides unless it isn't a master on this machine
+            let is_relevant = decisions
+                .get(root)
+                .or_else(|| decisions.get(&static_id))
+                .copied()
+                .unwrap_or(true)
+                || (Some(*peer) == successor && (master.object_info & 0b00100000) == 0);
+
+            if is_relevant && !was_relevant {
+                let components: Vec<(u16, Vec<u8>)> = components
+                    .into_iter()
+                    .map(|component| (component.get_type_id(), component.to_bytes()))
+                    .collect();
+                let bytes = entity_create_message(static_id, master.object_info, &components);
+
+                networking
+                    .transport
+                    .send_packet(*peer, Reliability::Reliable, &bytes);
+                relevant.insert(static_id);
+            } else if !is_relevant && was_relevant {
+                networking.transport.send_packet(
+                    *peer,
+                    Reliability::Reliable,
+                    &entity_delete_message(static_id),
+                );
+                //the peer loses its baseline along with the slave, so the next create starts over
+                networking.delta.forget_peer_entity(*peer, static_id);
+                relevant.remove(&static_id);
+            }
+        }
+    }
+}
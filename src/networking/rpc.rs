@@ -0,0 +1,154 @@
+use bevy::prelude::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use super::ids::EntityIndex;
+use super::transport::{PeerId, Reliability};
+use super::type_registry::hash_name;
+use super::{
+    handle_networking, NetworkingEvent, NetworkingState, SynchronizedMaster, SynchronizedSlave,
+};
+
+/**
+ * A remote procedure call is a serializable Bevy event that can be sent to other machines.
+ * Give it a unique NAME, register it with app.add_rpc::<T>(reliability), send it by
+ * writing a SendRpc<T>, and read it on the receiving machine with a regular EventReader<T>.
+ */
+pub trait Rpc: Event + Serialize + DeserializeOwned + Clone {
+    //identifies the rpc on the wire like the name of a registered component, usually the type name
+    const NAME: &'static str;
+    const ID: u16 = hash_name(Self::NAME);
+
+    //called before delivery so rpcs that care can record who sent them
+    fn set_sender(&mut self, _sender: PeerId) {}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RpcTarget {
+    Peer(PeerId),
+    //the machine that owns the entity with this static id
+    OwnerOf(u16),
+    //every player including this machine
+    Broadcast,
+}
+
+#[derive(Event)]
+pub struct SendRpc<T: Rpc> {
+    pub target: RpcTarget,
+    pub rpc: T,
+}
+
+#[derive(Resource)]
+struct RpcConfig<T: Rpc> {
+    reliability: Reliability,
+    _marker: PhantomData<T>,
+}
+
+//names of every registered rpc by id, used to catch collisions and drop rpcs nobody reads
+#[derive(Resource, Default)]
+pub struct RpcRegistry {
+    names: HashMap<u16, &'static str>,
+}
+
+impl RpcRegistry {
+    pub fn rpc_name(&self, id: u16) -> Option<&'static str> {
+        self.names.get(&id).copied()
+    }
+}
+
+pub trait AddRpc {
+    fn add_rpc<T: Rpc>(&mut self, reliability: Reliability) -> &mut Self;
+}
+
+impl AddRpc for App {
+    fn add_rpc<T: Rpc>(&mut self, reliability: Reliability) -> &mut Self {
+        let name = T::NAME;
+        let id = T::ID;
+
+        self.init_resource::<RpcRegistry>();
+        let mut registry = self.world.resource_mut::<RpcRegistry>();
+        if let Some(existing) = registry.names.get(&id) {
+            panic!("Rpcs {} and {} share the id {}", existing, name, id);
+        }
+        registry.names.insert(id, name);
+
+        self.add_event::<T>()
+            .add_event::<SendRpc<T>>()
+            .insert_resource(RpcConfig::<T> {
+                reliability,
+                _marker: PhantomData,
+            })
+            .add_systems(
+                Update,
+                (send_rpcs::<T>, receive_rpcs::<T>.after(handle_networking)),
+            )
+    }
+}
+
+fn send_rpcs<T: Rpc>(
+    networking: Option<Res<NetworkingState>>,
+    config: Res<RpcConfig<T>>,
+    mut outgoing: EventReader<SendRpc<T>>,
+    mut local: EventWriter<T>,
+    index: Option<Res<EntityIndex>>,
+    masters: Query<&SynchronizedMaster>,
+    slaves: Query<&SynchronizedSlave>,
+) {
+    for SendRpc { target, rpc } in outgoing.read() {
+        //without networking every rpc is handled locally
+        let Some(networking) = networking.as_ref() else {
+            local.send(rpc.clone());
+            continue;
+        };
+
+        let peer = match *target {
+            RpcTarget::Peer(peer) => peer,
+            RpcTarget::OwnerOf(static_id) => {
+                let entity = index.as_ref().and_then(|index| index.get(static_id));
+                if let Some(slave) = entity.and_then(|entity| slaves.get(entity).ok()) {
+                    slave.owner
+                } else if entity.is_some_and(|entity| masters.contains(entity)) {
+                    networking.player_id
+                } else {
+                    println!("Dropped rpc for unknown entity {}", static_id);
+                    continue;
+                }
+            }
+            RpcTarget::Broadcast => {
+                let event = NetworkingEvent::new(T::ID, bincode::serialize(rpc).unwrap());
+                networking.queue_event_all(config.reliability, event);
+
+                let mut rpc = rpc.clone();
+                rpc.set_sender(networking.player_id);
+                local.send(rpc);
+                continue;
+            }
+        };
+
+        if peer == networking.player_id {
+            let mut rpc = rpc.clone();
+            rpc.set_sender(peer);
+            local.send(rpc);
+        } else {
+            let event = NetworkingEvent::new(T::ID, bincode::serialize(rpc).unwrap());
+            networking.queue_event_to(peer, config.reliability, event);
+        }
+    }
+}
+
+fn receive_rpcs<T: Rpc>(networking: Option<Res<NetworkingState>>, mut writer: EventWriter<T>) {
+    let Some(networking) = networking else {
+        return;
+    };
+
+    for (sender, event) in networking.get_event_in(T::ID) {
+        match bincode::deserialize::<T>(&event.data) {
+            Ok(mut rpc) => {
+                rpc.set_sender(sender);
+                writer.send(rpc);
+            }
+            Err(_) => println!("Rejected malformed rpc {} from {:?}", T::NAME, sender),
+        }
+    }
+}
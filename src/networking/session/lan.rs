@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use super::{BackendEvent, LobbyAddress, LobbyInfo, SessionBackend};
+use crate::networking::transport::peer_from_address;
+use crate::networking::wire::Reader;
+use crate::networking::PROTOCOL_VERSION;
+
+//hosts broadcast their lobby on this port, separate from the game port so several hosts can share a machine
+pub const DISCOVERY_PORT: u16 = 7778;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+//lobbies that haven't been announced for this long are dropped from the list
+const LOBBY_TIMEOUT: Duration = Duration::from_secs(3);
+const MAGIC: &[u8; 4] = b"SCLD";
+
+struct HostedLobby {
+    name: String,
+    max_players: u16,
+    players: u16,
+    last_announce: Option<Instant>,
+    //the address the lobby was last reported as hosted at
+    address: SocketAddr,
+}
+
+/**
+ * Finds sessions on the local network. Hosts broadcast an announcement every second,
+ * players looking for lobbies listen for them. Joining connects straight to the
+ * address in the announcement, there is no lobby to enter.
+ */
+pub struct LanDiscovery {
+    game_port: u16,
+    announcer: Option<UdpSocket>,
+    //bound on the first refresh or host, None until then or if another instance on this machine already listens
+    listener: Option<UdpSocket>,
+    hosted: Option<HostedLobby>,
+    lobbies: HashMap<SocketAddr, (LobbyInfo, Instant)>,
+    events: Vec<BackendEvent>,
+}
+
+impl LanDiscovery {
+    pub fn new(game_port: u16) -> Self {
+        let announcer = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .and_then(|socket| socket.set_broadcast(true).map(|_| socket));
+        if let Err(error) = &announcer {
+            println!("Can't announce lobbies on the local network, {}", error);
+        }
+
+        Self {
+            game_port,
+            announcer: announcer.ok(),
+            listener: None,
+            hosted: None,
+            lobbies: HashMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    fn announce(&mut self) {
+        let (Some(announcer), Some(hosted)) = (self.announcer.as_ref(), self.hosted.as_mut())
+        else {
+            return;
+        };
+        if hosted
+            .last_announce
+            .is_some_and(|last| last.elapsed() < ANNOUNCE_INTERVAL)
+        {
+            return;
+        }
+        hosted.last_announce = Some(Instant::now());
+
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.game_port.to_le_bytes());
+        bytes.extend_from_slice(&hosted.players.to_le_bytes());
+        bytes.extend_from_slice(&hosted.max_players.to_le_bytes());
+        bytes.extend_from_slice(hosted.name.as_bytes());
+
+        let _ = announcer.send_to(&bytes, (Ipv4Addr::BROADCAST, DISCOVERY_PORT));
+    }
+
+    fn bind_listener(&mut self) {
+        if self.listener.is_some() {
+            return;
+        }
+
+        let listener = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))
+            .and_then(|socket| socket.set_nonblocking(true).map(|_| socket));
+        match listener {
+            Ok(listener) => self.listener = Some(listener),
+            Err(error) => println!("Can't look for lobbies on the local network, {}", error),
+        }
+    }
+
+    //returns true if the list of lobbies changed
+    fn listen(&mut self) -> bool {
+        let Some(listener) = self.listener.as_ref() else {
+            return false;
+        };
+        let announcer_port = self
+            .announcer
+            .as_ref()
+            .and_then(|announcer| announcer.local_addr().ok())
+            .map(|address| address.port());
+
+        let mut changed = false;
+        let mut buffer = [0; 512];
+        while let Ok((len, sender)) = listener.recv_from(&mut buffer) {
+            let Some((address, info)) = parse_announcement(&buffer[..len], sender) else {
+                continue;
+            };
+
+            //broadcasts come back to the machine that sent them, from the address the network sees it at
+            if Some(sender.port()) == announcer_port && address.port() == self.game_port {
+                if let Some(hosted) = self.hosted.as_mut() {
+                    if hosted.address != address && !address.ip().is_loopback() {
+                        hosted.address = address;
+                        self.events
+                            .push(BackendEvent::Hosted(LobbyAddress::Lan(address)));
+                    }
+                }
+                continue;
+            }
+
+            let previous = self.lobbies.insert(address, (info.clone(), Instant::now()));
+            changed |= previous.is_none_or(|(previous, _)| {
+                previous.players != info.players || previous.name != info.name
+            });
+        }
+
+        let count = self.lobbies.len();
+        self.lobbies
+            .retain(|_, (_, heard)| heard.elapsed() < LOBBY_TIMEOUT);
+        changed || count != self.lobbies.len()
+    }
+}
+
+//None for packets that aren't announcements of a compatible build
+fn parse_announcement(bytes: &[u8], sender: SocketAddr) -> Option<(SocketAddr, LobbyInfo)> {
+    if bytes.get(..MAGIC.len())? != MAGIC {
+        return None;
+    }
+
+    let mut reader = Reader::new(&bytes[MAGIC.len()..]);
+    if reader.u16()? != PROTOCOL_VERSION {
+        return None;
+    }
+    let address = SocketAddr::new(sender.ip(), reader.u16()?);
+    let players = reader.u16()?;
+    let max_players = reader.u16()?;
+    let name = String::from_utf8_lossy(reader.remaining()).to_string();
+
+    Some((
+        address,
+        LobbyInfo {
+            address: LobbyAddress::Lan(address),
+            name,
+            players,
+            max_players,
+        },
+    ))
+}
+
+//the interface the system would send to the destination from, connecting a udp socket sends nothing
+fn route_to(destination: Ipv4Addr) -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.set_broadcast(true).ok()?;
+    socket.connect((destination, 80)).ok()?;
+
+    match socket.local_addr().ok()?.ip() {
+        std::net::IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    }
+}
+
+//a first guess at the address other machines on the network reach this one at, networks without
+//  internet access have no route to a public address but still one to the broadcast address,
+//  listen corrects it once the host hears its own announcement
+fn local_address(port: u16) -> SocketAddr {
+    let ip = route_to(Ipv4Addr::new(8, 8, 8, 8))
+        .or_else(|| route_to(Ipv4Addr::BROADCAST))
+        .unwrap_or(Ipv4Addr::LOCALHOST);
+
+    SocketAddr::new(ip.into(), port)
+}
+
+impl SessionBackend for LanDiscovery {
+    fn host(&mut self, name: &str, max_players: u16) {
+        let address = local_address(self.game_port);
+        self.hosted = Some(HostedLobby {
+            name: name.to_string(),
+            max_players,
+            players: 1,
+            last_announce: None,
+            address,
+        });
+        self.events
+            .push(BackendEvent::Hosted(LobbyAddress::Lan(address)));
+
+        //hears its own announcements to learn the address the network sees it at
+        self.bind_listener();
+    }
+
+    fn join(&mut self, lobby: LobbyAddress) {
+        let event = match lobby {
+            LobbyAddress::Lan(address) => match peer_from_address(address) {
+                Ok(host) => BackendEvent::Joined { lobby, host },
+                Err(error) => BackendEvent::JoinFailed(error.to_string()),
+            },
+            LobbyAddress::Steam(_) => {
+                BackendEvent::JoinFailed("steam lobbies can't be joined over udp".to_string())
+            }
+        };
+        self.events.push(event);
+    }
+
+    fn refresh(&mut self) {
+        self.bind_listener();
+
+        //announcements arrive over the next second, lobbies already heard are reported right away
+        let lobbies = self
+            .lobbies
+            .values()
+            .map(|(info, _)| info.clone())
+            .collect();
+        self.events.push(BackendEvent::Lobbies(lobbies));
+    }
+
+    fn leave(&mut self) {
+        self.hosted = None;
+    }
+
+    fn update(&mut self, players: u16) -> Vec<BackendEvent> {
+        if let Some(hosted) = self.hosted.as_mut() {
+            hosted.players = players;
+        }
+        self.announce();
+
+        if self.listen() {
+            let lobbies = self
+                .lobbies
+                .values()
+                .map(|(info, _)| info.clone())
+                .collect();
+            self.events.push(BackendEvent::Lobbies(lobbies));
+        }
+
+        self.events.drain(..).collect()
+    }
+}
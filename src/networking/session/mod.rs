@@ -0,0 +1,604 @@
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+mod lan;
+mod steam;
+
+pub use lan::*;
+pub use steam::*;
+
+use super::migration::HostMigrated;
+use super::players::ConnectionRejected;
+use super::rpc::{Rpc, RpcTarget, SendRpc};
+use super::transport::{address_from_peer, peer_from_address, PeerId, TransportKind};
+use super::NetworkingState;
+use crate::utils::ConsoleCommand;
+
+//crockford base32, leaves out letters that are easy to mistake for digits
+const INVITE_ALPHABET: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+//where a session can be joined
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum LobbyAddress {
+    Steam(u64),
+    Lan(SocketAddr),
+}
+
+#[derive(Clone, Debug)]
+pub struct LobbyInfo {
+    pub address: LobbyAddress,
+    pub name: String,
+    pub players: u16,
+    pub max_players: u16,
+}
+
+impl LobbyInfo {
+    pub fn is_full(&self) -> bool {
+        self.players >= self.max_players
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SessionStatus {
+    #[default]
+    Idle,
+    //waiting for the backend to create the lobby
+    Creating,
+    Hosting,
+    //waiting for the host to accept the join
+    Joining,
+    Joined,
+}
+
+//write these to host, join or leave a session
+#[derive(Event, Clone, Debug)]
+pub enum SessionCommand {
+    Host { name: String },
+    Join(LobbyAddress),
+    JoinInvite(String),
+    //looks for lobbies, answered with SessionEvent::LobbiesFound
+    RefreshLobbies,
+    SetReady(bool),
+    //only the host of the session can kick
+    Kick(PeerId),
+    Leave,
+}
+
+#[derive(Event, Clone, Debug)]
+pub enum SessionEvent {
+    //the invite code is None if the transport has no session backend
+    Hosted { invite_code: Option<String> },
+    HostFailed(String),
+    Joined,
+    JoinFailed(String),
+    LobbiesFound(Vec<LobbyInfo>),
+    PlayerJoined(PeerId),
+    PlayerLeft(PeerId),
+    //the host left and another player took over, the session goes on
+    HostChanged { previous: PeerId, host: PeerId },
+    ReadyChanged { peer: PeerId, ready: bool },
+    Left,
+}
+
+//what a backend reports back from its update
+pub enum BackendEvent {
+    Hosted(LobbyAddress),
+    HostFailed(String),
+    //the lobby was joined, the session is joined through its host
+    Joined { lobby: LobbyAddress, host: PeerId },
+    JoinFailed(String),
+    Lobbies(Vec<LobbyInfo>),
+    //the player accepted an invite outside the game, e.g. through the steam overlay
+    InviteAccepted(LobbyAddress),
+}
+
+/**
+ * Publishes and finds lobbies. The lobby only tells players where the session is,
+ * joining the session itself goes through NetworkingState::join like any other peer.
+ */
+pub trait SessionBackend: Send + Sync {
+    fn host(&mut self, name: &str, max_players: u16);
+    fn join(&mut self, lobby: LobbyAddress);
+    fn refresh(&mut self);
+    fn leave(&mut self);
+    //called when this machine takes over as host after the previous one left
+    fn migrate(&mut self, name: &str, max_players: u16) {
+        self.host(name, max_players);
+    }
+    //called on every machine with the host the session elected after the previous one left
+    fn follow_host(&mut self, _host: PeerId) {}
+    //called every frame with the number of players in the session
+    fn update(&mut self, players: u16) -> Vec<BackendEvent>;
+}
+
+//None for transports without a way to find sessions, e.g. loopback
+#[derive(Resource)]
+pub struct SessionBackendResource(pub Option<Box<dyn SessionBackend>>);
+
+impl SessionBackendResource {
+    pub fn for_transport(transport: &TransportKind) -> Self {
+        if let Some(app_id) = transport.steam_app_id() {
+            return Self(Some(Box::new(SteamLobbies::new(app_id))));
+        }
+        if let Some(port) = transport.udp_port() {
+            return Self(Some(Box::new(LanDiscovery::new(port))));
+        }
+
+        Self(None)
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct Session {
+    pub status: SessionStatus,
+    pub lobby: Option<LobbyAddress>,
+    pub invite_code: Option<String>,
+    pub ready: HashMap<PeerId, bool>,
+    //results of the last SessionCommand::RefreshLobbies
+    pub lobbies: Vec<LobbyInfo>,
+    //published with the lobby again if this machine becomes the host
+    name: String,
+    //the player this machine joined the session through
+    host: Option<PeerId>,
+    //players that had joined as of the last frame, used to notice arrivals and departures
+    players: Vec<PeerId>,
+}
+
+impl Session {
+    pub fn is_ready(&self, peer: PeerId) -> bool {
+        self.ready.get(&peer).copied().unwrap_or(false)
+    }
+
+    //true once every player in the session is ready, dedicated servers don't count as players
+    pub fn all_ready(&self, networking: &NetworkingState) -> bool {
+        let local = (!networking.dedicated_server).then_some(networking.player_id);
+        networking
+            .active_players
+            .iter()
+            .filter(|peer| networking.server != Some(**peer))
+            .chain(local.iter())
+            .all(|peer| self.is_ready(*peer))
+    }
+
+    fn reset(&mut self) {
+        self.status = SessionStatus::Idle;
+        self.lobby = None;
+        self.invite_code = None;
+        self.name.clear();
+        self.ready.clear();
+        self.host = None;
+        self.players.clear();
+    }
+}
+
+//tells the other players whether this machine is ready
+#[derive(Event, Clone, Serialize, Deserialize)]
+pub struct ReadyState {
+    pub peer: PeerId,
+    pub ready: bool,
+}
+
+impl Rpc for ReadyState {
+    const NAME: &'static str = "ReadyState";
+
+    //players can only set their own ready state
+    fn set_sender(&mut self, sender: PeerId) {
+        self.peer = sender;
+    }
+}
+
+//None for lan lobbies on ipv6 addresses, which don't fit in a code
+pub fn invite_code(lobby: LobbyAddress) -> Option<String> {
+    let (prefix, mut value) = match lobby {
+        LobbyAddress::Steam(id) => ('S', id),
+        LobbyAddress::Lan(address) => ('L', peer_from_address(address).ok()?.0),
+    };
+
+    let mut digits: Vec<char> = Vec::new();
+    loop {
+        digits.push(INVITE_ALPHABET[(value % 32) as usize] as char);
+        value /= 32;
+        if value == 0 {
+            break;
+        }
+    }
+
+    Some(format!(
+        "{}-{}",
+        prefix,
+        digits.iter().rev().collect::<String>()
+    ))
+}
+
+//accepts codes in any case, None if the code is malformed
+pub fn parse_invite_code(code: &str) -> Option<LobbyAddress> {
+    let (prefix, digits) = code.trim().split_once('-')?;
+    if digits.is_empty() {
+        return None;
+    }
+
+    let mut value: u64 = 0;
+    for c in digits.chars() {
+        let c = match c.to_ascii_uppercase() {
+            'O' => '0',
+            'I' | 'L' => '1',
+            c => c,
+        };
+        let digit = INVITE_ALPHABET.iter().position(|d| *d as char == c)? as u64;
+        value = value.checked_mul(32)?.checked_add(digit)?;
+    }
+
+    match prefix.to_ascii_uppercase().as_str() {
+        "S" => Some(LobbyAddress::Steam(value)),
+        "L" => Some(LobbyAddress::Lan(address_from_peer(PeerId(value)))),
+        _ => None,
+    }
+}
+
+pub(super) fn handle_session_commands(
+    mut commands: EventReader<SessionCommand>,
+    mut session: ResMut<Session>,
+    mut networking: ResMut<NetworkingState>,
+    mut backend: ResMut<SessionBackendResource>,
+    mut ready_out: EventWriter<SendRpc<ReadyState>>,
+    mut events: EventWriter<SessionEvent>,
+) {
+    for command in commands.read() {
+        match command.clone() {
+            SessionCommand::Host { name } => {
+                if session.status != SessionStatus::Idle {
+                    println!("Can't host, leave the current session first");
+                    continue;
+                }
+
+                networking.host();
+                session.name = name.clone();
+                match backend.0.as_mut() {
+                    Some(backend) => {
+                        backend.host(&name, networking.max_players);
+                        session.status = SessionStatus::Creating;
+                    }
+                    None => {
+                        session.status = SessionStatus::Hosting;
+                        events.send(SessionEvent::Hosted { invite_code: None });
+                    }
+                }
+            }
+            SessionCommand::Join(lobby) => {
+                join_lobby(&mut session, &mut backend, &mut events, lobby);
+            }
+            SessionCommand::JoinInvite(code) => match parse_invite_code(&code) {
+                Some(lobby) => join_lobby(&mut session, &mut backend, &mut events, lobby),
+                None => {
+                    events.send(SessionEvent::JoinFailed(format!(
+                        "{} is not a valid invite code",
+                        code
+                    )));
+                }
+            },
+            SessionCommand::RefreshLobbies => match backend.0.as_mut() {
+                Some(backend) => backend.refresh(),
+                None => println!("This transport has no way to find lobbies"),
+            },
+            SessionCommand::SetReady(ready) => {
+                if networking.dedicated_server {
+                    continue;
+                }
+                //the broadcast is also delivered locally, which updates the session
+                ready_out.send(SendRpc {
+                    target: RpcTarget::Broadcast,
+                    rpc: ReadyState {
+                        peer: networking.player_id,
+                        ready,
+                    },
+                });
+            }
+            SessionCommand::Kick(peer) => {
+                if session.status != SessionStatus::Hosting {
+                    println!("Only the host of a session can kick players");
+                    continue;
+                }
+                networking.kick(peer);
+            }
+            SessionCommand::Leave => {
+                if session.status == SessionStatus::Idle {
+                    continue;
+                }
+                networking.leave();
+                if let Some(backend) = backend.0.as_mut() {
+                    backend.leave();
+                }
+                session.reset();
+                events.send(SessionEvent::Left);
+            }
+        }
+    }
+}
+
+/**
+ * Session commands typed into the terminal: host [name], join <invite code>, lobbies,
+ * ready, unready, kick <player id> and leave
+ */
+pub(super) fn run_console_commands(
+    mut console: EventReader<ConsoleCommand>,
+    mut commands: EventWriter<SessionCommand>,
+) {
+    for command in console.read() {
+        let arg = command.args.first();
+        let session_command = match (command.name.as_str(), arg) {
+            ("host", _) => SessionCommand::Host {
+                name: if command.args.is_empty() {
+                    "Session".to_string()
+                } else {
+                    command.args.join(" ")
+                },
+            },
+            ("join", Some(code)) => SessionCommand::JoinInvite(code.clone()),
+            ("lobbies", _) => SessionCommand::RefreshLobbies,
+            ("ready", _) => SessionCommand::SetReady(true),
+            ("unready", _) => SessionCommand::SetReady(false),
+            ("kick", Some(id)) => match id.parse() {
+                Ok(id) => SessionCommand::Kick(PeerId(id)),
+                Err(_) => {
+                    println!("{} is not a player id", id);
+                    continue;
+                }
+            },
+            ("leave", _) => SessionCommand::Leave,
+            ("join" | "kick", None) => {
+                println!("Usage: join <invite code>, kick <player id>");
+                continue;
+            }
+            //other modules have their own commands
+            _ => continue,
+        };
+        commands.send(session_command);
+    }
+}
+
+//the terminal is the only place session events are shown for now
+pub(super) fn print_session_events(
+    mut events: EventReader<SessionEvent>,
+    session: Res<Session>,
+    networking: Res<NetworkingState>,
+) {
+    for event in events.read() {
+        match event {
+            SessionEvent::Hosted {
+                invite_code: Some(code),
+            } => println!("Hosting a session, invite code {}", code),
+            SessionEvent::Hosted { invite_code: None } => println!("Hosting a session"),
+            SessionEvent::HostFailed(reason) => println!("Failed to host a session, {}", reason),
+            SessionEvent::Joined => println!("Joined the session"),
+            SessionEvent::JoinFailed(reason) => println!("Failed to join, {}", reason),
+            SessionEvent::LobbiesFound(lobbies) => {
+                println!("Found {} lobbies", lobbies.len());
+                for lobby in lobbies {
+                    println!(
+                        "  {} ({}/{}) {}",
+                        lobby.name,
+                        lobby.players,
+                        lobby.max_players,
+                        invite_code(lobby.address).unwrap_or_default()
+                    );
+                }
+            }
+            SessionEvent::PlayerJoined(peer) => println!("Player {} joined", peer.0),
+            SessionEvent::PlayerLeft(peer) => println!("Player {} left", peer.0),
+            SessionEvent::HostChanged { previous, host } => {
+                println!(
+                    "Player {} took over hosting from player {}",
+                    host.0, previous.0
+                )
+            }
+            SessionEvent::ReadyChanged { peer, ready } => {
+                println!(
+                    "Player {} is {}",
+                    peer.0,
+                    if *ready { "ready" } else { "not ready" }
+                );
+                if *ready && session.all_ready(&networking) {
+                    println!("Everyone is ready");
+                }
+            }
+            SessionEvent::Left => println!("Left the session"),
+        }
+    }
+}
+
+fn join_lobby(
+    session: &mut Session,
+    backend: &mut SessionBackendResource,
+    events: &mut EventWriter<SessionEvent>,
+    lobby: LobbyAddress,
+) {
+    if session.status != SessionStatus::Idle {
+        println!("Can't join, leave the current session first");
+        return;
+    }
+    //saves a round trip when the lobby list already shows there is no room
+    let info = session.lobbies.iter().find(|info| info.address == lobby);
+    if info.is_some_and(|info| info.is_full()) {
+        events.send(SessionEvent::JoinFailed("the session is full".to_string()));
+        return;
+    }
+    let name = info.map_or("Session".to_string(), |info| info.name.clone());
+    let Some(backend) = backend.0.as_mut() else {
+        events.send(SessionEvent::JoinFailed(
+            "this transport can't join lobbies".to_string(),
+        ));
+        return;
+    };
+
+    backend.join(lobby);
+    session.status = SessionStatus::Joining;
+    session.name = name;
+}
+
+//the ready states exchanged with the other players of the session
+#[derive(SystemParam)]
+pub(super) struct ReadyRpcs<'w, 's> {
+    received: EventReader<'w, 's, ReadyState>,
+    sent: EventWriter<'w, SendRpc<ReadyState>>,
+}
+
+//follows the backend and the players of the session
+pub(super) fn update_session(
+    mut session: ResMut<Session>,
+    mut networking: ResMut<NetworkingState>,
+    mut backend: ResMut<SessionBackendResource>,
+    mut rejections: EventReader<ConnectionRejected>,
+    mut migrations: EventReader<HostMigrated>,
+    mut ready: ReadyRpcs,
+    mut events: EventWriter<SessionEvent>,
+) {
+    let players = networking.active_players.len() as u16 + !networking.dedicated_server as u16;
+    let backend_events = match backend.0.as_mut() {
+        Some(backend) => backend.update(players),
+        None => Vec::new(),
+    };
+
+    for event in backend_events {
+        match event {
+            BackendEvent::Hosted(lobby) => {
+                let code = invite_code(lobby);
+                session.lobby = Some(lobby);
+                session.invite_code = code.clone();
+                session.status = SessionStatus::Hosting;
+                events.send(SessionEvent::Hosted { invite_code: code });
+            }
+            BackendEvent::HostFailed(reason) => {
+                networking.leave();
+                session.reset();
+                events.send(SessionEvent::HostFailed(reason));
+            }
+            BackendEvent::Joined { lobby, host } => {
+                if host == networking.player_id {
+                    backend.0.as_mut().unwrap().leave();
+                    session.reset();
+                    events.send(SessionEvent::JoinFailed(
+                        "this machine is the host of the lobby".to_string(),
+                    ));
+                    continue;
+                }
+
+                session.lobby = Some(lobby);
+                session.invite_code = invite_code(lobby);
+                session.host = Some(host);
+                networking.join(host);
+            }
+            BackendEvent::JoinFailed(reason) => {
+                session.reset();
+                events.send(SessionEvent::JoinFailed(reason));
+            }
+            BackendEvent::Lobbies(lobbies) => {
+                session.lobbies = lobbies.clone();
+                events.send(SessionEvent::LobbiesFound(lobbies));
+            }
+            BackendEvent::InviteAccepted(lobby) => {
+                join_lobby(&mut session, &mut backend, &mut events, lobby);
+            }
+        }
+    }
+
+    for rejection in rejections.read() {
+        if session.status == SessionStatus::Joining && session.host == Some(rejection.peer) {
+            if let Some(backend) = backend.0.as_mut() {
+                backend.leave();
+            }
+            session.reset();
+            events.send(SessionEvent::JoinFailed(rejection.reason.to_string()));
+        }
+    }
+
+    if session.status == SessionStatus::Joining {
+        if let Some(host) = session.host {
+            if networking.has_joined(host) {
+                session.status = SessionStatus::Joined;
+                events.send(SessionEvent::Joined);
+            } else if !networking.active_players.contains(&host) {
+                if let Some(backend) = backend.0.as_mut() {
+                    backend.leave();
+                }
+                session.reset();
+                events.send(SessionEvent::JoinFailed(
+                    "the host didn't answer".to_string(),
+                ));
+            }
+        }
+    }
+
+    for migration in migrations.read() {
+        events.send(SessionEvent::HostChanged {
+            previous: migration.previous,
+            host: migration.host,
+        });
+        if let Some(backend) = backend.0.as_mut() {
+            backend.follow_host(migration.host);
+        }
+        if migration.host == networking.player_id && session.status == SessionStatus::Joined {
+            session.status = SessionStatus::Hosting;
+            if let Some(backend) = backend.0.as_mut() {
+                backend.migrate(&session.name, networking.max_players);
+            }
+        }
+    }
+
+    let joined: Vec<PeerId> = networking
+        .active_players
+        .iter()
+        .copied()
+        .filter(|peer| networking.has_joined(*peer))
+        .collect();
+    for peer in joined.iter() {
+        if session.players.contains(peer) {
+            continue;
+        }
+        events.send(SessionEvent::PlayerJoined(*peer));
+
+        //players that join late still need to know who is ready
+        if !networking.dedicated_server {
+            ready.sent.send(SendRpc {
+                target: RpcTarget::Peer(*peer),
+                rpc: ReadyState {
+                    peer: networking.player_id,
+                    ready: session.is_ready(networking.player_id),
+                },
+            });
+        }
+    }
+    for peer in session.players.iter() {
+        if !joined.contains(peer) {
+            events.send(SessionEvent::PlayerLeft(*peer));
+        }
+    }
+    session
+        .ready
+        .retain(|peer, _| *peer == networking.player_id || joined.contains(peer));
+    session.players = joined;
+
+    //the session is over once everyone else is gone, either by leaving or by kicking this machine
+    if session.status == SessionStatus::Joined && session.players.is_empty() {
+        if let Some(backend) = backend.0.as_mut() {
+            backend.leave();
+        }
+        networking.leave();
+        session.reset();
+        events.send(SessionEvent::Left);
+    }
+
+    for state in ready.received.read() {
+        if state.peer != networking.player_id && !session.players.contains(&state.peer) {
+            continue;
+        }
+        if session.ready.insert(state.peer, state.ready) != Some(state.ready) {
+            events.send(SessionEvent::ReadyChanged {
+                peer: state.peer,
+                ready: state.ready,
+            });
+        }
+    }
+}
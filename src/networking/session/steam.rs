@@ -0,0 +1,217 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use steamworks::{CallbackHandle, Client, GameLobbyJoinRequested, LobbyId, LobbyType, SteamId};
+
+use super::{BackendEvent, LobbyAddress, LobbyInfo, SessionBackend};
+use crate::networking::transport::{steam_client, PeerId};
+use crate::networking::PROTOCOL_VERSION;
+
+//lobby data keys, lobbies without a matching protocol are hidden from the list
+const NAME_KEY: &str = "name";
+const PROTOCOL_KEY: &str = "protocol";
+//how long to wait for steam to report a new lobby owner before asking again
+const HANDOVER_RETRY: Duration = Duration::from_secs(2);
+
+/**
+ * Publishes sessions as steam lobbies. The owner of the lobby is the host of the session,
+ * joining the lobby connects to the owner over steam p2p.
+ * Steam answers through callbacks, which send their results over a channel to update.
+ */
+pub struct SteamLobbies {
+    client: Client,
+    lobby: Option<LobbyId>,
+    //the host the session elected, steam's choice of owner is corrected to match it
+    host: Option<PeerId>,
+    //when the lobby may be handed to the host next, None once the host owns it
+    handover_at: Option<Instant>,
+    sender: Sender<BackendEvent>,
+    receiver: Mutex<Receiver<BackendEvent>>,
+    _join_requests: CallbackHandle,
+}
+
+impl SteamLobbies {
+    pub fn new(app_id: u32) -> Self {
+        let (client, _) = steam_client(app_id);
+        let (sender, receiver) = channel();
+
+        //invites accepted through the steam overlay
+        let invites = sender.clone();
+        let join_requests = client.register_callback(move |request: GameLobbyJoinRequested| {
+            let lobby = LobbyAddress::Steam(request.lobby_steam_id.raw());
+            let _ = invites.send(BackendEvent::InviteAccepted(lobby));
+        });
+
+        Self {
+            client,
+            lobby: None,
+            host: None,
+            handover_at: None,
+            sender,
+            receiver: Mutex::new(receiver),
+            _join_requests: join_requests,
+        }
+    }
+
+    //steamworks 0.11 doesn't wrap SetLobbyOwner, false if steam refused the request
+    fn set_lobby_owner(&self, lobby: LobbyId, owner: SteamId) -> bool {
+        // SAFETY: the matchmaking interface lives as long as the steam client, which self holds,
+        //  and SetLobbyOwner only reads the two ids passed by value
+        unsafe {
+            steamworks_sys::SteamAPI_ISteamMatchmaking_SetLobbyOwner(
+                steamworks_sys::SteamAPI_SteamMatchmaking_v009(),
+                lobby.raw(),
+                owner.raw(),
+            )
+        }
+    }
+}
+
+impl SessionBackend for SteamLobbies {
+    fn host(&mut self, name: &str, max_players: u16) {
+        let client = self.client.clone();
+        let sender = self.sender.clone();
+        let name = name.to_string();
+
+        self.client.matchmaking().create_lobby(
+            LobbyType::Public,
+            max_players as u32,
+            move |result| {
+                let event = match result {
+                    Ok(lobby) => {
+                        let matchmaking = client.matchmaking();
+                        matchmaking.set_lobby_data(lobby, NAME_KEY, &name);
+                        matchmaking.set_lobby_data(
+                            lobby,
+                            PROTOCOL_KEY,
+                            &PROTOCOL_VERSION.to_string(),
+                        );
+                        BackendEvent::Hosted(LobbyAddress::Steam(lobby.raw()))
+                    }
+                    Err(error) => BackendEvent::HostFailed(error.to_string()),
+                };
+                let _ = sender.send(event);
+            },
+        );
+    }
+
+    fn join(&mut self, lobby: LobbyAddress) {
+        let LobbyAddress::Steam(id) = lobby else {
+            let _ = self.sender.send(BackendEvent::JoinFailed(
+                "lan sessions can't be joined over steam".to_string(),
+            ));
+            return;
+        };
+
+        let client = self.client.clone();
+        let sender = self.sender.clone();
+        self.client
+            .matchmaking()
+            .join_lobby(LobbyId::from_raw(id), move |result| {
+                let event = match result {
+                    Ok(lobby) => BackendEvent::Joined {
+                        lobby: LobbyAddress::Steam(lobby.raw()),
+                        host: PeerId(client.matchmaking().lobby_owner(lobby).raw()),
+                    },
+                    Err(_) => BackendEvent::JoinFailed("steam couldn't join the lobby".to_string()),
+                };
+                let _ = sender.send(event);
+            });
+    }
+
+    fn refresh(&mut self) {
+        let client = self.client.clone();
+        let sender = self.sender.clone();
+        let protocol = PROTOCOL_VERSION.to_string();
+
+        self.client.matchmaking().request_lobby_list(move |result| {
+            let event = match result {
+                Ok(lobbies) => {
+                    let matchmaking = client.matchmaking();
+                    let lobbies = lobbies
+                        .into_iter()
+                        .filter(|lobby| {
+                            matchmaking
+                                .lobby_data(*lobby, PROTOCOL_KEY)
+                                .is_some_and(|value| value == protocol)
+                        })
+                        .map(|lobby| LobbyInfo {
+                            address: LobbyAddress::Steam(lobby.raw()),
+                            name: matchmaking
+                                .lobby_data(lobby, NAME_KEY)
+                                .map(|name| name.to_string())
+                                .unwrap_or_default(),
+                            players: matchmaking.lobby_member_count(lobby) as u16,
+                            max_players: matchmaking
+                                .lobby_member_limit(lobby)
+                                .map_or(u16::MAX, |limit| limit as u16),
+                        })
+                        .collect();
+                    BackendEvent::Lobbies(lobbies)
+                }
+                Err(error) => {
+                    println!("Failed to list steam lobbies, {}", error);
+                    BackendEvent::Lobbies(Vec::new())
+                }
+            };
+            let _ = sender.send(event);
+        });
+    }
+
+    fn leave(&mut self) {
+        self.host = None;
+        self.handover_at = None;
+        if let Some(lobby) = self.lobby.take() {
+            self.client.matchmaking().leave_lobby(lobby);
+        }
+    }
+
+    //steam hands the lobby to a member of its choosing when its owner leaves,
+    //  update passes it on to the elected host, so the lobby is kept as it is
+    fn migrate(&mut self, _name: &str, _max_players: u16) {
+        if let Some(lobby) = self.lobby {
+            let _ = self
+                .sender
+                .send(BackendEvent::Hosted(LobbyAddress::Steam(lobby.raw())));
+        }
+    }
+
+    fn follow_host(&mut self, host: PeerId) {
+        self.host = Some(host);
+        self.handover_at = Some(Instant::now());
+    }
+
+    fn update(&mut self, _players: u16) -> Vec<BackendEvent> {
+        //only the owner can hand the lobby over, and steam may pick it after the election,
+        //  so whoever ends up owning it asks steam to pass it on until the host has it
+        if let (Some(lobby), Some(host), Some(handover_at)) =
+            (self.lobby, self.host, self.handover_at)
+        {
+            let owner = self.client.matchmaking().lobby_owner(lobby);
+            let local = self.client.user().steam_id();
+            if owner.raw() == host.0 {
+                self.handover_at = None;
+            } else if owner == local && Instant::now() >= handover_at {
+                if !self.set_lobby_owner(lobby, SteamId::from_raw(host.0)) {
+                    println!("Steam refused to hand the lobby to {:?}", host);
+                }
+                self.handover_at = Some(Instant::now() + HANDOVER_RETRY);
+            }
+        }
+
+        //steam keeps the member count of its lobbies itself
+        let events: Vec<BackendEvent> = self.receiver.lock().unwrap().try_iter().collect();
+        for event in events.iter() {
+            match event {
+                BackendEvent::Hosted(LobbyAddress::Steam(id))
+                | BackendEvent::Joined {
+                    lobby: LobbyAddress::Steam(id),
+                    ..
+                } => self.lobby = Some(LobbyId::from_raw(*id)),
+                _ => {}
+            }
+        }
+
+        events
+    }
+}
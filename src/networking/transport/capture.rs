@@ -0,0 +1,262 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{
+    read_capture, CaptureRecord, Direction, PeerId, Reliability, Transport, FORMAT_VERSION, MAGIC,
+};
+
+//a crash loses at most this much of the capture, the rest is written when the app exits
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+//writes every packet sent or read through the wrapped transport to a capture file
+pub struct CaptureTransport {
+    inner: Box<dyn Transport>,
+    //None once writing failed, so the error is only reported once
+    file: Mutex<Option<CaptureFile>>,
+    start: Instant,
+}
+
+struct CaptureFile {
+    writer: BufWriter<File>,
+    flushed_at: Instant,
+}
+
+impl CaptureTransport {
+    //type_names are written to the header so captures can be inspected without this build
+    pub fn create(
+        inner: Box<dyn Transport>,
+        path: &Path,
+        protocol_version: u16,
+        type_names: &[(u16, &str)],
+    ) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        header.extend_from_slice(&protocol_version.to_le_bytes());
+        header.extend_from_slice(&inner.local_peer().0.to_le_bytes());
+        header.extend_from_slice(&(type_names.len() as u16).to_le_bytes());
+        for (type_id, name) in type_names {
+            let name = &name.as_bytes()[..name.len().min(u8::MAX as usize)];
+            header.extend_from_slice(&type_id.to_le_bytes());
+            header.push(name.len() as u8);
+            header.extend_from_slice(name);
+        }
+        writer.write_all(&header)?;
+
+        Ok(Self {
+            inner,
+            file: Mutex::new(Some(CaptureFile {
+                writer,
+                flushed_at: Instant::now(),
+            })),
+            start: Instant::now(),
+        })
+    }
+
+    fn record(
+        &self,
+        direction: Direction,
+        peer: PeerId,
+        reliability: Option<Reliability>,
+        bytes: &[u8],
+    ) {
+        let mut file = self.file.lock().unwrap();
+        let Some(capture) = file.as_mut() else {
+            return;
+        };
+
+        let mut record = Vec::with_capacity(bytes.len() + 22);
+        record.extend_from_slice(&self.start.elapsed().as_secs_f64().to_bits().to_le_bytes());
+        record.push(match direction {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        });
+        record.extend_from_slice(&peer.0.to_le_bytes());
+        record.push(match reliability {
+            Some(Reliability::Unreliable) => 0,
+            Some(Reliability::UnreliableSequenced) => 1,
+            Some(Reliability::Reliable) => 2,
+            None => u8::MAX,
+        });
+        record.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        record.extend_from_slice(bytes);
+
+        let mut result = capture.writer.write_all(&record);
+        if result.is_ok() && capture.flushed_at.elapsed() >= FLUSH_INTERVAL {
+            capture.flushed_at = Instant::now();
+            result = capture.writer.flush();
+        }
+        if let Err(error) = result {
+            println!("Stopped writing the packet capture, {}", error);
+            *file = None;
+        }
+    }
+}
+
+impl Transport for CaptureTransport {
+    fn local_peer(&self) -> PeerId {
+        self.inner.local_peer()
+    }
+
+    fn send_packet(&self, peer: PeerId, reliability: Reliability, bytes: &[u8]) -> bool {
+        self.record(Direction::Sent, peer, Some(reliability), bytes);
+        self.inner.send_packet(peer, reliability, bytes)
+    }
+
+    fn is_packet_available(&self) -> Option<usize> {
+        self.inner.is_packet_available()
+    }
+
+    fn read_packet(&self, buffer: &mut [u8]) -> Option<(PeerId, usize)> {
+        let (sender, len) = self.inner.read_packet(buffer)?;
+        self.record(Direction::Received, sender, None, &buffer[..len]);
+
+        Some((sender, len))
+    }
+
+    fn accept_peer(&self, peer: PeerId) {
+        self.inner.accept_peer(peer);
+    }
+
+    fn forget_peer(&self, peer: PeerId) {
+        self.inner.forget_peer(peer);
+    }
+
+    fn observe_local_peer(&self, observed: PeerId) -> Option<PeerId> {
+        self.inner.observe_local_peer(observed)
+    }
+
+    fn lost_peers(&self) -> Vec<PeerId> {
+        self.inner.lost_peers()
+    }
+
+    fn flush(&self) {
+        let mut file = self.file.lock().unwrap();
+        if let Some(Err(error)) = file.as_mut().map(|capture| capture.writer.flush()) {
+            println!("Failed to write the end of the packet capture, {}", error);
+            *file = None;
+        }
+        self.inner.flush();
+    }
+}
+
+/**
+ * Plays back the packets a capture received at the times they were received,
+ * everything sent is discarded. Used to reproduce bugs in a headless app.
+ */
+pub struct ReplayTransport {
+    local_peer: PeerId,
+    packets: Mutex<VecDeque<CaptureRecord>>,
+    start: Instant,
+}
+
+impl ReplayTransport {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let (header, records) = read_capture(path)?;
+
+        Ok(Self {
+            local_peer: header.local_peer,
+            packets: Mutex::new(
+                records
+                    .into_iter()
+                    .filter(|record| record.direction == Direction::Received)
+                    .collect(),
+            ),
+            start: Instant::now(),
+        })
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn local_peer(&self) -> PeerId {
+        self.local_peer
+    }
+
+    fn send_packet(&self, _peer: PeerId, _reliability: Reliability, _bytes: &[u8]) -> bool {
+        true
+    }
+
+    fn is_packet_available(&self) -> Option<usize> {
+        let packets = self.packets.lock().unwrap();
+        let next = packets.front()?;
+        if next.time > self.start.elapsed().as_secs_f64() {
+            return None;
+        }
+
+        Some(next.bytes.len())
+    }
+
+    fn read_packet(&self, buffer: &mut [u8]) -> Option<(PeerId, usize)> {
+        let len = self.is_packet_available()?;
+        let packet = self.packets.lock().unwrap().pop_front()?;
+        if len > buffer.len() {
+            println!(
+                "Skipped a {} byte packet from {:?} in the replay, the buffer only holds {} bytes",
+                len,
+                packet.peer,
+                buffer.len()
+            );
+            return None;
+        }
+
+        buffer[..len].copy_from_slice(&packet.bytes);
+
+        Some((packet.peer, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::LoopbackNetwork;
+    use super::*;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn captures_survive_a_crash_and_replay() {
+        let path = std::env::temp_dir().join(format!("capture_test_{}", std::process::id()));
+        let network = LoopbackNetwork::new();
+        let local = network.endpoint();
+        let remote = network.endpoint();
+        let (local_peer, remote_peer) = (local.local_peer(), remote.local_peer());
+
+        let capture = CaptureTransport::create(Box::new(local), &path, 3, &[(7, "Transform")])
+            .expect("failed to create the capture");
+        capture.send_packet(remote_peer, Reliability::Reliable, &[1, 2]);
+        remote.send_packet(local_peer, Reliability::Reliable, &[3, 4, 5]);
+        let mut buffer = [0; 16];
+        assert_eq!(capture.read_packet(&mut buffer), Some((remote_peer, 3)));
+        capture.flush();
+
+        //a crash in the middle of writing a record leaves only part of it
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[0; 5])
+            .unwrap();
+
+        let (header, records) = read_capture(&path).expect("failed to read the capture");
+        assert_eq!(header.protocol_version, 3);
+        assert_eq!(header.local_peer, local_peer);
+        assert_eq!(header.type_names, vec![(7, "Transform".to_string())]);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Sent);
+        assert_eq!(records[0].reliability, Some(Reliability::Reliable));
+        assert_eq!(records[1].bytes, vec![3, 4, 5]);
+
+        //packets that don't fit the buffer are skipped instead of cut off
+        let replay = ReplayTransport::open(&path).expect("failed to open the replay");
+        //the packet is played back as long after the start as it was received
+        std::thread::sleep(Duration::from_secs_f64(records[1].time) + Duration::from_millis(10));
+        assert_eq!(replay.is_packet_available(), Some(3));
+        assert_eq!(replay.read_packet(&mut buffer[..2]), None);
+        assert_eq!(replay.is_packet_available(), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+/*!
+ * Reading packet capture files, kept free of bevy and the game's types
+ * so the capture inspector in src/bin can share it.
+ *
+ * Capture file layout, all little endian:
+ * header: magic, format version u16, protocol version u16, local peer u64,
+ *   type count u16, then per type its id u16, name length u8 and name
+ * records: time f64, direction u8, peer u64, reliability u8, length u32, bytes
+ */
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use super::{PeerId, Reliability};
+
+//written at the start of every capture file
+pub const MAGIC: &[u8; 4] = b"SCNC";
+//bump whenever the layout of capture files changes
+pub const FORMAT_VERSION: u16 = 2;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+//describes the machine a capture was recorded on
+#[derive(Clone, Debug)]
+pub struct CaptureHeader {
+    pub protocol_version: u16,
+    pub local_peer: PeerId,
+    //the networked types of the recording build, so components can be named without it
+    pub type_names: Vec<(u16, String)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CaptureRecord {
+    //seconds since the capture started
+    pub time: f64,
+    pub direction: Direction,
+    pub peer: PeerId,
+    //only known for sent packets
+    pub reliability: Option<Reliability>,
+    pub bytes: Vec<u8>,
+}
+
+pub fn read_capture(path: &Path) -> io::Result<(CaptureHeader, Vec<CaptureRecord>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u16(&mut reader)? != FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a capture file or written by a different build",
+        ));
+    }
+
+    let protocol_version = read_u16(&mut reader)?;
+    let local_peer = PeerId(read_u64(&mut reader)?);
+    let mut type_names = Vec::new();
+    for _ in 0..read_u16(&mut reader)? {
+        let type_id = read_u16(&mut reader)?;
+        let mut len = [0; 1];
+        reader.read_exact(&mut len)?;
+        let mut name = vec![0; len[0] as usize];
+        reader.read_exact(&mut name)?;
+        type_names.push((type_id, String::from_utf8_lossy(&name).into_owned()));
+    }
+
+    let header = CaptureHeader {
+        protocol_version,
+        local_peer,
+        type_names,
+    };
+
+    let mut records = Vec::new();
+    loop {
+        match read_record(&mut reader) {
+            Ok(record) => records.push(record),
+            //the file ends between records, or in the middle of one if the game crashed while writing it
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok((header, records))
+}
+
+fn read_record(reader: &mut impl Read) -> io::Result<CaptureRecord> {
+    let time = f64::from_bits(read_u64(reader)?);
+    let mut direction = [0; 1];
+    reader.read_exact(&mut direction)?;
+    let peer = PeerId(read_u64(reader)?);
+    let mut reliability = [0; 1];
+    reader.read_exact(&mut reliability)?;
+
+    let mut bytes = vec![0; read_u32(reader)? as usize];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(CaptureRecord {
+        time,
+        direction: match direction[0] {
+            0 => Direction::Sent,
+            _ => Direction::Received,
+        },
+        peer,
+        reliability: match reliability[0] {
+            0 => Some(Reliability::Unreliable),
+            1 => Some(Reliability::UnreliableSequenced),
+            2 => Some(Reliability::Reliable),
+            _ => None,
+        },
+        bytes,
+    })
+}
+
+fn read_u16(reader: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
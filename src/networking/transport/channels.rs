@@ -0,0 +1,592 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{PeerId, Reliability, Transport};
+
+//payload bytes per datagram, small enough to stay under common MTUs once headers are added
+const FRAGMENT_SIZE: usize = 1024;
+//the fragment count is sent as a u8
+const MAX_FRAGMENTS: usize = u8::MAX as usize;
+//how long to wait for an ack before sending a reliable fragment again
+const RESEND_INTERVAL: Duration = Duration::from_millis(200);
+//a peer that leaves a reliable fragment unacknowledged this long is treated as disconnected
+const RESEND_TIMEOUT: Duration = Duration::from_secs(10);
+//reliable messages that may wait for an older one before the peer is treated as disconnected
+const MAX_OUT_OF_ORDER: usize = 1024;
+//incomplete unreliable messages this many sequences behind the newest are discarded
+const UNRELIABLE_WINDOW: u16 = 32;
+//peers that haven't completed the handshake, packets from any more unknown peers are dropped
+const MAX_PENDING_PEERS: usize = 32;
+//how long a peer may take to complete the handshake before its channels are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+//a whole reliable message handed to a transport that guarantees reliability itself
+const PASSTHROUGH: u8 = 2;
+
+fn channel_index(reliability: Reliability) -> u8 {
+    match reliability {
+        Reliability::Unreliable => 0,
+        Reliability::UnreliableSequenced => 1,
+        Reliability::Reliable => 2,
+    }
+}
+
+//true if sequence a was sent after sequence b, accounting for wrap around
+fn sequence_newer(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+struct Fragments {
+    parts: Vec<Option<Vec<u8>>>,
+    received: usize,
+}
+
+impl Fragments {
+    fn new(count: usize) -> Self {
+        Self {
+            parts: vec![None; count],
+            received: 0,
+        }
+    }
+
+    //returns the whole message once every fragment has arrived
+    fn insert(&mut self, index: usize, payload: &[u8]) -> Option<Vec<u8>> {
+        if index < self.parts.len() && self.parts[index].is_none() {
+            self.parts[index] = Some(payload.to_vec());
+            self.received += 1;
+        }
+
+        if self.received < self.parts.len() {
+            return None;
+        }
+        Some(self.parts.drain(..).flatten().flatten().collect())
+    }
+}
+
+struct UnackedFragment {
+    sequence: u16,
+    fragment: u8,
+    datagram: Vec<u8>,
+    first_sent: Instant,
+    last_sent: Instant,
+}
+
+#[derive(Default)]
+struct PeerChannels {
+    next_sequence: [u16; 3],
+    unacked: Vec<UnackedFragment>,
+    pending_acks: Vec<(u16, u8)>,
+
+    //sequence of the next reliable message to deliver, messages after it wait until it arrives
+    next_reliable: u16,
+    reliable_in: HashMap<u16, Fragments>,
+    completed_reliable: HashMap<u16, Vec<u8>>,
+
+    //incomplete unreliable messages by channel and sequence
+    unreliable_in: HashMap<(u8, u16), Fragments>,
+    newest_unreliable: [Option<u16>; 2],
+}
+
+/**
+ * Splits messages into fragments over any transport and offers three channels:
+ * unreliable, unreliable sequenced which drops messages older than the newest
+ * one delivered, and reliable ordered which resends until acknowledged.
+ * Everything is sent to the wrapped transport unreliably. Skipping a reliable
+ * message would break the order, so a peer that never acknowledges one or never
+ * sends a missing one is dropped and reported through lost_peers instead.
+ * If the wrapped transport guarantees reliability, like steam, reliable messages
+ * are passed to it whole instead. Channels are only kept for a limited number of
+ * peers until they complete the handshake, so unknown senders can't grow them forever.
+ */
+pub struct ChanneledTransport {
+    inner: Box<dyn Transport>,
+    peers: Mutex<HashMap<PeerId, PeerChannels>>,
+    //peers with channels that haven't completed the handshake and when their channels were made
+    pending: Mutex<HashMap<PeerId, Instant>>,
+    ready: Mutex<VecDeque<(PeerId, Vec<u8>)>>,
+    lost: Mutex<Vec<PeerId>>,
+}
+
+impl ChanneledTransport {
+    pub fn new(inner: Box<dyn Transport>) -> Self {
+        Self {
+            inner,
+            peers: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            ready: Mutex::new(VecDeque::new()),
+            lost: Mutex::new(Vec::new()),
+        }
+    }
+
+    //the channels of the peer, made if there is room for another peer that hasn't completed the handshake
+    fn channels<'a>(
+        &self,
+        peers: &'a mut HashMap<PeerId, PeerChannels>,
+        peer: PeerId,
+    ) -> Option<&'a mut PeerChannels> {
+        if !peers.contains_key(&peer) {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.len() >= MAX_PENDING_PEERS {
+                return None;
+            }
+            pending.insert(peer, Instant::now());
+        }
+
+        Some(peers.entry(peer).or_default())
+    }
+
+    //forgets the peer and reports it once, instead of logging every fragment that failed
+    fn lose_peer(&self, peers: &mut HashMap<PeerId, PeerChannels>, peer: PeerId, reason: &str) {
+        self.pending.lock().unwrap().remove(&peer);
+        if peers.remove(&peer).is_some() {
+            println!("Lost reliable connection to {:?}, {}", peer, reason);
+            self.lost.lock().unwrap().push(peer);
+        }
+    }
+
+    fn pump(&self) {
+        let mut buffer = Vec::new();
+        while let Some(size) = self.inner.is_packet_available() {
+            buffer.resize(size, 0);
+            let Some((sender, len)) = self.inner.read_packet(&mut buffer) else {
+                break;
+            };
+            self.receive_datagram(sender, &buffer[..len]);
+        }
+
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        let timed_out: Vec<PeerId> = peers
+            .iter()
+            .filter(|(_, channels)| {
+                channels
+                    .unacked
+                    .iter()
+                    .any(|fragment| now - fragment.first_sent > RESEND_TIMEOUT)
+            })
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in timed_out {
+            self.lose_peer(&mut peers, peer, "a message went unacknowledged");
+        }
+
+        let never_joined: Vec<PeerId> = self
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, created)| now - **created > HANDSHAKE_TIMEOUT)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in never_joined {
+            self.lose_peer(&mut peers, peer, "the handshake never completed");
+        }
+
+        for (peer, channels) in peers.iter_mut() {
+            for fragment in channels.unacked.iter_mut() {
+                if now - fragment.last_sent > RESEND_INTERVAL {
+                    self.inner
+                        .send_packet(*peer, Reliability::Unreliable, &fragment.datagram);
+                    fragment.last_sent = now;
+                }
+            }
+
+            if !channels.pending_acks.is_empty() {
+                let mut ack = vec![ACK];
+                for (sequence, fragment) in channels.pending_acks.drain(..) {
+                    ack.extend_from_slice(&sequence.to_le_bytes());
+                    ack.push(fragment);
+                }
+                self.inner.send_packet(*peer, Reliability::Unreliable, &ack);
+            }
+        }
+    }
+
+    fn receive_datagram(&self, sender: PeerId, datagram: &[u8]) {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(channels) = self.channels(&mut peers, sender) else {
+            return;
+        };
+
+        match datagram.first() {
+            Some(&PASSTHROUGH) => {
+                self.ready
+                    .lock()
+                    .unwrap()
+                    .push_back((sender, datagram[1..].to_vec()));
+            }
+            Some(&ACK) => {
+                for ack in datagram[1..].chunks_exact(3) {
+                    let sequence = u16::from_le_bytes([ack[0], ack[1]]);
+                    channels.unacked.retain(|fragment| {
+                        fragment.sequence != sequence || fragment.fragment != ack[2]
+                    });
+                }
+            }
+            Some(&DATA) if datagram.len() >= 6 => {
+                let channel = datagram[1];
+                let sequence = u16::from_le_bytes([datagram[2], datagram[3]]);
+                let fragment = datagram[4] as usize;
+                let fragment_count = datagram[5] as usize;
+                let payload = &datagram[6..];
+
+                if fragment_count == 0 || fragment >= fragment_count {
+                    return;
+                }
+
+                let mut ready = self.ready.lock().unwrap();
+                if channel == channel_index(Reliability::Reliable) {
+                    //duplicates are acked again in case the first ack was lost
+                    channels.pending_acks.push((sequence, fragment as u8));
+                    if sequence_newer(channels.next_reliable, sequence)
+                        || channels.completed_reliable.contains_key(&sequence)
+                    {
+                        return;
+                    }
+
+                    let message = channels
+                        .reliable_in
+                        .entry(sequence)
+                        .or_insert_with(|| Fragments::new(fragment_count))
+                        .insert(fragment, payload);
+                    if let Some(message) = message {
+                        channels.reliable_in.remove(&sequence);
+                        channels.completed_reliable.insert(sequence, message);
+                    }
+
+                    //delivers every message that is now in order
+                    while let Some(message) =
+                        channels.completed_reliable.remove(&channels.next_reliable)
+                    {
+                        ready.push_back((sender, message));
+                        channels.next_reliable = channels.next_reliable.wrapping_add(1);
+                    }
+
+                    //the missing message is never coming, the sender gave up on it
+                    if channels.completed_reliable.len() + channels.reliable_in.len()
+                        > MAX_OUT_OF_ORDER
+                    {
+                        self.lose_peer(&mut peers, sender, "a message never arrived");
+                    }
+                } else if channel < 2 {
+                    let newest = &mut channels.newest_unreliable[channel as usize];
+                    let sequenced = channel == channel_index(Reliability::UnreliableSequenced);
+                    if sequenced {
+                        if let Some(newest) = newest {
+                            if !sequence_newer(sequence, *newest) {
+                                return;
+                            }
+                        }
+                    }
+
+                    let message = channels
+                        .unreliable_in
+                        .entry((channel, sequence))
+                        .or_insert_with(|| Fragments::new(fragment_count))
+                        .insert(fragment, payload);
+
+                    if let Some(message) = message {
+                        channels.unreliable_in.remove(&(channel, sequence));
+                        if newest.is_none_or(|newest| sequence_newer(sequence, newest)) {
+                            *newest = Some(sequence);
+                        }
+                        ready.push_back((sender, message));
+                    }
+
+                    //discards fragments of messages that are too old to complete
+                    let newest = channels.newest_unreliable[channel as usize].unwrap_or(sequence);
+                    channels.unreliable_in.retain(|(c, s), _| {
+                        *c != channel || newest.wrapping_sub(*s) < UNRELIABLE_WINDOW
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Transport for ChanneledTransport {
+    fn local_peer(&self) -> PeerId {
+        self.inner.local_peer()
+    }
+
+    fn send_packet(&self, peer: PeerId, reliability: Reliability, bytes: &[u8]) -> bool {
+        let fragment_count = bytes.len().div_ceil(FRAGMENT_SIZE).max(1);
+        if fragment_count > MAX_FRAGMENTS {
+            println!(
+                "Message of {} bytes is too large to send to {:?}",
+                bytes.len(),
+                peer
+            );
+            return false;
+        }
+
+        let mut peers = self.peers.lock().unwrap();
+        let Some(channels) = self.channels(&mut peers, peer) else {
+            println!(
+                "Dropped a message to {:?}, too many peers are joining",
+                peer
+            );
+            return false;
+        };
+
+        if reliability == Reliability::Reliable && self.inner.guarantees_reliable() {
+            let mut datagram = vec![PASSTHROUGH];
+            datagram.extend_from_slice(bytes);
+            return self.inner.send_packet(peer, reliability, &datagram);
+        }
+        let channel = channel_index(reliability);
+
+        let sequence = channels.next_sequence[channel as usize];
+        channels.next_sequence[channel as usize] = sequence.wrapping_add(1);
+
+        let now = Instant::now();
+        let mut sent = true;
+        for fragment in 0..fragment_count {
+            let start = fragment * FRAGMENT_SIZE;
+            let end = (start + FRAGMENT_SIZE).min(bytes.len());
+
+            let mut datagram = vec![DATA, channel];
+            datagram.extend_from_slice(&sequence.to_le_bytes());
+            datagram.push(fragment as u8);
+            datagram.push(fragment_count as u8);
+            datagram.extend_from_slice(&bytes[start..end]);
+
+            sent &= self
+                .inner
+                .send_packet(peer, Reliability::Unreliable, &datagram);
+
+            if reliability == Reliability::Reliable {
+                channels.unacked.push(UnackedFragment {
+                    sequence,
+                    fragment: fragment as u8,
+                    datagram,
+                    first_sent: now,
+                    last_sent: now,
+                });
+            }
+        }
+
+        //reliable messages are resent until acknowledged, so a failed first send isn't fatal
+        sent || reliability == Reliability::Reliable
+    }
+
+    fn is_packet_available(&self) -> Option<usize> {
+        self.pump();
+        self.ready
+            .lock()
+            .unwrap()
+            .front()
+            .map(|(_, bytes)| bytes.len())
+    }
+
+    fn read_packet(&self, buffer: &mut [u8]) -> Option<(PeerId, usize)> {
+        let (sender, bytes) = self.ready.lock().unwrap().pop_front()?;
+        let len = bytes.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&bytes[..len]);
+
+        Some((sender, len))
+    }
+
+    fn accept_peer(&self, peer: PeerId) {
+        self.pending.lock().unwrap().remove(&peer);
+        self.inner.accept_peer(peer);
+    }
+
+    //stops resending messages to a peer that left
+    fn forget_peer(&self, peer: PeerId) {
+        self.peers.lock().unwrap().remove(&peer);
+        self.pending.lock().unwrap().remove(&peer);
+        self.inner.forget_peer(peer);
+    }
+
+    fn observe_local_peer(&self, observed: PeerId) -> Option<PeerId> {
+        self.inner.observe_local_peer(observed)
+    }
+
+    fn lost_peers(&self) -> Vec<PeerId> {
+        let mut lost = std::mem::take(&mut *self.lost.lock().unwrap());
+        lost.extend(self.inner.lost_peers());
+        lost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{LoopbackNetwork, LoopbackTransport};
+    use super::*;
+
+    const SENDER: PeerId = PeerId(7);
+
+    //a loopback endpoint that claims to deliver reliable packets itself, like steam
+    struct GuaranteedTransport(LoopbackTransport);
+
+    impl Transport for GuaranteedTransport {
+        fn local_peer(&self) -> PeerId {
+            self.0.local_peer()
+        }
+
+        fn send_packet(&self, peer: PeerId, reliability: Reliability, bytes: &[u8]) -> bool {
+            self.0.send_packet(peer, reliability, bytes)
+        }
+
+        fn is_packet_available(&self) -> Option<usize> {
+            self.0.is_packet_available()
+        }
+
+        fn read_packet(&self, buffer: &mut [u8]) -> Option<(PeerId, usize)> {
+            self.0.read_packet(buffer)
+        }
+
+        fn guarantees_reliable(&self) -> bool {
+            true
+        }
+    }
+
+    fn datagram(
+        reliability: Reliability,
+        sequence: u16,
+        fragment: u8,
+        count: u8,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut datagram = vec![DATA, channel_index(reliability)];
+        datagram.extend_from_slice(&sequence.to_le_bytes());
+        datagram.push(fragment);
+        datagram.push(count);
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    fn receiver() -> ChanneledTransport {
+        ChanneledTransport::new(Box::new(LoopbackNetwork::new().endpoint()))
+    }
+
+    fn drain(transport: &ChanneledTransport) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        while let Some(size) = transport.is_packet_available() {
+            let mut buffer = vec![0; size];
+            let (_, len) = transport.read_packet(&mut buffer).unwrap();
+            messages.push(buffer[..len].to_vec());
+        }
+        messages
+    }
+
+    #[test]
+    fn large_messages_are_split_and_put_back_together() {
+        let network = LoopbackNetwork::new();
+        let sender = ChanneledTransport::new(Box::new(network.endpoint()));
+        let receiver = ChanneledTransport::new(Box::new(network.endpoint()));
+
+        let message: Vec<u8> = (0..FRAGMENT_SIZE * 3 + 10).map(|i| i as u8).collect();
+        assert!(sender.send_packet(receiver.local_peer(), Reliability::Reliable, &message));
+
+        assert_eq!(drain(&receiver), vec![message]);
+    }
+
+    #[test]
+    fn messages_over_the_fragment_limit_are_refused() {
+        let network = LoopbackNetwork::new();
+        let sender = ChanneledTransport::new(Box::new(network.endpoint()));
+        let peer = network.endpoint().local_peer();
+
+        let message = vec![0; FRAGMENT_SIZE * MAX_FRAGMENTS + 1];
+        assert!(!sender.send_packet(peer, Reliability::Reliable, &message));
+    }
+
+    #[test]
+    fn fragments_arriving_out_of_order_are_reassembled() {
+        let transport = receiver();
+        transport.receive_datagram(SENDER, &datagram(Reliability::Unreliable, 0, 2, 3, &[5, 6]));
+        transport.receive_datagram(SENDER, &datagram(Reliability::Unreliable, 0, 0, 3, &[1, 2]));
+        assert!(drain(&transport).is_empty());
+
+        transport.receive_datagram(SENDER, &datagram(Reliability::Unreliable, 0, 1, 3, &[3, 4]));
+        assert_eq!(drain(&transport), vec![vec![1, 2, 3, 4, 5, 6]]);
+    }
+
+    #[test]
+    fn reliable_messages_wait_for_the_ones_sent_before_them() {
+        let transport = receiver();
+        transport.receive_datagram(SENDER, &datagram(Reliability::Reliable, 1, 0, 1, &[1]));
+        transport.receive_datagram(SENDER, &datagram(Reliability::Reliable, 2, 0, 1, &[2]));
+        assert!(drain(&transport).is_empty());
+
+        transport.receive_datagram(SENDER, &datagram(Reliability::Reliable, 0, 0, 1, &[0]));
+        //a resent duplicate isn't delivered twice
+        transport.receive_datagram(SENDER, &datagram(Reliability::Reliable, 1, 0, 1, &[1]));
+        assert_eq!(drain(&transport), vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn sequenced_messages_older_than_the_newest_are_dropped() {
+        let transport = receiver();
+        let sequenced = Reliability::UnreliableSequenced;
+        transport.receive_datagram(SENDER, &datagram(sequenced, 5, 0, 1, &[5]));
+        transport.receive_datagram(SENDER, &datagram(sequenced, 4, 0, 1, &[4]));
+        transport.receive_datagram(SENDER, &datagram(sequenced, 6, 0, 1, &[6]));
+
+        assert_eq!(drain(&transport), vec![vec![5], vec![6]]);
+    }
+
+    #[test]
+    fn sequenced_messages_keep_their_order_across_wrap_around() {
+        let transport = receiver();
+        let sequenced = Reliability::UnreliableSequenced;
+        transport.receive_datagram(SENDER, &datagram(sequenced, u16::MAX, 0, 1, &[1]));
+        transport.receive_datagram(SENDER, &datagram(sequenced, 0, 0, 1, &[2]));
+        transport.receive_datagram(SENDER, &datagram(sequenced, u16::MAX - 1, 0, 1, &[0]));
+
+        assert_eq!(drain(&transport), vec![vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn a_missing_reliable_message_loses_the_peer() {
+        let transport = receiver();
+        for sequence in 1..=MAX_OUT_OF_ORDER as u16 + 1 {
+            transport.receive_datagram(
+                SENDER,
+                &datagram(Reliability::Reliable, sequence, 0, 1, &[0]),
+            );
+        }
+
+        assert!(drain(&transport).is_empty());
+        assert_eq!(transport.lost_peers(), vec![SENDER]);
+        assert!(transport.lost_peers().is_empty());
+    }
+
+    #[test]
+    fn reliable_messages_pass_through_transports_that_guarantee_them() {
+        let network = LoopbackNetwork::new();
+        let sender = ChanneledTransport::new(Box::new(GuaranteedTransport(network.endpoint())));
+        let receiver = ChanneledTransport::new(Box::new(network.endpoint()));
+        let peer = receiver.local_peer();
+
+        let message: Vec<u8> = (0..FRAGMENT_SIZE * 2).map(|i| i as u8).collect();
+        assert!(sender.send_packet(peer, Reliability::Reliable, &message));
+
+        assert_eq!(drain(&receiver), vec![message]);
+        assert!(sender.peers.lock().unwrap()[&peer].unacked.is_empty());
+    }
+
+    #[test]
+    fn peers_that_havent_joined_are_limited() {
+        let transport = receiver();
+        for peer in 0..=MAX_PENDING_PEERS as u64 {
+            let message = datagram(Reliability::Unreliable, 0, 0, 1, &[peer as u8]);
+            transport.receive_datagram(PeerId(100 + peer), &message);
+        }
+        assert_eq!(drain(&transport).len(), MAX_PENDING_PEERS);
+
+        //a peer completing the handshake makes room for the next one
+        transport.accept_peer(PeerId(100));
+        transport.receive_datagram(
+            PeerId(200),
+            &datagram(Reliability::Unreliable, 0, 0, 1, &[0]),
+        );
+        assert_eq!(drain(&transport).len(), 1);
+    }
+}
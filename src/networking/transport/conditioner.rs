@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{PeerId, Reliability, Transport};
+use crate::utils::Rng;
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct NetworkConditions {
+    //one way delay added to every packet, in seconds
+    pub latency: f32,
+    //random extra delay of up to this many seconds
+    pub jitter: f32,
+    //chance from 0 to 1 that an unreliable packet is dropped
+    pub loss: f32,
+    //chance from 0 to 1 that an unreliable packet is sent twice
+    pub duplication: f32,
+    //chance from 0 to 1 that an unreliable packet is held back long enough to arrive after later ones
+    pub reorder: f32,
+}
+
+impl NetworkConditions {
+    //parses comma separated settings like "latency=0.1,loss=0.05", None if one is malformed
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut conditions = Self::default();
+        for setting in text.split(',') {
+            let (name, value) = setting.split_once('=')?;
+            let value: f32 = value.trim().parse().ok()?;
+            match name.trim() {
+                "latency" => conditions.latency = value,
+                "jitter" => conditions.jitter = value,
+                "loss" => conditions.loss = value,
+                "duplication" => conditions.duplication = value,
+                "reorder" => conditions.reorder = value,
+                _ => return None,
+            }
+        }
+        Some(conditions)
+    }
+}
+
+//a clock that only moves when advanced, shared with whoever drives it so runs don't depend on wall time
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct ManualClock(std::sync::Arc<Mutex<Duration>>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+
+    fn now(&self) -> Duration {
+        *self.0.lock().unwrap()
+    }
+}
+
+enum Clock {
+    Real(Instant),
+    #[cfg(test)]
+    Manual(ManualClock),
+}
+
+struct DelayedPacket {
+    release: Duration,
+    peer: PeerId,
+    reliability: Reliability,
+    bytes: Vec<u8>,
+}
+
+/**
+ * Wraps another transport and degrades the packets it sends, so bad connections
+ * can be reproduced locally. The networking plugin puts the ChanneledTransport on top
+ * of it, which sends everything unreliably, so loss, duplication and reordering hit
+ * reliable messages as well and exercise their resends. Packets sent to it as reliable
+ * directly are only delayed, in order, since the wrapped transport promises they arrive.
+ */
+pub struct ConditionedTransport {
+    inner: Box<dyn Transport>,
+    default_conditions: NetworkConditions,
+    peer_conditions: Mutex<HashMap<PeerId, NetworkConditions>>,
+    rng: Mutex<Rng>,
+    clock: Mutex<Clock>,
+    delayed: Mutex<Vec<DelayedPacket>>,
+}
+
+impl ConditionedTransport {
+    pub fn new(inner: Box<dyn Transport>, conditions: NetworkConditions, rng: Rng) -> Self {
+        Self {
+            inner,
+            default_conditions: conditions,
+            peer_conditions: Mutex::new(HashMap::new()),
+            rng: Mutex::new(rng),
+            clock: Mutex::new(Clock::Real(Instant::now())),
+            delayed: Mutex::new(Vec::new()),
+        }
+    }
+
+    //stops following wall time, packets are only released once the clock is advanced past their delay
+    #[cfg(test)]
+    pub fn with_manual_clock(self, clock: ManualClock) -> Self {
+        *self.clock.lock().unwrap() = Clock::Manual(clock);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn advance(&self, duration: Duration) {
+        if let Clock::Manual(clock) = &*self.clock.lock().unwrap() {
+            clock.advance(duration);
+        }
+        self.release_due_packets();
+    }
+
+    //only tests pick conditions per peer, --simulate applies the same ones to everyone
+    #[cfg(test)]
+    pub fn set_peer_conditions(&self, peer: PeerId, conditions: NetworkConditions) {
+        self.peer_conditions
+            .lock()
+            .unwrap()
+            .insert(peer, conditions);
+    }
+
+    fn now(&self) -> Duration {
+        match &*self.clock.lock().unwrap() {
+            Clock::Real(start) => start.elapsed(),
+            #[cfg(test)]
+            Clock::Manual(clock) => clock.now(),
+        }
+    }
+
+    fn roll(&self) -> f32 {
+        let mut rng = self.rng.lock().unwrap();
+        rng.mutate_state();
+        rng.f32()
+    }
+
+    fn release_due_packets(&self) {
+        let now = self.now();
+
+        let mut delayed = self.delayed.lock().unwrap();
+        //sorted so packets leave in the order they were scheduled to arrive
+        delayed.sort_by_key(|packet| packet.release);
+
+        let due = delayed
+            .iter()
+            .take_while(|packet| packet.release <= now)
+            .count();
+        for packet in delayed.drain(..due) {
+            self.inner
+                .send_packet(packet.peer, packet.reliability, &packet.bytes);
+        }
+    }
+}
+
+impl Transport for ConditionedTransport {
+    fn local_peer(&self) -> PeerId {
+        self.inner.local_peer()
+    }
+
+    fn send_packet(&self, peer: PeerId, reliability: Reliability, bytes: &[u8]) -> bool {
+        let conditions = self
+            .peer_conditions
+            .lock()
+            .unwrap()
+            .get(&peer)
+            .copied()
+            .unwrap_or(self.default_conditions);
+
+        let mut copies = 1;
+        let mut delay = conditions.latency + conditions.jitter * self.roll();
+        let mut earliest = Duration::ZERO;
+
+        if reliability != Reliability::Reliable {
+            if self.roll() < conditions.loss {
+                //reports success like a real transport would for a packet lost on the way
+                return true;
+            }
+            if self.roll() < conditions.duplication {
+                copies = 2;
+            }
+            if self.roll() < conditions.reorder {
+                delay += conditions.latency + conditions.jitter;
+            }
+        } else {
+            //reliable packets keep their order, so they can't leave before an earlier one
+            let last_reliable = self
+                .delayed
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|packet| packet.peer == peer && packet.reliability == reliability)
+                .map(|packet| packet.release)
+                .max();
+            earliest = last_reliable.unwrap_or_default();
+        }
+
+        let release = (self.now() + Duration::from_secs_f32(delay.max(0.0))).max(earliest);
+        let mut delayed = self.delayed.lock().unwrap();
+        for _ in 0..copies {
+            delayed.push(DelayedPacket {
+                release,
+                peer,
+                reliability,
+                bytes: bytes.to_vec(),
+            });
+        }
+
+        true
+    }
+
+    fn is_packet_available(&self) -> Option<usize> {
+        self.release_due_packets();
+        self.inner.is_packet_available()
+    }
+
+    fn read_packet(&self, buffer: &mut [u8]) -> Option<(PeerId, usize)> {
+        self.inner.read_packet(buffer)
+    }
+
+    //reliable packets are only delayed, in order
+    fn guarantees_reliable(&self) -> bool {
+        self.inner.guarantees_reliable()
+    }
+
+    fn accept_peer(&self, peer: PeerId) {
+        self.inner.accept_peer(peer);
+    }
+
+    fn forget_peer(&self, peer: PeerId) {
+        self.inner.forget_peer(peer);
+    }
+
+    fn observe_local_peer(&self, observed: PeerId) -> Option<PeerId> {
+        self.inner.observe_local_peer(observed)
+    }
+
+    fn lost_peers(&self) -> Vec<PeerId> {
+        self.inner.lost_peers()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::LoopbackNetwork;
+    use super::*;
+
+    fn conditioned(
+        conditions: NetworkConditions,
+        seed: usize,
+    ) -> (ConditionedTransport, Box<dyn Transport>, ManualClock) {
+        let network = LoopbackNetwork::new();
+        let clock = ManualClock::new();
+        let sender =
+            ConditionedTransport::new(Box::new(network.endpoint()), conditions, Rng::new(seed))
+                .with_manual_clock(clock.clone());
+        let receiver: Box<dyn Transport> = Box::new(network.endpoint());
+
+        (sender, receiver, clock)
+    }
+
+    fn drain(receiver: &dyn Transport) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut buffer = [0; 16];
+        while let Some((_, len)) = receiver.read_packet(&mut buffer) {
+            packets.push(buffer[..len].to_vec());
+        }
+        packets
+    }
+
+    #[test]
+    fn delays_packets_until_the_clock_reaches_them() {
+        let conditions = NetworkConditions {
+            latency: 0.1,
+            ..Default::default()
+        };
+        let (sender, receiver, clock) = conditioned(conditions, 1);
+        let peer = receiver.local_peer();
+
+        sender.send_packet(peer, Reliability::Unreliable, &[1]);
+        sender.advance(Duration::from_millis(50));
+        assert!(drain(receiver.as_ref()).is_empty());
+
+        clock.advance(Duration::from_millis(60));
+        sender.is_packet_available();
+        assert_eq!(drain(receiver.as_ref()), vec![vec![1]]);
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_run() {
+        let conditions = NetworkConditions {
+            latency: 0.05,
+            jitter: 0.05,
+            loss: 0.3,
+            duplication: 0.1,
+            reorder: 0.1,
+        };
+
+        let run = || {
+            let (sender, receiver, _) = conditioned(conditions, 12345);
+            let peer = receiver.local_peer();
+            for i in 0..100u8 {
+                sender.send_packet(peer, Reliability::Unreliable, &[i]);
+                sender.advance(Duration::from_millis(10));
+            }
+            sender.advance(Duration::from_secs(1));
+            drain(receiver.as_ref())
+        };
+
+        let first = run();
+        assert_eq!(first, run());
+
+        let distinct: std::collections::HashSet<&Vec<u8>> = first.iter().collect();
+        assert!(!distinct.is_empty() && distinct.len() < 100);
+    }
+
+    #[test]
+    fn reliable_packets_stay_in_order() {
+        let conditions = NetworkConditions {
+            latency: 0.05,
+            jitter: 0.2,
+            loss: 1.0,
+            ..Default::default()
+        };
+        let (sender, receiver, _) = conditioned(conditions, 7);
+        let peer = receiver.local_peer();
+
+        for i in 0..20u8 {
+            sender.send_packet(peer, Reliability::Reliable, &[i]);
+        }
+        sender.advance(Duration::from_secs(5));
+
+        let expected: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i]).collect();
+        assert_eq!(drain(receiver.as_ref()), expected);
+    }
+
+    #[test]
+    fn peers_can_have_their_own_conditions() {
+        let conditions = NetworkConditions {
+            loss: 1.0,
+            ..Default::default()
+        };
+        let (sender, receiver, _) = conditioned(conditions, 3);
+        let peer = receiver.local_peer();
+
+        sender.send_packet(peer, Reliability::Unreliable, &[1]);
+        sender.set_peer_conditions(peer, NetworkConditions::default());
+        sender.send_packet(peer, Reliability::Unreliable, &[2]);
+        sender.advance(Duration::ZERO);
+
+        assert_eq!(drain(receiver.as_ref()), vec![vec![2]]);
+    }
+
+    #[test]
+    fn conditions_parse_from_the_command_line() {
+        let conditions = NetworkConditions::parse("latency=0.1, loss=0.05").unwrap();
+        assert_eq!(conditions.latency, 0.1);
+        assert_eq!(conditions.loss, 0.05);
+        assert_eq!(conditions.jitter, 0.0);
+
+        assert!(NetworkConditions::parse("latency").is_none());
+        assert!(NetworkConditions::parse("lag=0.1").is_none());
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use super::{PeerId, Reliability, Transport};
+
+//channel of every endpoint by peer id
+type Endpoints = HashMap<PeerId, Sender<(PeerId, Vec<u8>)>>;
+
+/**
+ * In-process network where every endpoint is a channel, used to run
+ * several apps in the same process without touching any sockets.
+ */
+#[derive(Clone, Default)]
+pub struct LoopbackNetwork {
+    endpoints: Arc<Mutex<Endpoints>>,
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //creates a new endpoint with the next free peer id
+    pub fn endpoint(&self) -> LoopbackTransport {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let local_peer = PeerId(endpoints.len() as u64 + 1);
+
+        let (sender, receiver) = channel();
+        endpoints.insert(local_peer, sender);
+
+        LoopbackTransport {
+            network: self.clone(),
+            local_peer,
+            receiver: Mutex::new(receiver),
+            pending: Mutex::new(None),
+        }
+    }
+}
+
+pub struct LoopbackTransport {
+    network: LoopbackNetwork,
+    local_peer: PeerId,
+    receiver: Mutex<Receiver<(PeerId, Vec<u8>)>>,
+    //a packet that has been received by is_packet_available but not yet read
+    pending: Mutex<Option<(PeerId, Vec<u8>)>>,
+}
+
+impl Transport for LoopbackTransport {
+    fn local_peer(&self) -> PeerId {
+        self.local_peer
+    }
+
+    fn send_packet(&self, peer: PeerId, _reliability: Reliability, bytes: &[u8]) -> bool {
+        let endpoints = self.network.endpoints.lock().unwrap();
+        match endpoints.get(&peer) {
+            Some(sender) => sender.send((self.local_peer, bytes.to_vec())).is_ok(),
+            None => false,
+        }
+    }
+
+    fn is_packet_available(&self) -> Option<usize> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.is_none() {
+            *pending = self.receiver.lock().unwrap().try_recv().ok();
+        }
+
+        pending.as_ref().map(|(_, bytes)| bytes.len())
+    }
+
+    fn read_packet(&self, buffer: &mut [u8]) -> Option<(PeerId, usize)> {
+        self.is_packet_available()?;
+
+        let (sender, bytes) = self.pending.lock().unwrap().take()?;
+        let len = bytes.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&bytes[..len]);
+
+        Some((sender, len))
+    }
+}
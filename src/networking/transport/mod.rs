@@ -0,0 +1,119 @@
+use crate::utils::Rng;
+
+mod capture;
+mod capture_file;
+mod channels;
+mod conditioner;
+#[cfg(test)]
+mod loopback;
+mod packet;
+mod steam;
+mod udp;
+
+pub use capture::*;
+pub use capture_file::*;
+pub use channels::*;
+pub use conditioner::*;
+#[cfg(test)]
+pub use loopback::*;
+pub use packet::*;
+pub use steam::*;
+pub use udp::*;
+
+pub trait Transport: Send + Sync {
+    //the id other peers use to address this machine
+    fn local_peer(&self) -> PeerId;
+
+    fn send_packet(&self, peer: PeerId, reliability: Reliability, bytes: &[u8]) -> bool;
+
+    //returns the size of the next packet if one is waiting
+    fn is_packet_available(&self) -> Option<usize>;
+
+    //reads the next packet into the buffer, returning the sender and the number of bytes read
+    fn read_packet(&self, buffer: &mut [u8]) -> Option<(PeerId, usize)>;
+
+    //true if packets sent as Reliable always arrive and in order,
+    //  the channel layer then passes them straight through instead of resending them itself
+    fn guarantees_reliable(&self) -> bool {
+        false
+    }
+
+    //called once a peer completed the handshake, until then transports may limit what they keep for it
+    fn accept_peer(&self, _peer: PeerId) {}
+
+    //drops any state kept for a peer that left the session
+    fn forget_peer(&self, _peer: PeerId) {}
+
+    //called with the id a peer reached this machine at, transports that can't know their own id,
+    //  like udp bound to an unspecified address, adopt it and return the new id
+    fn observe_local_peer(&self, _observed: PeerId) -> Option<PeerId> {
+        None
+    }
+
+    //peers the transport gave up on since the last call, they are dropped from the session
+    fn lost_peers(&self) -> Vec<PeerId> {
+        Vec::new()
+    }
+
+    //writes out anything buffered, called when the app exits
+    fn flush(&self) {}
+}
+
+#[derive(Clone)]
+pub enum TransportKind {
+    Steam {
+        app_id: u32,
+    },
+    Udp {
+        bind_address: std::net::SocketAddr,
+    },
+    //wraps another transport to simulate lag, loss and reordering
+    Conditioned {
+        inner: Box<TransportKind>,
+        conditions: NetworkConditions,
+        seed: usize,
+    },
+    //plays back the packets received in a capture file
+    Replay {
+        path: std::path::PathBuf,
+    },
+}
+
+impl TransportKind {
+    pub fn create(&self) -> Box<dyn Transport> {
+        match self {
+            TransportKind::Steam { app_id } => Box::new(SteamTransport::new(*app_id)),
+            TransportKind::Udp { bind_address } => Box::new(UdpTransport::bind(*bind_address)),
+            TransportKind::Conditioned {
+                inner,
+                conditions,
+                seed,
+            } => Box::new(ConditionedTransport::new(
+                inner.create(),
+                *conditions,
+                Rng::new(*seed),
+            )),
+            TransportKind::Replay { path } => {
+                Box::new(ReplayTransport::open(path).expect("failed to open the capture to replay"))
+            }
+        }
+    }
+
+    //the steam app id if packets go through steam, directly or wrapped in a conditioner
+    pub fn steam_app_id(&self) -> Option<u32> {
+        match self {
+            TransportKind::Steam { app_id } => Some(*app_id),
+            TransportKind::Conditioned { inner, .. } => inner.steam_app_id(),
+            _ => None,
+        }
+    }
+
+    //the local udp port if packets go through udp
+    pub fn udp_port(&self) -> Option<u16> {
+        match self {
+            TransportKind::Udp { bind_address } => Some(bind_address.port()),
+            TransportKind::Conditioned { inner, .. } => inner.udp_port(),
+            _ => None,
+        }
+    }
+}
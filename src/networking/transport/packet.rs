@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+//identifies a peer independently of the backend used to reach it,
+//  for steam this is the raw steam id, for udp it's the packed socket address
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+pub struct PeerId(pub u64);
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Reliability {
+    Unreliable,
+    //unreliable, but a message is dropped if a newer one on the same channel was already received
+    UnreliableSequenced,
+    //resent until acknowledged and delivered in the order it was sent
+    Reliable,
+}
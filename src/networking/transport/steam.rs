@@ -0,0 +1,93 @@
+use std::sync::Mutex;
+use steamworks::{CallbackHandle, Client, P2PSessionRequest, SendType, SingleClient, SteamId};
+
+use super::{PeerId, Reliability, Transport};
+
+//steam can only be initialized once per process, the transport and the lobby backend share this client
+static CLIENT: Mutex<Option<Client>> = Mutex::new(None);
+
+/**
+ * Steam only delivers callbacks to the thread that calls run_callbacks,
+ * so this has to live in a non send resource and run every frame.
+ */
+pub struct SteamCallbacks {
+    single: SingleClient,
+    _session_requests: CallbackHandle,
+}
+
+impl SteamCallbacks {
+    pub fn run(&self) {
+        self.single.run_callbacks();
+    }
+}
+
+//initializes steam on the first call, only that call returns the callbacks to run
+pub fn steam_client(app_id: u32) -> (Client, Option<SteamCallbacks>) {
+    let mut client = CLIENT.lock().unwrap();
+    if let Some(client) = client.as_ref() {
+        return (client.clone(), None);
+    }
+
+    let (new_client, single) = Client::init_app(app_id).unwrap();
+    //accepts packets from anyone, the join handshake decides who is let into the session
+    let acceptor = new_client.clone();
+    let session_requests = new_client.register_callback(move |request: P2PSessionRequest| {
+        acceptor.networking().accept_p2p_session(request.remote);
+    });
+
+    *client = Some(new_client.clone());
+    (
+        new_client,
+        Some(SteamCallbacks {
+            single,
+            _session_requests: session_requests,
+        }),
+    )
+}
+
+pub struct SteamTransport {
+    pub client: Client,
+    player_id: SteamId,
+}
+
+impl SteamTransport {
+    pub fn new(app_id: u32) -> Self {
+        let (client, _) = steam_client(app_id);
+        let player_id = client.user().steam_id();
+
+        Self { client, player_id }
+    }
+}
+
+impl Transport for SteamTransport {
+    fn local_peer(&self) -> PeerId {
+        PeerId(self.player_id.raw())
+    }
+
+    fn send_packet(&self, peer: PeerId, reliability: Reliability, bytes: &[u8]) -> bool {
+        let send_type = match reliability {
+            Reliability::Unreliable | Reliability::UnreliableSequenced => SendType::Unreliable,
+            Reliability::Reliable => SendType::Reliable,
+        };
+
+        self.client
+            .networking()
+            .send_p2p_packet(SteamId::from_raw(peer.0), send_type, bytes)
+    }
+
+    //steam resends reliable packets and delivers them in order
+    fn guarantees_reliable(&self) -> bool {
+        true
+    }
+
+    fn is_packet_available(&self) -> Option<usize> {
+        self.client.networking().is_p2p_packet_available()
+    }
+
+    fn read_packet(&self, buffer: &mut [u8]) -> Option<(PeerId, usize)> {
+        self.client
+            .networking()
+            .read_p2p_packet(buffer)
+            .map(|(sender, len)| (PeerId(sender.raw()), len))
+    }
+}
@@ -0,0 +1,116 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::Mutex;
+
+use super::{PeerId, Reliability, Transport};
+
+//largest payload a single udp datagram can carry
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/**
+ * Plain udp backend for running several instances on one machine or in CI
+ * without steam. Reliable packets are sent the same way as unreliable ones,
+ * delivery guarantees come from the ChanneledTransport wrapped around it.
+ * Peers are identified by their address, a socket bound to an unspecified address like 0.0.0.0
+ * doesn't know its own and takes the one the first peer to reach it used, see observe_local_peer.
+ */
+pub struct UdpTransport {
+    socket: UdpSocket,
+    local_peer: Mutex<PeerId>,
+    //a datagram that has been received by is_packet_available but not yet read
+    pending: Mutex<Option<(PeerId, Vec<u8>)>>,
+}
+
+impl UdpTransport {
+    pub fn bind(address: SocketAddr) -> Self {
+        let socket = UdpSocket::bind(address).unwrap();
+        socket.set_nonblocking(true).unwrap();
+
+        let local_peer = peer_from_address(socket.local_addr().unwrap())
+            .expect("UdpTransport can't bind to an ipv6 address");
+
+        Self {
+            socket,
+            local_peer: Mutex::new(local_peer),
+            pending: Mutex::new(None),
+        }
+    }
+}
+
+//packs an ipv4 address and port into a peer id so no lookup table is needed
+pub fn peer_from_address(address: SocketAddr) -> io::Result<PeerId> {
+    match address {
+        SocketAddr::V4(address) => Ok(PeerId(
+            ((u32::from(*address.ip()) as u64) << 16) | address.port() as u64,
+        )),
+        SocketAddr::V6(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only ipv4 addresses are supported",
+        )),
+    }
+}
+
+pub fn address_from_peer(peer: PeerId) -> SocketAddr {
+    let ip = Ipv4Addr::from((peer.0 >> 16) as u32);
+    let port = (peer.0 & 0xFFFF) as u16;
+    SocketAddr::V4(SocketAddrV4::new(ip, port))
+}
+
+impl Transport for UdpTransport {
+    fn local_peer(&self) -> PeerId {
+        *self.local_peer.lock().unwrap()
+    }
+
+    fn send_packet(&self, peer: PeerId, _reliability: Reliability, bytes: &[u8]) -> bool {
+        self.socket.send_to(bytes, address_from_peer(peer)).is_ok()
+    }
+
+    fn is_packet_available(&self) -> Option<usize> {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some((_, bytes)) = pending.as_ref() {
+            return Some(bytes.len());
+        }
+
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((len, sender)) => {
+                    //a datagram from an ipv6 sender has no peer id, so it is dropped
+                    let Ok(sender) = peer_from_address(sender) else {
+                        continue;
+                    };
+                    buffer.truncate(len);
+                    *pending = Some((sender, buffer));
+                    return Some(len);
+                }
+                //WouldBlock means nothing is waiting, any other error is treated the same way
+                Err(_) => return None,
+            }
+        }
+    }
+
+    fn read_packet(&self, buffer: &mut [u8]) -> Option<(PeerId, usize)> {
+        self.is_packet_available()?;
+
+        let (sender, bytes) = self.pending.lock().unwrap().take()?;
+        let len = bytes.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&bytes[..len]);
+
+        Some((sender, len))
+    }
+
+    fn observe_local_peer(&self, observed: PeerId) -> Option<PeerId> {
+        let mut local_peer = self.local_peer.lock().unwrap();
+        let local = address_from_peer(*local_peer);
+        let address = address_from_peer(observed);
+        if !local.ip().is_unspecified()
+            || address.ip().is_unspecified()
+            || address.port() != local.port()
+        {
+            return None;
+        }
+
+        *local_peer = observed;
+        Some(observed)
+    }
+}
@@ -1,20 +1,234 @@
+use bevy::ecs::system::EntityCommands;
 use bevy::prelude::*;
+use bevy_rapier3d::prelude::Velocity;
+use bevy_trait_query::RegisterExt;
+use std::collections::HashMap;
+
+use super::hierarchy::NetworkParent;
+use super::Serializable;
+use crate::ai::persona::{AssociativeMemory, Persona, Scratch};
+use crate::game::prediction::PlayerController;
+use crate::rpg::RPG;
 
 pub trait Registered:
     serde::Serialize + serde::de::DeserializeOwned + Send + Sync + std::any::Any + 'static
 {
-    fn registered_id(&self) -> u16;
+    //used to identify the type of the component when synchronizing
+    const ID: u16;
+    const NAME: &'static str;
+
+    fn registered_id(&self) -> u16 {
+        Self::ID
+    }
+}
+
+//FNV-1a hash of the type name folded into 16 bits, so ids only change if a type is renamed
+pub(super) const fn hash_name(name: &str) -> u16 {
+    let bytes = name.as_bytes();
+    let mut hash: u32 = 0x811c9dc5;
+
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x01000193);
+        i += 1;
+    }
+
+    ((hash >> 16) ^ (hash & 0xFFFF)) as u16
 }
 
-macro_rules! register {
-    ($type:ty, $id:expr) => {
-        impl Registered for $type {
-            fn registered_id(&self) -> u16 {
-                $id
+macro_rules! register_types {
+    ($($type:ident),* $(,)?) => {
+        $(
+            impl Registered for $type {
+                const ID: u16 = hash_name(stringify!($type));
+                const NAME: &'static str = stringify!($type);
             }
+        )*
+
+        //registers every networked type with the app, panics if two ids collide
+        pub fn register_all(app: &mut App) {
+            $(app.register_networked::<$type>();)*
         }
     };
 }
 
-register!(Transform, 0);
-// TODO: register all types here
+//add a type here to synchronize it over the network
+register_types!(
+    Transform,
+    Velocity,
+    RPG,
+    Persona,
+    Scratch,
+    AssociativeMemory,
+    PlayerController,
+    NetworkParent,
+);
+
+//inserts a component decoded from bytes into an entity, returns false if the bytes are invalid
+type Constructor = fn(&mut EntityCommands, &[u8]) -> bool;
+
+#[derive(Resource, Default)]
+pub struct TypeRegistry {
+    constructors: HashMap<u16, Constructor>,
+    names: HashMap<u16, &'static str>,
+}
+
+impl TypeRegistry {
+    pub fn register<T: Registered + Component>(&mut self) {
+        if let Some(existing) = self.names.get(&T::ID) {
+            if *existing != T::NAME {
+                panic!(
+                    "Networked types {} and {} share the type id {}, rename one of them",
+                    existing,
+                    T::NAME,
+                    T::ID
+                );
+            }
+        }
+
+        self.constructors.insert(T::ID, construct::<T>);
+        self.names.insert(T::ID, T::NAME);
+    }
+
+    pub fn type_name(&self, type_id: u16) -> Option<&'static str> {
+        self.names.get(&type_id).copied()
+    }
+
+    //every registered id and name, sorted by id
+    pub fn type_names(&self) -> Vec<(u16, &'static str)> {
+        let mut names: Vec<(u16, &'static str)> =
+            self.names.iter().map(|(id, name)| (*id, *name)).collect();
+        names.sort();
+        names
+    }
+
+    //FNV-1a hash of every registered id and name, equal on machines with the same networked types
+    pub fn table_hash(&self) -> u64 {
+        let mut ids: Vec<&u16> = self.names.keys().collect();
+        ids.sort();
+
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for id in ids {
+            for byte in id.to_le_bytes().iter().chain(self.names[id].as_bytes()) {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+
+        hash
+    }
+
+    //formats a type id for log messages
+    pub fn describe(&self, type_id: u16) -> String {
+        match self.type_name(type_id) {
+            Some(name) => format!("{} ({})", name, type_id),
+            None => format!("unknown type {}", type_id),
+        }
+    }
+
+    pub fn construct(&self, type_id: u16, entity: &mut EntityCommands, bytes: &[u8]) -> bool {
+        match self.constructors.get(&type_id) {
+            Some(constructor) => constructor(entity, bytes),
+            None => false,
+        }
+    }
+}
+
+fn construct<T: Registered + Component>(entity: &mut EntityCommands, bytes: &[u8]) -> bool {
+    match bincode::deserialize::<T>(bytes) {
+        Ok(component) => {
+            entity.insert(component);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+pub trait RegisterNetworked {
+    //makes a component both queryable as Serializable and constructable from the network
+    fn register_networked<T: Registered + Component>(&mut self) -> &mut Self;
+}
+
+impl RegisterNetworked for App {
+    fn register_networked<T: Registered + Component>(&mut self) -> &mut Self {
+        self.init_resource::<TypeRegistry>();
+        self.world.resource_mut::<TypeRegistry>().register::<T>();
+        self.register_component_as::<dyn Serializable, T>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Component, Serialize, Deserialize)]
+    struct First;
+
+    #[derive(Component, Serialize, Deserialize)]
+    struct Second;
+
+    impl Registered for First {
+        const ID: u16 = 1;
+        const NAME: &'static str = "First";
+    }
+
+    impl Registered for Second {
+        const ID: u16 = 1;
+        const NAME: &'static str = "Second";
+    }
+
+    #[test]
+    fn names_hash_to_folded_fnv_1a() {
+        assert_eq!(hash_name(""), 0x811c ^ 0x9dc5);
+        assert_eq!(hash_name("a"), 0xe40c ^ 0x292c);
+        assert_eq!(Transform::ID, hash_name("Transform"));
+    }
+
+    #[test]
+    fn registered_types_have_distinct_ids() {
+        let ids = [
+            Transform::ID,
+            Velocity::ID,
+            RPG::ID,
+            Persona::ID,
+            Scratch::ID,
+            AssociativeMemory::ID,
+            PlayerController::ID,
+            NetworkParent::ID,
+        ];
+        let distinct: std::collections::HashSet<u16> = ids.iter().copied().collect();
+        assert_eq!(distinct.len(), ids.len());
+    }
+
+    #[test]
+    #[should_panic(expected = "share the type id")]
+    fn colliding_ids_panic() {
+        let mut registry = TypeRegistry::default();
+        registry.register::<First>();
+        registry.register::<Second>();
+    }
+
+    #[test]
+    fn registering_a_type_again_is_allowed() {
+        let mut registry = TypeRegistry::default();
+        registry.register::<First>();
+        registry.register::<First>();
+        assert_eq!(registry.type_name(1), Some("First"));
+    }
+
+    #[test]
+    fn table_hash_ignores_registration_order() {
+        let mut forward = TypeRegistry::default();
+        forward.register::<Transform>();
+        forward.register::<Velocity>();
+
+        let mut backward = TypeRegistry::default();
+        backward.register::<Velocity>();
+        backward.register::<Transform>();
+
+        assert_eq!(forward.table_hash(), backward.table_hash());
+        assert_ne!(forward.table_hash(), TypeRegistry::default().table_hash());
+    }
+}
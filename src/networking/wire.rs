@@ -0,0 +1,80 @@
+/*!
+ * Helpers for the component wire format shared by EntityCreate and EntityUpdate.
+ * Every component is written as its type id (u16), the length of its data (u32)
+ * and the data itself, so a receiver can skip components it doesn't know.
+ */
+
+//reads values from a packet without ever indexing past its end
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.bytes[self.position.min(self.bytes.len())..]
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.position.checked_add(len)?;
+        if end > self.bytes.len() {
+            return None;
+        }
+
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Some(bytes)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.bytes(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        self.bytes(8)
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+//returns false without writing anything if the data is too long for its length prefix
+pub fn write_component(bytes: &mut Vec<u8>, type_id: u16, data: &[u8]) -> bool {
+    let Ok(len) = u32::try_from(data.len()) else {
+        return false;
+    };
+
+    bytes.extend_from_slice(&type_id.to_le_bytes());
+    bytes.extend_from_slice(&len.to_le_bytes());
+    bytes.extend_from_slice(data);
+    true
+}
+
+//reads components until the end of the packet, returns None if the packet is truncated
+pub fn read_components<'a>(reader: &mut Reader<'a>) -> Option<Vec<(u16, &'a [u8])>> {
+    let mut components = Vec::new();
+
+    while !reader.is_empty() {
+        let type_id = reader.u16()?;
+        let len = reader.u32()? as usize;
+        components.push((type_id, reader.bytes(len)?));
+    }
+
+    Some(components)
+}
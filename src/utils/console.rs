@@ -0,0 +1,57 @@
+use bevy::prelude::*;
+use std::io::BufRead;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Mutex;
+
+/**
+ * A line typed into the terminal the game runs in, split on whitespace,
+ * e.g. "kick 12345" has the name "kick" and the args ["12345"]
+ */
+#[derive(Event, Clone, Debug)]
+pub struct ConsoleCommand {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+impl ConsoleCommand {
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut words = line.split_whitespace().map(str::to_string);
+        Some(Self {
+            name: words.next()?.to_lowercase(),
+            args: words.collect(),
+        })
+    }
+}
+
+//lines read from stdin, reading blocks so it happens on its own thread
+#[derive(Resource)]
+pub struct Console(Mutex<Receiver<String>>);
+
+//opened on startup so the reader thread only exists once the app runs
+pub fn open_console(mut commands: Commands) {
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    commands.insert_resource(Console(Mutex::new(receiver)));
+}
+
+pub fn read_console(console: Option<Res<Console>>, mut commands: EventWriter<ConsoleCommand>) {
+    let Some(console) = console else {
+        return;
+    };
+
+    for line in console.0.lock().unwrap().try_iter() {
+        if let Some(command) = ConsoleCommand::parse(&line) {
+            commands.send(command);
+        }
+    }
+}
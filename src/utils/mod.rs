@@ -1,8 +1,10 @@
 use bevy::prelude::*;
 use std::time::SystemTime;
 
+mod console;
 mod one_shot_registry;
 mod rng;
+pub use console::*;
 pub use one_shot_registry::*;
 pub use rng::*;
 
@@ -17,6 +19,8 @@ impl Plugin for UtilPlugin {
                 .as_nanos() as usize,
         ))
         .init_resource::<OneShotRegistry>()
-        .add_systems(Update, rng_system);
+        .add_event::<ConsoleCommand>()
+        .add_systems(Startup, open_console)
+        .add_systems(Update, (rng_system, read_console));
     }
 }